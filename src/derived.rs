@@ -0,0 +1,179 @@
+// --- Derived Measurements ---
+//
+// [[derived]] entries compute measurements the inverter does not report, like
+// efficiency = AC_Power / DC_Power. An expression is two operands (a
+// measurement Type or a number) joined by + - * or /, written with spaces.
+
+use crate::{clean_value, parse_value, DerivedConfig, Measurement};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Type(String),
+    Number(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DerivedMeasurement {
+    name: String,
+    unit: Option<String>,
+    left: Operand,
+    operator: Operator,
+    right: Operand,
+}
+
+impl Operand {
+    fn parse(token: &str) -> Operand {
+        match token.parse::<f64>() {
+            Ok(number) if number.is_finite() => Operand::Number(number),
+            _ => Operand::Type(token.to_string()),
+        }
+    }
+
+    fn value(&self, measurements: &[Measurement]) -> Option<f64> {
+        match self {
+            Operand::Number(number) => Some(*number),
+            Operand::Type(typ) => measurements
+                .iter()
+                .find(|m| &m.typ == typ)
+                .and_then(|m| clean_value(m.value.as_deref()))
+                .and_then(parse_value),
+        }
+    }
+}
+
+impl DerivedMeasurement {
+    pub(crate) fn parse(config: &DerivedConfig) -> Result<Self, String> {
+        if config.name.trim().is_empty() {
+            return Err("derived measurement name must not be empty.".to_string());
+        }
+
+        let tokens: Vec<&str> = config.expression.split_whitespace().collect();
+        let [left, operator, right] = tokens[..] else {
+            return Err(format!(
+                "Invalid expression '{}' for derived measurement {}, expected '<operand> <+|-|*|/> <operand>'.",
+                config.expression, config.name
+            ));
+        };
+        let operator = match operator {
+            "+" => Operator::Add,
+            "-" => Operator::Subtract,
+            "*" => Operator::Multiply,
+            "/" => Operator::Divide,
+            other => {
+                return Err(format!(
+                    "Invalid operator '{}' for derived measurement {}, allowed are +, -, * and /.",
+                    other, config.name
+                ));
+            }
+        };
+
+        Ok(DerivedMeasurement {
+            name: config.name.clone(),
+            unit: config.unit.clone(),
+            left: Operand::parse(left),
+            operator,
+            right: Operand::parse(right),
+        })
+    }
+
+    // Missing operands, division by zero and other non-finite results yield no
+    // measurement at all rather than a misleading value.
+    fn evaluate(&self, measurements: &[Measurement]) -> Option<f64> {
+        let left = self.left.value(measurements)?;
+        let right = self.right.value(measurements)?;
+        let result = match self.operator {
+            Operator::Add => left + right,
+            Operator::Subtract => left - right,
+            Operator::Multiply => left * right,
+            Operator::Divide if right == 0.0 => return None,
+            Operator::Divide => left / right,
+        };
+        result.is_finite().then(|| (result * 1e9).round() / 1e9)
+    }
+}
+
+// Entries are applied in order, so later ones can use the result of earlier ones.
+pub(crate) fn add_derived_measurements(measurements: &mut Vec<Measurement>, derived: &[DerivedMeasurement]) {
+    for rule in derived {
+        if let Some(value) = rule.evaluate(measurements) {
+            measurements.retain(|m| m.typ != rule.name);
+            measurements.push(Measurement {
+                value: Some(value.to_string()),
+                typ: rule.name.clone(),
+                unit: rule.unit.clone(),
+                name: None,
+                description: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(typ: &str, value: &str) -> Measurement {
+        Measurement {
+            value: Some(value.to_string()),
+            typ: typ.to_string(),
+            unit: None,
+            name: None,
+            description: None,
+        }
+    }
+
+    fn rule(name: &str, expression: &str) -> DerivedMeasurement {
+        DerivedMeasurement::parse(&DerivedConfig {
+            name: name.to_string(),
+            expression: expression.to_string(),
+            unit: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn evaluates_in_order_and_replaces_existing_types() {
+        let mut measurements = vec![measurement("AC_Power", "380"), measurement("DC_Power", "400"), measurement("Efficiency", "1")];
+        let rules = [rule("Efficiency", "AC_Power / DC_Power"), rule("Efficiency_Percent", "Efficiency * 100")];
+
+        add_derived_measurements(&mut measurements, &rules);
+
+        let values: Vec<(&str, Option<&str>)> = measurements.iter().map(|m| (m.typ.as_str(), m.value.as_deref())).collect();
+        assert_eq!(
+            values,
+            [("AC_Power", Some("380")), ("DC_Power", Some("400")), ("Efficiency", Some("0.95")), ("Efficiency_Percent", Some("95"))]
+        );
+    }
+
+    #[test]
+    fn skips_missing_operands_and_division_by_zero() {
+        let mut measurements = vec![measurement("AC_Power", "0"), measurement("DC_Power", "NaN")];
+        let rules = [rule("A", "DC_Power / AC_Power"), rule("B", "1 / AC_Power"), rule("C", "Missing + 1")];
+
+        add_derived_measurements(&mut measurements, &rules);
+
+        assert_eq!(measurements.len(), 2);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        let parse = |expression: &str| {
+            DerivedMeasurement::parse(&DerivedConfig {
+                name: "X".to_string(),
+                expression: expression.to_string(),
+                unit: None,
+            })
+        };
+        assert!(parse("AC_Power/DC_Power").is_err());
+        assert!(parse("AC_Power % DC_Power").is_err());
+        assert!(parse("AC_Power + DC_Power + 1").is_err());
+    }
+}
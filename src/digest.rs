@@ -0,0 +1,119 @@
+// --- HTTP Digest Authentication (RFC 7616, MD5 only) ---
+//
+// reqwest only knows Basic auth, but older Kontron web servers insist on
+// Digest. The inverter answers the first request with 401 and a challenge,
+// the request is then repeated with the computed Authorization header.
+
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub(crate) struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: String,
+    // Only "auth" is supported, servers without qop use the RFC 2069 scheme.
+    qop_auth: bool,
+}
+
+impl DigestChallenge {
+    // Parses the value of a WWW-Authenticate header like
+    // `Digest realm="inverter", nonce="abc", qop="auth"`.
+    pub(crate) fn parse(header: &str) -> Result<Self, String> {
+        let params = header
+            .trim()
+            .strip_prefix("Digest ")
+            .ok_or_else(|| format!("Expected a Digest challenge, got '{}'.", header))?;
+        let params = parse_params(params);
+
+        let qop_auth = match params.get("qop") {
+            None => false,
+            Some(qop) if qop.split(',').any(|q| q.trim() == "auth") => true,
+            Some(qop) => return Err(format!("Unsupported digest qop '{}'.", qop)),
+        };
+
+        Ok(DigestChallenge {
+            realm: params.get("realm").cloned().unwrap_or_default(),
+            nonce: params
+                .get("nonce")
+                .cloned()
+                .ok_or("Digest challenge has no nonce.")?,
+            opaque: params.get("opaque").cloned(),
+            algorithm: params.get("algorithm").cloned().unwrap_or_else(|| "MD5".to_string()),
+            qop_auth,
+        })
+    }
+
+    // Builds the Authorization header value for one request.
+    pub(crate) fn authorization(
+        &self,
+        username: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        cnonce: &str,
+    ) -> Result<String, String> {
+        let nc = "00000001";
+        let ha1 = match self.algorithm.to_ascii_uppercase().as_str() {
+            "MD5" => md5_hex(&format!("{}:{}:{}", username, self.realm, password)),
+            "MD5-SESS" => md5_hex(&format!(
+                "{}:{}:{}",
+                md5_hex(&format!("{}:{}:{}", username, self.realm, password)),
+                self.nonce,
+                cnonce
+            )),
+            other => return Err(format!("Unsupported digest algorithm '{}'.", other)),
+        };
+        let ha2 = md5_hex(&format!("{}:{}", method, uri));
+
+        let response = if self.qop_auth {
+            md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, self.nonce, nc, cnonce, ha2))
+        } else {
+            md5_hex(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        };
+
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+            username, self.realm, self.nonce, uri, self.algorithm, response
+        );
+        if self.qop_auth {
+            header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+        }
+        if let Some(opaque) = &self.opaque {
+            header.push_str(&format!(", opaque=\"{}\"", opaque));
+        }
+        Ok(header)
+    }
+}
+
+// Splits `key=value, key="quoted, value"` pairs, keys are lowercased.
+fn parse_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = input.trim();
+
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let after = after.trim_start();
+
+        let (value, remainder) = if let Some(quoted) = after.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match after.find(',') {
+                Some(end) => (&after[..end], &after[end..]),
+                None => (after, ""),
+            }
+        };
+
+        params.insert(key, value.trim().to_string());
+        rest = remainder.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+fn md5_hex(input: &str) -> String {
+    format!("{:x}", Md5::digest(input.as_bytes()))
+}
@@ -0,0 +1,129 @@
+// --- Log File ---
+//
+// [logging] file writes the log to a file instead of stdout, for targets
+// without journald. Once the file would grow beyond max_size_mb it is renamed
+// to <file>.1, older ones move on to <file>.2 and so on, and only max_files
+// of them are kept.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+pub(crate) const DEFAULT_LOG_MAX_SIZE_MB: u64 = 10;
+pub(crate) const DEFAULT_LOG_MAX_FILES: usize = 5;
+
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    // Rotated files kept besides the current one, 0 truncates it instead.
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    // Appends to an existing file, its size counts towards the first rotation.
+    pub(crate) fn open(path: &str, max_size: u64, max_files: usize) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        let file = open_append(&path).map_err(|e| format!("Could not open log file '{}': {}", path.display(), e))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(RotatingFile { path, max_size, max_files, file, size })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            // The oldest one may not exist yet, e.g. after the first rotations.
+            let _ = fs::remove_file(self.rotated(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    // tracing writes every event at once, so a line is never split between two files.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size
+            && let Err(e) = self.rotate() {
+            // The log cannot report on itself. Keeps appending and tries
+            // again after another max_size, losing lines would be worse.
+            eprintln!("Log file rotation of '{}' failed: {}", self.path.display(), e);
+            self.size = 0;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("solbrid_log_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_max_files() {
+        let dir = log_dir("rotate");
+        let path = dir.join("bridge.log");
+        let mut file = RotatingFile::open(path.to_str().unwrap(), 10, 2).unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("bridge.log"), "fourth\n");
+        assert_eq!(read("bridge.log.1"), "third\n");
+        assert_eq!(read("bridge.log.2"), "second\n");
+        assert!(!dir.join("bridge.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn continues_an_existing_file_and_truncates_without_max_files() {
+        let dir = log_dir("truncate");
+        let path = dir.join("bridge.log");
+        fs::write(&path, "earlier run\n").unwrap();
+        let mut file = RotatingFile::open(path.to_str().unwrap(), 20, 0).unwrap();
+
+        file.write_all(b"more\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "earlier run\nmore\n");
+        file.write_all(b"too much now\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "too much now\n");
+        assert!(!dir.join("bridge.log.1").exists());
+        assert!(RotatingFile::open(dir.join("missing/bridge.log").to_str().unwrap(), 20, 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
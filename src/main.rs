@@ -1,269 +1,4761 @@
 // SolBridXML2MQTT - A Rust application for Kontron Solbrid inverter data to MQTT.
 
-use futures::stream;
-use influxdb2::{models::DataPoint, Client as InfluxClient};
-use reqwest::Client;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use serde::Deserialize;
+mod derived;
+mod digest;
+mod log_file;
+mod mqtt_client;
+mod purge;
+mod sinks;
+mod smoothing;
+mod units;
+mod validate;
+
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use derived::{add_derived_measurements, DerivedMeasurement};
+use digest::DigestChallenge;
+use encoding_rs::{Encoding, UTF_8};
+use futures::future::{join_all, try_join_all};
+use influxdb2::api::write::TimestampPrecision;
+use log_file::{RotatingFile, DEFAULT_LOG_MAX_FILES, DEFAULT_LOG_MAX_SIZE_MB};
+use validate::validate;
+use mqtt_client::{parse_mqtt_version, MqttClient, MqttEvent, MqttVersion};
+use rand::Rng;
+use regex::Regex;
+use reqwest::{Certificate, Client};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+use rumqttc::{MqttOptions, QoS, TlsConfiguration, Transport};
+use serde::{Deserialize, Serialize};
 use serde_xml_rs::from_str;
+use sinks::{
+    valid_metric_name, InfluxSink, MeasurementSink, MqttSink, PrometheusSink, SqliteSink, StdoutSink, WebhookSink,
+    DEFAULT_PROMETHEUS_PREFIX,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::fmt;
 use std::fs;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tokio::time::sleep;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::EnvFilter;
+use purge::purge_retained;
+use smoothing::Smoothing;
+use units::{canonical_unit, canonicalize_units};
+
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_MAX_BACKOFF_SECS: u64 = 60;
+const DEFAULT_ERROR_COOLDOWN_SECS: u64 = 600;
+const DEFAULT_HTTP_REBUILD_AFTER_ERRORS: u32 = 5;
+const DEFAULT_NIGHT_MODE_AFTER_ERRORS: u32 = 10;
+const DEFAULT_NIGHT_MODE_POLL_INTERVAL_SECS: u64 = 600;
+const DEFAULT_HEALTH_MAX_AGE_SECS: u64 = 300;
+const DEFAULT_RAW_DUMP_MAX_FILES: usize = 1000;
+const MQTT_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_MQTT_RECONNECT_MAX_DELAY_SECS: u64 = 60;
+const DEFAULT_MQTT_CHANNEL_CAPACITY: usize = 10;
+const DEFAULT_MQTT_BIRTH_PAYLOAD: &str = "online";
+const DEFAULT_MQTT_WS_PATH: &str = "/mqtt";
+const MQTT_KEEP_ALIVE_SECS: u64 = 5;
+const SINK_CHECK_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_TOPIC_TEMPLATE: &str = "inverter/{serial}/{type}";
+const DISCOVERY_TOPIC_PREFIX: &str = "homeassistant/sensor";
+const FETCH_MS_TYPE: &str = "_fetch_ms";
+const DEFAULT_BRIDGE_HEALTH_TOPIC: &str = "inverter/{serial}/bridge";
+const INFLUX_TAGS: [&str; 4] = ["serial", "type", "unit", "name"];
+const TOPIC_PLACEHOLDERS: [&str; 4] = ["serial", "type", "name", "unit"];
+
+// Log target of the startup summary, so show_banner can filter it on its own.
+const BANNER_TARGET: &str = "SolBridXML2MQTT::banner";
+// Log target of the READY line, printed even in quiet_mode.
+const READY_TARGET: &str = "SolBridXML2MQTT::ready";
+// Exit codes besides 0 (success) and 1 (any other error).
+const EXIT_USAGE: u8 = 2;
+const EXIT_NOT_READY: u8 = 3;
+const USAGE: &str = "Usage: SolBridXML2MQTT [OPTIONS]
+
+Options:
+  -c, --config <PATH>  Load the configuration from PATH instead of the default locations
+      --discover       Fetch once, print every measurement type with its unit and a sample value and exit
+      --dry-run        Fetch and parse once, print the measurements and exit without publishing
+      --once           Poll and publish once, then exit with a non-zero code on failure
+      --print-example-config
+                       Print a commented example config.toml with every option and exit
+      --purge-retained <SERIAL>
+                       Clear the retained MQTT messages and discovery configs of SERIAL and exit
+      --validate       Check the config, the inverters, MQTT and InfluxDB without publishing and exit
+      --wait-ready <SECS>
+                       Exit with code 3 unless every inverter is polled successfully within SECS
+  -h, --help           Print this help and exit";
+const EXAMPLE_CONFIG: &str = include_str!("../config.example.toml");
+
+// --- Command Line Arguments ---
+
+#[derive(Debug, Default)]
+struct Args {
+    config_path: Option<String>,
+    discover: bool,
+    dry_run: bool,
+    once: bool,
+    print_example_config: bool,
+    purge_serial: Option<String>,
+    validate: bool,
+    wait_ready_secs: Option<u64>,
+    help: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a path argument.", arg))?;
+                parsed.config_path = Some(path);
+            }
+            "--discover" => parsed.discover = true,
+            "--dry-run" => parsed.dry_run = true,
+            "--once" => parsed.once = true,
+            "--print-example-config" => parsed.print_example_config = true,
+            "--purge-retained" => {
+                let serial = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a serial argument.", arg))?;
+                parsed.purge_serial = Some(serial);
+            }
+            "--validate" => parsed.validate = true,
+            "--wait-ready" => {
+                let secs = args
+                    .next()
+                    .and_then(|secs| secs.parse::<u64>().ok())
+                    .filter(|secs| *secs > 0)
+                    .ok_or_else(|| format!("{} requires a number of seconds greater than 0.", arg))?;
+                parsed.wait_ready_secs = Some(secs);
+            }
+            "-h" | "--help" => parsed.help = true,
+            _ => {
+                if let Some(path) = arg.strip_prefix("--config=") {
+                    parsed.config_path = Some(path.to_string());
+                } else {
+                    return Err(format!("Unknown argument: {} (see --help)", arg));
+                }
+            }
+        }
+    }
 
-const HTTP_TIMEOUT_SECS: u64 = 5;
+    if parsed.dry_run && parsed.once {
+        return Err("--dry-run and --once cannot be combined.".to_string());
+    }
+    if parsed.purge_serial.is_some() && (parsed.dry_run || parsed.once) {
+        return Err("--purge-retained cannot be combined with --dry-run or --once.".to_string());
+    }
+    if parsed.wait_ready_secs.is_some() && (parsed.dry_run || parsed.once || parsed.purge_serial.is_some()) {
+        return Err("--wait-ready cannot be combined with --dry-run, --once or --purge-retained.".to_string());
+    }
+    if parsed.discover
+        && (parsed.dry_run || parsed.once || parsed.purge_serial.is_some() || parsed.wait_ready_secs.is_some()) {
+        return Err("--discover cannot be combined with --dry-run, --once, --purge-retained or --wait-ready.".to_string());
+    }
+    if parsed.validate
+        && (parsed.discover
+            || parsed.dry_run
+            || parsed.once
+            || parsed.purge_serial.is_some()
+            || parsed.wait_ready_secs.is_some()) {
+        return Err("--validate cannot be combined with --discover, --dry-run, --once, --purge-retained or --wait-ready.".to_string());
+    }
+
+    Ok(parsed)
+}
 
 // --- New Nested Configuration Structs ---
 
 #[derive(Debug, Deserialize)]
 struct Config {
-    inverter_url: String,
-    poll_interval_secs: u64,
-    max_errors: u32,
+    // Single inverter setup. When [[inverter]] entries are used instead,
+    // poll_interval_secs and max_errors act as defaults for them.
+    inverter_url: Option<InverterUrls>,
+    // "basic" or "digest" for inverters behind HTTP authentication.
+    inverter_auth: Option<String>,
+    inverter_username: Option<String>,
+    inverter_password: Option<String>,
+    inverter_password_env: Option<String>,
+    // Extra request headers, e.g. an API key for a gateway in front of the inverter.
+    inverter_headers: Option<BTreeMap<String, String>>,
+    inverter_user_agent: Option<String>,
+    poll_interval_secs: Option<u64>,
+    // Shifts every poll randomly by up to this many seconds in either direction.
+    poll_jitter_secs: Option<f64>,
+    // Waits this long before the first poll, e.g. for the network after a boot.
+    initial_delay_secs: Option<u64>,
+    // false waits one poll_interval_secs before the first poll, after initial_delay_secs.
+    poll_on_start: Option<bool>,
+    max_errors: Option<u32>,
+    // IANA name like "Europe/Berlin". Inverter times without an offset are
+    // read as local time of this zone, and the log prints its local time.
+    timezone: Option<String>,
+    // Used for devices whose @Serial is empty.
+    serial_override: Option<String>,
+    // "index" or "name", how measurements repeating a @Type are told apart.
+    duplicate_types: Option<String>,
+    // Element names of the XML, "solbrid" or "solbrid-legacy". xml_root and
+    // device_element override single names of the profile.
+    firmware_profile: Option<String>,
+    xml_root: Option<String>,
+    device_element: Option<String>,
+    // Drops prefixes like <ns:Device> and the xmlns declarations before parsing.
+    strip_xml_namespaces: Option<bool>,
+    #[serde(default, rename = "inverter")]
+    inverters: Vec<InverterConfig>,
+    include_types: Option<Vec<String>>,
+    exclude_types: Option<Vec<String>>,
+    // Polls with more measurements than this (after the type filters) are
+    // treated as corrupt and skipped.
+    max_measurements: Option<usize>,
+    // Shorthand for show_banner = false and verbose_publishes = false, also
+    // lowers the default log level to warn.
     quiet_mode: Option<bool>,
+    // Startup configuration summary, defaults to !quiet_mode.
+    show_banner: Option<bool>,
+    // Logs every publish and write of the sinks.
+    verbose_publishes: Option<bool>,
+    // Upper bound for the poll delay while consecutive errors double it.
+    max_backoff_secs: Option<u64>,
+    // Whole request including the body; the connect timeout only covers
+    // establishing the TCP connection and defaults to the same value.
+    http_timeout_secs: Option<u64>,
+    http_connect_timeout_secs: Option<u64>,
+    // Connection reuse between polls. reqwest keeps idle connections for 90s
+    // without a limit per host; some firmware hangs on a reused socket, which
+    // http_keep_alive = false avoids by opening a new connection every poll.
+    http_keep_alive: Option<bool>,
+    http_pool_max_idle_per_host: Option<usize>,
+    http_pool_idle_timeout_secs: Option<u64>,
+    http_tcp_keepalive_secs: Option<u64>,
+    // Failed requests in a row before an inverter gets a new HTTP client, 0 never.
+    http_rebuild_after_errors: Option<u32>,
+    // PEM file with the CA (or the self-signed certificate itself) of HTTPS
+    // inverters, trusted in addition to the system roots.
+    http_ca_cert_path: Option<String>,
+    // Disables all certificate checks for HTTPS inverters. Last resort only.
+    http_danger_accept_invalid_certs: Option<bool>,
+    // Every fetched response is saved here before parsing, for debugging.
+    raw_dump_dir: Option<String>,
+    raw_dump_max_files: Option<usize>,
+    raw_dump_max_age_secs: Option<u64>,
+    // A response cut off mid-transfer is requested again once before the
+    // poll counts as failed.
+    retry_truncated: Option<bool>,
+    // A response that is complete but fails to parse is requested again up to
+    // this many times before the poll counts as failed.
+    parse_retries: Option<u32>,
+    // When false, reaching max_errors pauses the inverter instead of exiting.
+    restart_on_max_errors: Option<bool>,
+    error_cooldown_secs: Option<u64>,
 
     // These sections are Optional.
     // If [mqtt] is missing in TOML, this field will be None.
     mqtt: Option<MqttConfig>,
     influxdb: Option<InfluxDbConfig>,
+    influxdb_v1: Option<InfluxDbV1Config>,
+    stdout: Option<StdoutConfig>,
+    sqlite: Option<SqliteConfig>,
+    webhook: Option<WebhookConfig>,
+    prometheus: Option<PrometheusConfig>,
+    prometheus_measurements: Option<PrometheusMeasurementsConfig>,
+    health: Option<HealthConfig>,
+    logging: Option<LoggingConfig>,
+    night_mode: Option<NightModeConfig>,
+    // Keyed by measurement Type, applied before any sink sees the value.
+    #[serde(default)]
+    normalize: BTreeMap<String, NormalizeRule>,
+    // Keyed by measurement Type, values outside the range are dropped.
+    #[serde(default)]
+    limits: BTreeMap<String, LimitRule>,
+    // Adds the fetch time of each poll as measurement _fetch_ms.
+    publish_diagnostics: Option<bool>,
+    // Exit at startup when an output cannot be reached, instead of starting
+    // with the others and letting it catch up.
+    require_all_sinks: Option<bool>,
+    // Polls in a row that may return the same inverter timestamp before the
+    // data counts as stale, off without it.
+    stale_after_polls: Option<u32>,
+    // Drops stale devices instead of publishing them again.
+    skip_stale_data: Option<bool>,
+    // MQTT topic segment per measurement Type, e.g. AC_U_L1 = "grid_voltage".
+    #[serde(default)]
+    topic_map: BTreeMap<String, String>,
+    // Minimum seconds between two MQTT publishes of one topic, per measurement Type.
+    #[serde(default)]
+    throttle: BTreeMap<String, f64>,
+    // Per measurement Type, MQTT publishes only when the value moved further than this.
+    #[serde(default)]
+    deadband: BTreeMap<String, DeadbandRule>,
+    // Per measurement Type retain flag of its MQTT topic, over [mqtt] retain.
+    #[serde(default)]
+    retain: BTreeMap<String, bool>,
+    // Per measurement Type labels MQTT publishes instead of its status codes,
+    // e.g. Inverter_State = { 3 = "Running" }.
+    #[serde(default)]
+    state_map: BTreeMap<String, BTreeMap<String, String>>,
+    // Moving average per measurement Type, e.g. AC_Power = { window = 5 }.
+    #[serde(default)]
+    smoothing: BTreeMap<String, SmoothingRule>,
+    // Computed measurements, evaluated after normalization in this order.
+    #[serde(default)]
+    derived: Vec<DerivedConfig>,
 }
 
+// Plausible range of a measurement, e.g. AC_Voltage1 = { min = 0, max = 300 }.
 #[derive(Debug, Deserialize)]
-struct MqttConfig {
-    broker: String,
-    port: u16,
-    client_id: String,
+struct LimitRule {
+    min: Option<f64>,
+    max: Option<f64>,
 }
 
+// Exactly one of the two, absolute in the unit of the value or percent of
+// the last published value.
 #[derive(Debug, Deserialize)]
-struct InfluxDbConfig {
-    url: String,
-    token: String,
-    org: String,
-    bucket: String,
+struct DeadbandRule {
+    absolute: Option<f64>,
+    percent: Option<f64>,
 }
 
-// --- XML Parsing Structs (Unchanged) ---
-
 #[derive(Debug, Deserialize)]
-struct Root {
-    #[serde(rename = "Device")]
-    device: Device,
+struct SmoothingRule {
+    // Number of polls averaged.
+    window: usize,
+    // Also publishes the unsmoothed value as <Type>_raw.
+    publish_raw: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
-struct Device {
-    #[serde(rename = "@Name")]
+struct DerivedConfig {
     name: String,
-    #[serde(rename = "@Serial")]
-    serial: String,
-    #[serde(rename = "Measurements")]
-    measurements: Measurements,
+    // e.g. "AC_Power / DC_Power"
+    expression: String,
+    unit: Option<String>,
 }
 
+// Rescales a measurement, e.g. AC_Power = { from_unit = "W", factor = 0.001, unit = "kW" }.
 #[derive(Debug, Deserialize)]
-struct Measurements {
-    #[serde(rename = "Measurement")]
-    measurement: Vec<Measurement>,
+struct NormalizeRule {
+    // Only apply the rule when the inverter reports this unit.
+    from_unit: Option<String>,
+    factor: Option<f64>,
+    unit: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-struct Measurement {
-    #[serde(rename = "@Value")]
-    value: Option<String>,
-    #[serde(rename = "@Type")]
-    typ: String,
-    #[serde(rename = "@Unit")]
-    unit: Option<String>,
+struct InverterConfig {
+    url: InverterUrls,
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    password_env: Option<String>,
+    headers: Option<BTreeMap<String, String>>,
+    user_agent: Option<String>,
+    poll_interval_secs: Option<u64>,
+    poll_jitter_secs: Option<f64>,
+    max_errors: Option<u32>,
+    // Labels the log output of this inverter, the real serial comes from the XML.
+    serial: Option<String>,
+    include_types: Option<Vec<String>>,
+    exclude_types: Option<Vec<String>>,
+    max_measurements: Option<usize>,
+    timezone: Option<String>,
+    serial_override: Option<String>,
+    duplicate_types: Option<String>,
+    firmware_profile: Option<String>,
+    xml_root: Option<String>,
+    device_element: Option<String>,
+    strip_xml_namespaces: Option<bool>,
 }
 
-fn parse_value(value: &str) -> Option<f64> {
-    value.parse::<f64>().ok()
+// One entry of include_types or exclude_types: an exact @Type, a glob with
+// * and ? like "AC_U_*", or a regex between slashes like "/^AC_U_L[123]$/".
+#[derive(Debug, Clone)]
+enum TypePattern {
+    Exact(String),
+    Regex(Regex),
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // --- Configuration Loading ---
-    let config_paths = [
-        "config.toml",
-        "/etc/solbridxml2mqtt/config.toml",
-    ];
+impl TypePattern {
+    fn parse(pattern: &str, field: &str) -> Result<Self, String> {
+        let regex = if let Some(regex) = pattern.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+            regex.to_string()
+        } else if pattern.contains(['*', '?']) {
+            let glob: String = pattern
+                .chars()
+                .map(|c| match c {
+                    '*' => ".*".to_string(),
+                    '?' => ".".to_string(),
+                    c => regex::escape(&c.to_string()),
+                })
+                .collect();
+            format!("^{}$", glob)
+        } else {
+            return Ok(TypePattern::Exact(pattern.to_string()));
+        };
+        Regex::new(&regex)
+            .map(TypePattern::Regex)
+            .map_err(|e| format!("Invalid {} pattern '{}': {}", field, pattern, e))
+    }
 
-    let mut config_str = None;
-    let mut used_path = String::new();
+    fn matches(&self, typ: &str) -> bool {
+        match self {
+            TypePattern::Exact(exact) => exact == typ,
+            TypePattern::Regex(regex) => regex.is_match(typ),
+        }
+    }
+}
 
-    for path in &config_paths {
-        if let Ok(content) = fs::read_to_string(path) {
-            config_str = Some(content);
-            used_path = path.to_string();
-            break;
+// Selects measurements by their @Type. Without an include list every type
+// passes, the exclude list is applied afterwards.
+#[derive(Debug, Clone, Default)]
+struct TypeFilter {
+    include: Option<Vec<TypePattern>>,
+    exclude: Vec<TypePattern>,
+}
+
+impl TypeFilter {
+    fn new(include: Option<&Vec<String>>, exclude: Option<&Vec<String>>) -> Result<Self, String> {
+        let parse = |patterns: &Vec<String>, field: &str| {
+            patterns.iter().map(|pattern| TypePattern::parse(pattern, field)).collect::<Result<Vec<_>, _>>()
+        };
+        Ok(TypeFilter {
+            include: include.map(|types| parse(types, "include_types")).transpose()?,
+            exclude: exclude.map(|types| parse(types, "exclude_types")).transpose()?.unwrap_or_default(),
+        })
+    }
+
+    fn allows(&self, typ: &str) -> bool {
+        self.include.as_ref().is_none_or(|include| include.iter().any(|p| p.matches(typ)))
+            && !self.exclude.iter().any(|p| p.matches(typ))
+    }
+}
+
+// One URL or a list of them. Inverters that spread their data over several
+// XML endpoints are polled on all of them and the devices merged by serial.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum InverterUrls {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl InverterUrls {
+    fn resolve(&self) -> Result<Vec<String>, String> {
+        match self {
+            InverterUrls::One(url) => Ok(vec![url.clone()]),
+            InverterUrls::Many(urls) if urls.is_empty() => Err("Inverter URL list must not be empty.".to_string()),
+            InverterUrls::Many(urls) => Ok(urls.clone()),
         }
     }
+}
 
-    let config_str = config_str
-        .ok_or("Could not find config.toml in any of these locations.")?;
+// Where the XML comes from, decided once at startup from the URL scheme.
+#[derive(Debug, Clone)]
+enum Source {
+    Http(String),
+    File(PathBuf),
+}
 
-    let config: Config = toml::from_str(&config_str)
-        .map_err(|e| format!("Failed to parse config.toml: {}", e))?;
+impl Source {
+    fn from_url(url: &str) -> Result<Self, String> {
+        if !url.starts_with("file:") {
+            return Ok(Source::Http(url.to_string()));
+        }
+        reqwest::Url::parse(url)
+            .ok()
+            .and_then(|parsed| parsed.to_file_path().ok())
+            .map(Source::File)
+            .ok_or_else(|| format!("'{}' is not a valid local file URL, use file:///absolute/path.xml.", url))
+    }
+}
 
-    let quiet_mode = config.quiet_mode.unwrap_or(false);
+#[derive(Debug, Clone)]
+enum InverterAuth {
+    None,
+    Basic { username: String, password: String },
+    Digest { username: String, password: String },
+}
 
-    // --- Client Initialization ---
+impl InverterAuth {
+    fn resolve(
+        auth: Option<&str>,
+        username: Option<&String>,
+        password: Option<&String>,
+        password_env: Option<&String>,
+        url: &str,
+    ) -> Result<Self, String> {
+        let Some(auth) = auth else {
+            return Ok(InverterAuth::None);
+        };
 
-    let http_client = Client::builder()
-        .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
-        .build()?;
+        let username = username
+            .cloned()
+            .ok_or_else(|| format!("Inverter auth is set but no username was given for {}.", url))?;
+        let password = match (password, password_env) {
+            (Some(_), Some(_)) => {
+                return Err(format!("Inverter config sets both password and password_env for {}, use only one.", url));
+            }
+            (Some(password), None) => password.clone(),
+            (None, Some(var)) => std::env::var(var).map_err(|_| {
+                format!("Inverter password_env refers to '{}', but that environment variable is not set.", var)
+            })?,
+            (None, None) => return Err(format!("Inverter auth is set but no password was given for {}.", url)),
+        };
 
-    // MQTT Client Setup
-    // Now we just check if the `config.mqtt` struct exists
-    let mqtt_client_option = if let Some(mqtt_conf) = &config.mqtt {
-        if !quiet_mode {
-            println!("MQTT Configuration found: {}:{}", mqtt_conf.broker, mqtt_conf.port);
+        match auth {
+            "basic" => Ok(InverterAuth::Basic { username, password }),
+            "digest" => Ok(InverterAuth::Digest { username, password }),
+            other => Err(format!(
+                "Invalid inverter auth '{}' for {}, allowed values are \"basic\" and \"digest\".",
+                other, url
+            )),
         }
-        let mut mqttoptions = MqttOptions::new(&mqtt_conf.client_id, &mqtt_conf.broker, mqtt_conf.port);
-        mqttoptions.set_keep_alive(Duration::from_secs(5));
+    }
+}
 
-        let (mqtt_client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+fn build_headers(
+    headers: Option<&BTreeMap<String, String>>,
+    user_agent: Option<&String>,
+    url: &str,
+) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers.into_iter().flatten() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name '{}' for inverter {}.", name, url))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid value for header '{}' for inverter {}.", name, url))?;
+        map.insert(name, value);
+    }
+    if let Some(user_agent) = user_agent {
+        let value = HeaderValue::from_str(user_agent)
+            .map_err(|_| format!("Invalid user_agent '{}' for inverter {}.", user_agent, url))?;
+        map.insert(USER_AGENT, value);
+    }
+    Ok(map)
+}
 
-        tokio::spawn(async move {
-            loop {
-                if let Err(e) = eventloop.poll().await {
-                    eprintln!("MQTT Eventloop Error: {:?}", e);
-                    sleep(Duration::from_secs(1)).await;
-                }
+// One polling task is spawned per Inverter, resolved from the config above.
+#[derive(Debug, Clone)]
+struct Inverter {
+    // All URLs joined, for log and error messages.
+    url: String,
+    urls: Vec<String>,
+    sources: Vec<Source>,
+    auth: InverterAuth,
+    headers: HeaderMap,
+    poll_interval_secs: u64,
+    poll_jitter_secs: f64,
+    max_errors: u32,
+    label: String,
+    filter: TypeFilter,
+    max_measurements: Option<usize>,
+    timezone: Option<Tz>,
+    retry_truncated: bool,
+    parse_retries: u32,
+    serial_override: Option<String>,
+    duplicate_types: DuplicateTypes,
+    xml_shape: XmlShape,
+}
+
+fn parse_timezone(name: Option<&str>) -> Result<Option<Tz>, String> {
+    name.map(|name| {
+        name.parse::<Tz>()
+            .map_err(|_| format!("Unknown timezone '{}', use an IANA name like \"Europe/Berlin\".", name))
+    })
+    .transpose()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DuplicateTypes {
+    Index,
+    Name,
+}
+
+fn parse_duplicate_types(value: Option<&str>) -> Result<DuplicateTypes, String> {
+    match value {
+        None | Some("index") => Ok(DuplicateTypes::Index),
+        Some("name") => Ok(DuplicateTypes::Name),
+        Some(other) => Err(format!(
+            "Invalid duplicate_types '{}', allowed values are \"index\" and \"name\".",
+            other
+        )),
+    }
+}
+
+// --- Firmware Profiles ---
+
+// Element names of one XML layout. Measurements may sit in a Measurements
+// element or directly inside the device, that needs no setting.
+#[derive(Debug, Clone, PartialEq)]
+struct XmlShape {
+    // Checked only when set, the parser itself ignores the root name.
+    root: Option<String>,
+    device: String,
+    strip_namespaces: bool,
+}
+
+impl XmlShape {
+    fn resolve(
+        profile: Option<&str>,
+        root: Option<&str>,
+        device: Option<&str>,
+        strip_namespaces: bool,
+    ) -> Result<Self, String> {
+        let (profile_root, profile_device) = match profile {
+            // <root><Device><Measurements><Measurement/>
+            None | Some("solbrid") => (None, "Device"),
+            // <SolBrid><Inverter><Measurement/>
+            Some("solbrid-legacy") => (Some("SolBrid"), "Inverter"),
+            Some(other) => {
+                return Err(format!(
+                    "Unknown firmware_profile '{}', allowed values are \"solbrid\" and \"solbrid-legacy\".",
+                    other
+                ));
             }
-        });
-        Some(mqtt_client)
-    } else {
-        None
-    };
+        };
+        for (field, name) in [("xml_root", root), ("device_element", device)] {
+            if let Some(name) = name
+                && (name.is_empty() || name.contains(|c: char| c.is_whitespace() || "<>/\"'=".contains(c))) {
+                return Err(format!("{} '{}' is not a valid XML element name.", field, name));
+            }
+        }
+        Ok(XmlShape {
+            root: root.or(profile_root).map(str::to_string),
+            device: device.unwrap_or(profile_device).to_string(),
+            strip_namespaces,
+        })
+    }
 
-    // InfluxDB Client Setup
-    let influx_client_option = if let Some(influx_conf) = &config.influxdb {
-        if !quiet_mode {
-            println!("InfluxDB Configuration found: {}", influx_conf.url);
+    // Strips the namespaces if asked to, checks the root element and renames
+    // the device elements to <Device> for the parser.
+    fn adapt<'a>(&self, xml: &'a str) -> Result<Cow<'a, str>, String> {
+        let xml = if self.strip_namespaces { strip_namespaces(xml) } else { Cow::Borrowed(xml) };
+        if let Some(expected) = &self.root
+            && let Some(found) = root_element(&xml)
+            && found != expected {
+            return Err(format!(
+                "XML root element is <{}>, expected <{}>. Check firmware_profile and xml_root.",
+                found, expected
+            ));
         }
-        Some(InfluxClient::new(
-            &influx_conf.url,
-            &influx_conf.org,
-            &influx_conf.token,
-        ))
-    } else {
-        None
-    };
+        if self.device == "Device" {
+            return Ok(xml);
+        }
+        Ok(Cow::Owned(rename_element(&xml, &self.device, "Device")))
+    }
+}
+
+// <ns:Device ns:Serial='1' xmlns:ns='urn:x'> becomes <Device Serial='1'>, the
+// element and attribute names the parser knows. Comments, CDATA, the XML
+// declaration and attribute values are left alone.
+fn strip_namespaces(xml: &str) -> Cow<'_, str> {
+    if !xml.contains(':') && !xml.contains("xmlns") {
+        return Cow::Borrowed(xml);
+    }
+    let local_name = |name: &str| name.rsplit(':').next().unwrap_or(name).to_string();
+    let mut stripped = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let skip_to = [("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>"), ("<!", ">")]
+            .into_iter()
+            .find(|(open, _)| rest.starts_with(open))
+            .map(|(_, close)| rest.find(close).map_or(rest.len(), |end| end + close.len()));
+        if let Some(end) = skip_to {
+            stripped.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        // <name, </name or the attributes up to > of one tag.
+        let closing = rest.starts_with("</");
+        stripped.push_str(if closing { "</" } else { "<" });
+        rest = &rest[if closing { 2 } else { 1 }..];
+        let name_end = rest.find(|c: char| c.is_whitespace() || c == '/' || c == '>').unwrap_or(rest.len());
+        stripped.push_str(&local_name(&rest[..name_end]));
+        rest = &rest[name_end..];
+        loop {
+            let trimmed = rest.trim_start();
+            let space = &rest[..rest.len() - trimmed.len()];
+            if trimmed.is_empty() || trimmed.starts_with('>') || trimmed.starts_with('/') {
+                stripped.push_str(space);
+                rest = trimmed;
+                break;
+            }
+            let name_end = trimmed.find(|c: char| c.is_whitespace() || c == '=' || c == '>').unwrap_or(trimmed.len());
+            let name = &trimmed[..name_end];
+            let after_name = &trimmed[name_end..];
+            // The value, including the = and its quotes.
+            let value_len = after_name
+                .trim_start()
+                .strip_prefix('=')
+                .map(str::trim_start)
+                .and_then(|value| {
+                    let quote = value.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+                    let close = value[1..].find(quote)?;
+                    Some(after_name.len() - value.len() + close + 2)
+                })
+                .unwrap_or(0);
+            if name != "xmlns" && !name.starts_with("xmlns:") {
+                stripped.push_str(space);
+                stripped.push_str(&local_name(name));
+                stripped.push_str(&after_name[..value_len]);
+            }
+            rest = &after_name[value_len..];
+            if name.is_empty() && value_len == 0 {
+                // Not an attribute, the parser reports whatever this is.
+                stripped.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+        let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+        stripped.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    stripped.push_str(rest);
+    Cow::Owned(stripped)
+}
+
+fn root_element(xml: &str) -> Option<&str> {
+    let mut rest = xml.trim_start();
+    while rest.starts_with("<?") || rest.starts_with("<!") {
+        let end = if rest.starts_with("<!--") { rest.find("-->")? + 3 } else { rest.find('>')? + 1 };
+        rest = rest[end..].trim_start();
+    }
+    rest.strip_prefix('<')?
+        .split(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .next()
+        .filter(|name| !name.is_empty())
+}
 
-    if mqtt_client_option.is_none() && influx_client_option.is_none() {
-        return Err("No valid MQTT or InfluxDB configuration found. Please check your config.toml.".into());
+// Renames the opening and closing tags of `from`, e.g. <Inverter> but not <InverterInfo>.
+fn rename_element(xml: &str, from: &str, to: &str) -> String {
+    let mut renamed = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        renamed.push_str(&rest[..=start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('/') {
+            renamed.push('/');
+            rest = after;
+        }
+        if let Some(after) = rest.strip_prefix(from)
+            && after.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+            renamed.push_str(to);
+            rest = after;
+        }
     }
+    renamed.push_str(rest);
+    renamed
+}
+
+fn resolve_inverters(config: &Config) -> Result<Vec<Inverter>, String> {
+    let missing = |field: &str, url: &str| format!("{} is not set for inverter {}.", field, url);
 
-    if !quiet_mode {
-        println!("--- Startup Configuration ---");
-        println!("Using configuration from: {}", used_path);
-        println!("Inverter URL: {}", config.inverter_url);
-        println!("Poll Interval: {}s", config.poll_interval_secs);
-        println!("-----------------------------");
+    if config.inverters.is_empty() {
+        let urls = config
+            .inverter_url
+            .as_ref()
+            .ok_or("No inverter configured, set inverter_url or add [[inverter]] entries.")?
+            .resolve()?;
+        let url = urls.join(", ");
+        return Ok(vec![Inverter {
+            sources: urls.iter().map(|url| Source::from_url(url)).collect::<Result<_, _>>()?,
+            auth: InverterAuth::resolve(
+                config.inverter_auth.as_deref(),
+                config.inverter_username.as_ref(),
+                config.inverter_password.as_ref(),
+                config.inverter_password_env.as_ref(),
+                &url,
+            )?,
+            headers: build_headers(config.inverter_headers.as_ref(), config.inverter_user_agent.as_ref(), &url)?,
+            poll_interval_secs: config.poll_interval_secs.ok_or_else(|| missing("poll_interval_secs", &url))?,
+            poll_jitter_secs: config.poll_jitter_secs.unwrap_or(0.0),
+            max_errors: config.max_errors.ok_or_else(|| missing("max_errors", &url))?,
+            label: url.clone(),
+            filter: TypeFilter::new(config.include_types.as_ref(), config.exclude_types.as_ref())?,
+            max_measurements: config.max_measurements,
+            timezone: parse_timezone(config.timezone.as_deref())?,
+            retry_truncated: config.retry_truncated.unwrap_or(true),
+            parse_retries: config.parse_retries.unwrap_or(0),
+            serial_override: config.serial_override.clone(),
+            duplicate_types: parse_duplicate_types(config.duplicate_types.as_deref())?,
+            xml_shape: XmlShape::resolve(
+                config.firmware_profile.as_deref(),
+                config.xml_root.as_deref(),
+                config.device_element.as_deref(),
+                config.strip_xml_namespaces.unwrap_or(false),
+            )?,
+            url,
+            urls,
+        }]);
     }
 
-    let mut error_count = 0;
+    if config.inverter_url.is_some() {
+        return Err("Use either inverter_url or [[inverter]] entries, not both.".to_string());
+    }
 
-    loop {
-        match http_client.get(&config.inverter_url).send().await {
-            Ok(resp) => {
-                match resp.text().await {
-                    Ok(xml_str) => {
-                        match from_str::<Root>(&xml_str) {
-                            Ok(root) => {
-                                error_count = 0;
-                                let device_serial = &root.device.serial;
-
-                                if !quiet_mode {
-                                    println!("Device: {:?}", root.device.name);
-                                }
-
-                                let mut influx_points: Vec<DataPoint> = Vec::new();
-
-                                for measurement in &root.device.measurements.measurement {
-                                    if let Some(value_str) = &measurement.value {
-                                        let measurement_name = &measurement.typ;
-                                        let unit_str = measurement.unit.as_deref().unwrap_or("");
-
-                                        // 1. MQTT Publish
-                                        if let Some(mqtt_client) = &mqtt_client_option {
-                                            let topic = format!("inverter/{}/{}", device_serial, measurement_name);
-                                            let payload = format!("{} {}", value_str, unit_str).trim().to_string();
-
-                                            if let Err(e) = mqtt_client
-                                                .publish(&topic, QoS::AtLeastOnce, false, payload.as_bytes())
-                                                .await {
-                                                eprintln!("MQTT Publish Error: {:?}", e);
-                                            } else {
-                                                if !quiet_mode {
-                                                    println!("MQTT Published: {} = {}", topic, payload);
-                                                }
-                                            }
-                                        }
-
-                                        // 2. InfluxDB Point Preparation
-                                        if let Some(_influx_client) = &influx_client_option {
-                                            if let Some(value) = parse_value(value_str) {
-                                                let mut builder = DataPoint::builder("inverter_data")
-                                                    .tag("serial", device_serial.as_str())
-                                                    .tag("type", measurement_name.as_str())
-                                                    .field("value", value);
-
-                                                if let Some(unit) = &measurement.unit {
-                                                    builder = builder.tag("unit", unit.as_str());
-                                                }
-
-                                                if let Ok(point) = builder.build() {
-                                                    influx_points.push(point);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-
-                                // 3. InfluxDB Write Batch
-                                // We access the bucket from the struct now: config.influxdb.as_ref().unwrap().bucket
-                                if let Some(influx_client) = &influx_client_option {
-                                    if !influx_points.is_empty() {
-                                        // Safe to unwrap here because we know influx_client_option is Some
-                                        let bucket = &config.influxdb.as_ref().unwrap().bucket;
-
-                                        let points_stream = stream::iter(influx_points);
-
-                                        match influx_client.write(bucket, points_stream).await {
-                                            Ok(_) => {
-                                                if !quiet_mode {
-                                                    println!("InfluxDB Write Success");
-                                                }
-                                            },
-                                            Err(e) => {
-                                                error_count += 1;
-                                                eprintln!("InfluxDB Write Error: {:?}", e);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error_count += 1;
-                                eprintln!("XML Parse Error: {:?}", e);
-                            }
-                        }
+    config
+        .inverters
+        .iter()
+        .map(|entry| {
+            let urls = entry.url.resolve()?;
+            let url = urls.join(", ");
+            Ok(Inverter {
+                sources: urls.iter().map(|url| Source::from_url(url)).collect::<Result<_, _>>()?,
+                auth: InverterAuth::resolve(
+                    entry.auth.as_deref().or(config.inverter_auth.as_deref()),
+                    entry.username.as_ref().or(config.inverter_username.as_ref()),
+                    entry.password.as_ref().or(config.inverter_password.as_ref()),
+                    entry.password_env.as_ref().or(config.inverter_password_env.as_ref()),
+                    &url,
+                )?,
+                headers: build_headers(
+                    entry.headers.as_ref().or(config.inverter_headers.as_ref()),
+                    entry.user_agent.as_ref().or(config.inverter_user_agent.as_ref()),
+                    &url,
+                )?,
+                poll_interval_secs: entry
+                    .poll_interval_secs
+                    .or(config.poll_interval_secs)
+                    .ok_or_else(|| missing("poll_interval_secs", &url))?,
+                poll_jitter_secs: entry.poll_jitter_secs.or(config.poll_jitter_secs).unwrap_or(0.0),
+                max_errors: entry
+                    .max_errors
+                    .or(config.max_errors)
+                    .ok_or_else(|| missing("max_errors", &url))?,
+                label: entry.serial.clone().unwrap_or_else(|| url.clone()),
+                filter: TypeFilter::new(
+                    entry.include_types.as_ref().or(config.include_types.as_ref()),
+                    entry.exclude_types.as_ref().or(config.exclude_types.as_ref()),
+                )?,
+                max_measurements: entry.max_measurements.or(config.max_measurements),
+                timezone: parse_timezone(entry.timezone.as_deref().or(config.timezone.as_deref()))?,
+                retry_truncated: config.retry_truncated.unwrap_or(true),
+                parse_retries: config.parse_retries.unwrap_or(0),
+                serial_override: entry.serial_override.clone().or_else(|| config.serial_override.clone()),
+                duplicate_types: parse_duplicate_types(
+                    entry.duplicate_types.as_deref().or(config.duplicate_types.as_deref()),
+                )?,
+                xml_shape: XmlShape::resolve(
+                    entry.firmware_profile.as_deref().or(config.firmware_profile.as_deref()),
+                    entry.xml_root.as_deref().or(config.xml_root.as_deref()),
+                    entry.device_element.as_deref().or(config.device_element.as_deref()),
+                    entry.strip_xml_namespaces.or(config.strip_xml_namespaces).unwrap_or(false),
+                )?,
+                url,
+                urls,
+            })
+        })
+        .collect()
+}
+
+fn validate_influx_points(points: &InfluxPointConfig, problems: &mut Vec<String>) {
+    if let Err(e) = parse_influx_precision(points.precision.as_deref()) {
+        problems.push(e);
+    }
+    if let Err(e) = parse_influx_schema(points.schema.as_deref()) {
+        problems.push(e);
+    }
+    for (typ, field_type) in points.field_types.iter().flatten() {
+        if let Err(e) = parse_field_type(field_type) {
+            problems.push(format!("{} (field_types.{})", e, typ));
+        }
+    }
+    if points.measurement.as_ref().is_some_and(|m| m.trim().is_empty()) {
+        problems.push("InfluxDB measurement must not be empty.".to_string());
+    }
+    if points.batch_size == Some(0) {
+        problems.push("InfluxDB batch_size must be at least 1.".to_string());
+    }
+    if points.batch_interval_secs == Some(0) {
+        problems.push("InfluxDB batch_interval_secs must be at least 1.".to_string());
+    }
+    for (key, value) in points.extra_tags.iter().flatten() {
+        if key.trim().is_empty() || value.trim().is_empty() {
+            problems.push(format!("InfluxDB extra_tags must not have empty keys or values ('{}' = '{}').", key, value));
+        } else if INFLUX_TAGS.contains(&key.as_str()) {
+            problems.push(format!("InfluxDB extra_tags must not override the built-in tag '{}'.", key));
+        }
+    }
+}
+
+// Checks everything that can be checked without network access and reports
+// all problems at once instead of stopping at the first one.
+fn validate_config(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    match resolve_inverters(config) {
+        Ok(inverters) => {
+            for inverter in &inverters {
+                if inverter.poll_interval_secs == 0 {
+                    problems.push(format!("poll_interval_secs must be greater than 0 for inverter {}.", inverter.url));
+                }
+                if !(0.0..inverter.poll_interval_secs as f64).contains(&inverter.poll_jitter_secs) {
+                    problems.push(format!(
+                        "poll_jitter_secs must be at least 0 and smaller than poll_interval_secs for inverter {}.",
+                        inverter.url
+                    ));
+                }
+                if inverter.max_errors == 0 {
+                    problems.push(format!("max_errors must be at least 1 for inverter {}.", inverter.url));
+                }
+                if inverter.max_measurements == Some(0) {
+                    problems.push(format!("max_measurements must be at least 1 for inverter {}.", inverter.url));
+                }
+                if let Some(serial) = &inverter.serial_override
+                    && (serial.trim().is_empty() || serial.contains(['/', '+', '#'])) {
+                    problems.push(format!(
+                        "serial_override '{}' for inverter {} must not be empty or contain '/', '+' or '#'.",
+                        serial, inverter.url
+                    ));
+                }
+                for url in &inverter.urls {
+                    if let Err(e) = validate_inverter_url(url) {
+                        problems.push(format!("Inverter URL {}", e));
                     }
-                    Err(e) => eprintln!("Response Text Error: {:?}", e),
                 }
             }
-            Err(e) => {
-                error_count += 1;
-                eprintln!("Request Error: {:?}", e);
+        }
+        Err(e) => problems.push(e),
+    }
+    if config.stale_after_polls == Some(0) {
+        problems.push("stale_after_polls must be at least 1.".to_string());
+    }
+    if config.skip_stale_data.is_some() && config.stale_after_polls.is_none() {
+        problems.push("skip_stale_data is set but stale_after_polls is missing.".to_string());
+    }
+
+    if let Some(mqtt_conf) = &config.mqtt {
+        if mqtt_conf.broker.trim().is_empty() {
+            problems.push("MQTT broker must not be empty.".to_string());
+        }
+        if mqtt_conf.client_id.trim().is_empty() {
+            problems.push("MQTT client_id must not be empty.".to_string());
+        }
+        if mqtt_conf.max_reconnect_attempts == Some(0) {
+            problems.push("MQTT max_reconnect_attempts must be at least 1.".to_string());
+        }
+        if mqtt_conf.channel_capacity == Some(0) {
+            problems.push("MQTT channel_capacity must be at least 1.".to_string());
+        }
+        if mqtt_conf.birth_payload.is_some() && mqtt_conf.birth_topic.is_none() {
+            problems.push("MQTT birth_payload is set but birth_topic is missing.".to_string());
+        }
+        if mqtt_conf.base_topic.as_deref().is_some_and(|base| base.contains(['+', '#'])) {
+            problems.push("MQTT base_topic must not contain '+' or '#'.".to_string());
+        }
+        if mqtt_conf.user_properties.is_some() && parse_mqtt_version(mqtt_conf.mqtt_version) == Ok(MqttVersion::V311) {
+            problems.push("MQTT user_properties need mqtt_version = 5.".to_string());
+        }
+        let encoding = parse_payload_encoding(mqtt_conf.payload_encoding.as_deref());
+        if matches!(encoding, Ok(e) if e != PayloadEncoding::String)
+            && parse_payload_format(mqtt_conf.payload_format.as_deref()) == Ok(PayloadFormat::Json) {
+            problems.push("MQTT payload_encoding only applies to payload_format = \"individual\".".to_string());
+        }
+        if matches!(encoding, Ok(PayloadEncoding::MessagePack | PayloadEncoding::Float32Le))
+            && mqtt_conf.homeassistant_discovery.unwrap_or(false) {
+            problems.push("MQTT homeassistant_discovery needs payload_encoding \"string\" or \"json\".".to_string());
+        }
+        let checks = [
+            mqtt_credentials(mqtt_conf).err(),
+            parse_qos(mqtt_conf.qos).err(),
+            parse_mqtt_version(mqtt_conf.mqtt_version).err(),
+            parse_mqtt_transport(mqtt_conf).err(),
+            parse_payload_format(mqtt_conf.payload_format.as_deref()).err(),
+            encoding.err(),
+            parse_channel_full(mqtt_conf.channel_full.as_deref()).err(),
+            mqtt_conf.topic_template.as_deref().and_then(|t| TopicTemplate::parse(t).err()),
+        ];
+        problems.extend(checks.into_iter().flatten());
+    }
+
+    if let Some(influx_conf) = &config.influxdb {
+        if let Err(e) = validate_url(&influx_conf.url) {
+            problems.push(format!("InfluxDB url {}", e));
+        }
+        for (field, value) in [("bucket", &influx_conf.bucket), ("org", &influx_conf.org)] {
+            if value.trim().is_empty() {
+                problems.push(format!("InfluxDB {} must not be empty.", field));
+            }
+        }
+        if let Err(e) = influx_token(influx_conf) {
+            problems.push(e);
+        }
+        validate_influx_points(&influx_conf.points, &mut problems);
+    }
+
+    if let Some(influx_conf) = &config.influxdb_v1 {
+        if config.influxdb.is_some() {
+            problems.push("Use either [influxdb] (InfluxDB 2.x) or [influxdb_v1] (InfluxDB 1.x), not both.".to_string());
+        }
+        if let Err(e) = validate_url(&influx_conf.url) {
+            problems.push(format!("InfluxDB url {}", e));
+        }
+        if influx_conf.database.trim().is_empty() {
+            problems.push("InfluxDB database must not be empty.".to_string());
+        }
+        if influx_conf.username.is_some() != influx_conf.password.is_some() {
+            problems.push("InfluxDB username and password must be set together.".to_string());
+        }
+        validate_influx_points(&influx_conf.points, &mut problems);
+    }
+
+    if let Some(stdout_conf) = &config.stdout
+        && let Err(e) = parse_stdout_format(stdout_conf.format.as_deref()) {
+        problems.push(e);
+    }
+
+    if config.sqlite.as_ref().is_some_and(|sqlite_conf| sqlite_conf.path.trim().is_empty()) {
+        problems.push("SQLite path must not be empty.".to_string());
+    }
+
+    if let Some(webhook_conf) = &config.webhook {
+        if let Err(e) = validate_url(&webhook_conf.url) {
+            problems.push(format!("Webhook url {}", e));
+        }
+        if webhook_conf.batch_size == Some(0) {
+            problems.push("Webhook batch_size must be at least 1.".to_string());
+        }
+        if webhook_conf.authorization.is_some() && webhook_conf.authorization_env.is_some() {
+            problems.push("Webhook config sets both authorization and authorization_env, use only one.".to_string());
+        }
+    }
+
+    for (typ, rule) in &config.normalize {
+        if rule.factor.is_some_and(|f| !f.is_finite() || f == 0.0) {
+            problems.push(format!("normalize factor for {} must be a finite, non-zero number.", typ));
+        }
+        if rule.factor.is_none() && rule.unit.is_none() {
+            problems.push(format!("normalize rule for {} needs a factor or a unit.", typ));
+        }
+    }
+    for (typ, rule) in &config.limits {
+        match (rule.min, rule.max) {
+            (None, None) => problems.push(format!("limits for {} need a min or a max.", typ)),
+            (Some(min), Some(max)) if min > max => {
+                problems.push(format!("limits min for {} must not be greater than max.", typ));
+            }
+            _ => {}
+        }
+        if rule.min.into_iter().chain(rule.max).any(|v| !v.is_finite()) {
+            problems.push(format!("limits for {} must be finite numbers.", typ));
+        }
+    }
+    let mut topic_targets: BTreeMap<&str, &str> = BTreeMap::new();
+    for (typ, segment) in &config.topic_map {
+        if segment.trim().is_empty() || segment.contains(['/', '+', '#']) {
+            problems.push(format!("topic_map name '{}' for {} must be one topic level without '/', '+' or '#'.", segment, typ));
+        } else if let Some(other) = topic_targets.insert(segment, typ) {
+            problems.push(format!("topic_map maps both {} and {} to '{}'.", other, typ, segment));
+        }
+    }
+    for (typ, rule) in &config.smoothing {
+        if rule.window == 0 {
+            problems.push(format!("smoothing window for {} must be at least 1.", typ));
+        }
+    }
+    for (typ, secs) in &config.throttle {
+        if !(secs.is_finite() && *secs > 0.0) {
+            problems.push(format!("throttle for {} must be a number of seconds greater than 0.", typ));
+        }
+    }
+    for (typ, rule) in &config.deadband {
+        match (rule.absolute, rule.percent) {
+            (Some(threshold), None) | (None, Some(threshold)) if threshold.is_finite() && threshold >= 0.0 => {}
+            (Some(_), None) | (None, Some(_)) => {
+                problems.push(format!("deadband for {} must be a finite number of at least 0.", typ))
+            }
+            _ => problems.push(format!("deadband for {} needs either absolute or percent.", typ)),
+        }
+    }
+
+    for (typ, labels) in &config.state_map {
+        for (code, label) in labels {
+            if label.trim().is_empty() {
+                problems.push(format!("state_map label of {} code {} must not be empty.", typ, code));
+            }
+        }
+    }
+
+    for derived in &config.derived {
+        if let Err(e) = DerivedMeasurement::parse(derived) {
+            problems.push(e);
+        }
+    }
+
+    if config.http_timeout_secs == Some(0) {
+        problems.push("http_timeout_secs must be greater than 0.".to_string());
+    }
+    if config.http_connect_timeout_secs == Some(0) {
+        problems.push("http_connect_timeout_secs must be greater than 0.".to_string());
+    }
+    if let Some(path) = &config.http_ca_cert_path
+        && let Err(e) = load_ca_cert(path, "Inverter") {
+        problems.push(e);
+    }
+    if config.http_tcp_keepalive_secs == Some(0) {
+        problems.push("http_tcp_keepalive_secs must be greater than 0.".to_string());
+    }
+    if config.http_keep_alive == Some(false)
+        && (config.http_pool_max_idle_per_host.is_some() || config.http_pool_idle_timeout_secs.is_some()) {
+        problems.push("http_pool_* settings have no effect with http_keep_alive = false.".to_string());
+    }
+
+    if let Some(dir) = &config.raw_dump_dir {
+        if !fs::metadata(dir).is_ok_and(|m| m.is_dir()) {
+            problems.push(format!("raw_dump_dir '{}' is not an existing directory.", dir));
+        }
+        if config.raw_dump_max_files == Some(0) {
+            problems.push("raw_dump_max_files must be at least 1.".to_string());
+        }
+    }
+
+    if let Some(health_conf) = &config.health
+        && health_conf.max_age_secs == Some(0) {
+        problems.push("health max_age_secs must be greater than 0.".to_string());
+    }
+
+    if let Some(measurements_conf) = &config.prometheus_measurements {
+        if config.prometheus.as_ref().is_some_and(|p| p.listen == measurements_conf.listen) {
+            problems.push("prometheus_measurements listen must differ from the one of [prometheus].".to_string());
+        }
+        let prefix = measurements_conf.prefix.as_deref().unwrap_or(DEFAULT_PROMETHEUS_PREFIX);
+        if !prefix.is_empty() && !valid_metric_name(prefix) {
+            problems.push(format!(
+                "prometheus_measurements prefix '{}' may only contain letters, digits, '_' and ':' and not start with a digit.",
+                prefix
+            ));
+        }
+        if measurements_conf.max_age_secs == Some(0) {
+            problems.push("prometheus_measurements max_age_secs must be greater than 0.".to_string());
+        }
+    }
+
+    if let Some(night_conf) = &config.night_mode {
+        let after_errors = night_conf.after_errors.unwrap_or(DEFAULT_NIGHT_MODE_AFTER_ERRORS);
+        if after_errors == 0 {
+            problems.push("night_mode after_errors must be at least 1.".to_string());
+        }
+        if night_conf.poll_interval_secs == Some(0) {
+            problems.push("night_mode poll_interval_secs must be greater than 0.".to_string());
+        }
+        // Otherwise max_errors ends the polling before night mode ever starts.
+        for inverter in resolve_inverters(config).iter().flatten() {
+            if after_errors >= inverter.max_errors {
+                problems.push(format!(
+                    "night_mode after_errors ({}) must be smaller than max_errors ({}) of inverter {}.",
+                    after_errors, inverter.max_errors, inverter.url
+                ));
             }
         }
+    }
 
-        if error_count >= config.max_errors {
-            return Err(format!("Too many errors ({}), stopping.", error_count).into());
+    if let Some(logging_conf) = &config.logging {
+        if logging_conf.file.as_deref().is_some_and(|file| file.trim().is_empty()) {
+            problems.push("logging file must not be empty.".to_string());
         }
+        if logging_conf.max_size_mb == Some(0) {
+            problems.push("logging max_size_mb must be greater than 0.".to_string());
+        }
+    }
 
-        sleep(Duration::from_secs(config.poll_interval_secs)).await;
+    problems
+}
+
+// --- Environment Variables in the Config ---
+
+// Replaces ${NAME} in every string value, so secrets like the InfluxDB token
+// can stay out of the file. Strings without a placeholder are kept as they are.
+fn load_config(config_str: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+    // Parsed once as written first, so type errors point to the line of the file.
+    toml::from_str::<Config>(config_str).map_err(|e| format!("Failed to parse config.toml: {}", e))?;
+
+    let mut value: toml::Value =
+        toml::from_str(config_str).map_err(|e| format!("Failed to parse config.toml: {}", e))?;
+    let mut missing = Vec::new();
+    expand_env(&mut value, &lookup, &mut missing)?;
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        return Err(format!("config.toml references unset environment variables: {}", missing.join(", ")));
     }
-}
\ No newline at end of file
+    value.try_into().map_err(|e| format!("Failed to parse config.toml: {}", e))
+}
+
+fn expand_env(
+    value: &mut toml::Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+    missing: &mut Vec<String>,
+) -> Result<(), String> {
+    match value {
+        toml::Value::String(text) => {
+            let mut expanded = String::new();
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find("${") {
+                expanded.push_str(&rest[..start]);
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or_else(|| format!("config.toml value '{}' has an unclosed '${{'.", text))?;
+                let name = &rest[start + 2..start + end];
+                if name.is_empty() {
+                    return Err(format!("config.toml value '{}' has an empty '${{}}'.", text));
+                }
+                match lookup(name) {
+                    Some(env_value) => expanded.push_str(&env_value),
+                    None => missing.push(name.to_string()),
+                }
+                rest = &rest[start + end + 1..];
+            }
+            expanded.push_str(rest);
+            *text = expanded;
+        }
+        toml::Value::Array(values) => {
+            for value in values {
+                expand_env(value, lookup, missing)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                expand_env(value, lookup, missing)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn validate_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => Ok(()),
+        Ok(parsed) => Err(format!("'{}' has unsupported scheme '{}', use http or https.", url, parsed.scheme())),
+        Err(e) => Err(format!("'{}' is not a valid URL: {}", url, e)),
+    }
+}
+
+fn validate_inverter_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "file" => Source::from_url(url).map(|_| ()),
+        Ok(_) => validate_url(url),
+        Err(e) => Err(format!("'{}' is not a valid URL: {}", url, e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MqttConfig {
+    broker: String,
+    port: u16,
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    // Name of an environment variable holding the password, so it does not
+    // have to be stored in plaintext in config.toml.
+    password_env: Option<String>,
+    // File holding the password, e.g. a mounted Kubernetes secret.
+    password_file: Option<String>,
+    tls: Option<bool>,
+    // "tcp" (default), "tls", "ws" or "wss". tls = true is the same as "tls".
+    transport: Option<String>,
+    // URL path of the WebSocket endpoint for "ws" and "wss".
+    path: Option<String>,
+    // PEM file with the CA certificate(s) of the broker, also used for "wss".
+    // Without it the platform's native root certificates are used.
+    ca_cert_path: Option<String>,
+    // Retained topic that carries "online"/"offline", backed by the Last Will.
+    availability_topic: Option<String>,
+    // "individual" (one topic per measurement) or "json" (one state document per poll).
+    payload_format: Option<String>,
+    // Payload of a measurement in individual mode: "string" (default), "json",
+    // "msgpack" or "float32_le".
+    payload_encoding: Option<String>,
+    homeassistant_discovery: Option<bool>,
+    // Supports the placeholders {serial}, {type}, {name} and {unit}.
+    topic_template: Option<String>,
+    // Namespace in front of every topic the bridge publishes, e.g. "acme/solar".
+    base_topic: Option<String>,
+    qos: Option<u8>,
+    retain: Option<bool>,
+    // Skip measurements without a usable value (default) instead of publishing "null".
+    skip_empty: Option<bool>,
+    // Publish "230.5" instead of "230.5 V" in individual mode, the unit goes
+    // to a retained <topic>/unit instead.
+    numeric_payloads: Option<bool>,
+    // Retained <topic>/unit, <topic>/name and <topic>/type next to every
+    // measurement topic in individual mode, sent again only when they change.
+    publish_metadata: Option<bool>,
+    // Decimal places of published numbers, InfluxDB keeps the full value.
+    value_precision: Option<usize>,
+    // Publishes a JSON health summary of the bridge itself after every poll.
+    bridge_health: Option<bool>,
+    // Supports the placeholder {serial}.
+    bridge_health_topic: Option<String>,
+    // Skip publishes whose payload equals the last one on the same topic ...
+    publish_on_change_only: Option<bool>,
+    // ... but still republish everything at least this often.
+    force_publish_interval_secs: Option<u64>,
+    // Reconnects start after 1s and double up to this delay.
+    reconnect_max_delay_secs: Option<u64>,
+    // Exit after this many failed connection attempts in a row, retries forever without it.
+    max_reconnect_attempts: Option<u32>,
+    // false keeps the broker session, so QoS 1 messages queued for us survive a reconnect.
+    clean_session: Option<bool>,
+    // Published (not retained) after every successful (re)connect.
+    birth_topic: Option<String>,
+    birth_payload: Option<String>,
+    // Size of the request queue between the sinks and the eventloop.
+    channel_capacity: Option<usize>,
+    // "wait" (default) for room in a full queue, or "drop" the publish.
+    channel_full: Option<String>,
+    // 3 (MQTT 3.1.1, default) or 5.
+    mqtt_version: Option<u8>,
+    // Sent with every publish and the Last Will, MQTT 5 only.
+    user_properties: Option<BTreeMap<String, String>>,
+}
+
+impl MqttConfig {
+    // Puts a topic below base_topic, "acme/solar" and "/acme/solar/" both
+    // make inverter/7799/AC_Power acme/solar/inverter/7799/AC_Power.
+    fn topic(&self, topic: &str) -> String {
+        match self.base_topic.as_deref().map(|base| base.trim_matches('/')).filter(|base| !base.is_empty()) {
+            Some(base) => format!("{}/{}", base, topic.trim_start_matches('/')),
+            None => topic.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MqttTransport {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+}
+
+fn parse_mqtt_transport(mqtt_conf: &MqttConfig) -> Result<MqttTransport, String> {
+    let tls = mqtt_conf.tls.unwrap_or(false);
+    match (mqtt_conf.transport.as_deref(), tls) {
+        (None, false) | (Some("tcp"), false) => Ok(MqttTransport::Tcp),
+        (None, true) | (Some("tls"), _) => Ok(MqttTransport::Tls),
+        (Some("ws"), false) => Ok(MqttTransport::Ws),
+        (Some("wss"), _) => Ok(MqttTransport::Wss),
+        (Some(transport @ ("tcp" | "ws")), true) => Err(format!(
+            "MQTT tls = true contradicts transport \"{}\", use transport \"tls\" or \"wss\" instead.",
+            transport
+        )),
+        (Some(other), _) => Err(format!(
+            "Invalid MQTT transport '{}', allowed values are \"tcp\", \"tls\", \"ws\" and \"wss\".",
+            other
+        )),
+    }
+}
+
+fn parse_qos(value: Option<u8>) -> Result<QoS, String> {
+    match value {
+        Some(0) => Ok(QoS::AtMostOnce),
+        None | Some(1) => Ok(QoS::AtLeastOnce),
+        Some(2) => Ok(QoS::ExactlyOnce),
+        Some(other) => Err(format!("Invalid MQTT qos {}, allowed values are 0, 1 and 2.", other)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PayloadFormat {
+    Individual,
+    Json,
+}
+
+fn parse_payload_format(value: Option<&str>) -> Result<PayloadFormat, String> {
+    match value {
+        None | Some("individual") => Ok(PayloadFormat::Individual),
+        Some("json") => Ok(PayloadFormat::Json),
+        Some(other) => Err(format!(
+            "Invalid MQTT payload_format '{}', allowed values are \"individual\" and \"json\".",
+            other
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PayloadEncoding {
+    String,
+    Json,
+    MessagePack,
+    Float32Le,
+}
+
+fn parse_payload_encoding(value: Option<&str>) -> Result<PayloadEncoding, String> {
+    match value {
+        None | Some("string") => Ok(PayloadEncoding::String),
+        Some("json") => Ok(PayloadEncoding::Json),
+        Some("msgpack") => Ok(PayloadEncoding::MessagePack),
+        Some("float32_le") => Ok(PayloadEncoding::Float32Le),
+        Some(other) => Err(format!(
+            "Invalid MQTT payload_encoding '{}', allowed values are \"string\", \"json\", \"msgpack\" and \"float32_le\".",
+            other
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChannelFull {
+    Wait,
+    Drop,
+}
+
+fn parse_channel_full(value: Option<&str>) -> Result<ChannelFull, String> {
+    match value {
+        None | Some("wait") => Ok(ChannelFull::Wait),
+        Some("drop") => Ok(ChannelFull::Drop),
+        Some(other) => Err(format!(
+            "Invalid MQTT channel_full '{}', allowed values are \"wait\" and \"drop\".",
+            other
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct InfluxDbConfig {
+    url: String,
+    token: Option<String>,
+    // File holding the token, e.g. a mounted Kubernetes secret. Wins over token.
+    token_file: Option<String>,
+    org: String,
+    bucket: String,
+    #[serde(flatten)]
+    points: InfluxPointConfig,
+}
+
+// InfluxDB 1.x: database and retention policy instead of bucket and org.
+#[derive(Debug, Deserialize)]
+struct InfluxDbV1Config {
+    url: String,
+    database: String,
+    // The default retention policy of the database is used without it.
+    retention_policy: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(flatten)]
+    points: InfluxPointConfig,
+}
+
+// Shared by [influxdb] and [influxdb_v1].
+#[derive(Debug, Deserialize)]
+struct InfluxPointConfig {
+    // Extra write attempts before a failed write counts as an error.
+    retries: Option<u32>,
+    // Timestamp precision of the written points: "s", "ms", "us" or "ns" (default).
+    precision: Option<String>,
+    // Added to every point besides serial, type and unit, e.g. site = "garage".
+    extra_tags: Option<BTreeMap<String, String>>,
+    // Name of the InfluxDB measurement, defaults to "inverter_data".
+    measurement: Option<String>,
+    // Adds the device name as "name" tag.
+    name_tag: Option<bool>,
+    // Field type per measurement Type: "float" (default), "integer", "boolean" or "string".
+    field_types: Option<BTreeMap<String, String>>,
+    // Writes non-numeric values of float types to a "text" field instead of
+    // dropping them, for all types (true) or the listed ones.
+    string_values: Option<StringValues>,
+    // Writes the [topic_map] name as "type" tag instead of the raw @Type.
+    topic_map: Option<bool>,
+    // "tall" (default): one point per measurement with a type tag, "wide":
+    // one point per poll with a field per type.
+    schema: Option<String>,
+    // Collects the points of this many publishes into one write, or of
+    // batch_interval_secs if that passes first.
+    batch_size: Option<usize>,
+    batch_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum StringValues {
+    All(bool),
+    Types(Vec<String>),
+}
+
+impl StringValues {
+    fn applies_to(&self, typ: &str) -> bool {
+        match self {
+            StringValues::All(all) => *all,
+            StringValues::Types(types) => types.iter().any(|t| t == typ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldType {
+    Float,
+    Integer,
+    Boolean,
+    String,
+}
+
+fn parse_field_type(value: &str) -> Result<FieldType, String> {
+    match value {
+        "float" => Ok(FieldType::Float),
+        "integer" => Ok(FieldType::Integer),
+        "boolean" => Ok(FieldType::Boolean),
+        "string" => Ok(FieldType::String),
+        other => Err(format!(
+            "Invalid InfluxDB field type '{}', allowed values are \"float\", \"integer\", \"boolean\" and \"string\".",
+            other
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InfluxSchema {
+    Tall,
+    Wide,
+}
+
+fn parse_influx_schema(value: Option<&str>) -> Result<InfluxSchema, String> {
+    match value {
+        None | Some("tall") => Ok(InfluxSchema::Tall),
+        Some("wide") => Ok(InfluxSchema::Wide),
+        Some(other) => Err(format!("Invalid InfluxDB schema '{}', allowed values are \"tall\" and \"wide\".", other)),
+    }
+}
+
+fn parse_influx_precision(value: Option<&str>) -> Result<TimestampPrecision, String> {
+    match value {
+        Some("s") => Ok(TimestampPrecision::Seconds),
+        Some("ms") => Ok(TimestampPrecision::Milliseconds),
+        Some("us") => Ok(TimestampPrecision::Microseconds),
+        None | Some("ns") => Ok(TimestampPrecision::Nanoseconds),
+        Some(other) => Err(format!(
+            "Invalid InfluxDB precision '{}', allowed values are \"s\", \"ms\", \"us\" and \"ns\".",
+            other
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StdoutConfig {
+    // "jsonl" (one compact object per line), "json" (pretty-printed) or
+    // "protobuf" (length-delimited, see proto/measurement.proto).
+    format: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StdoutFormat {
+    Json,
+    Jsonl,
+    Protobuf,
+}
+
+fn parse_stdout_format(value: Option<&str>) -> Result<StdoutFormat, String> {
+    match value {
+        None | Some("jsonl") => Ok(StdoutFormat::Jsonl),
+        Some("json") => Ok(StdoutFormat::Json),
+        Some("protobuf") => Ok(StdoutFormat::Protobuf),
+        Some(other) => Err(format!(
+            "Invalid stdout format '{}', allowed values are \"json\", \"jsonl\" and \"protobuf\".",
+            other
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SqliteConfig {
+    // Created with the measurements table if it does not exist.
+    path: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookConfig {
+    url: String,
+    // Sent as Authorization header, e.g. "Bearer <token>".
+    authorization: Option<String>,
+    authorization_env: Option<String>,
+    // Device records per POST, collected over several polls if needed.
+    batch_size: Option<usize>,
+    retries: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PrometheusConfig {
+    // Address for the /metrics endpoint, e.g. "0.0.0.0:9100".
+    listen: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PrometheusMeasurementsConfig {
+    // Address for the /metrics endpoint with the inverter values, must
+    // differ from the one of [prometheus].
+    listen: String,
+    // Put in front of every metric name, "solbrid_" by default.
+    prefix: Option<String>,
+    // Devices not polled successfully for this long are left out.
+    max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthConfig {
+    // Address for the /healthz endpoint, e.g. "0.0.0.0:8080".
+    listen: String,
+    // /healthz fails once the last successful poll is older than this.
+    max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoggingConfig {
+    // Log to this file instead of stdout, falls back to stderr if it cannot be opened.
+    file: Option<String>,
+    // The file is rotated before it grows beyond this.
+    max_size_mb: Option<u64>,
+    // Rotated files kept as <file>.1 (newest) to <file>.<max_files>.
+    max_files: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NightModeConfig {
+    // Unreachable polls in a row before the night interval is used.
+    after_errors: Option<u32>,
+    // Poll interval while the inverter stays unreachable.
+    poll_interval_secs: Option<u64>,
+}
+
+// --- XML Parsing Structs (Unchanged) ---
+
+#[derive(Debug, Deserialize)]
+struct Root {
+    // Usually a single Device, some setups list a master and its slaves.
+    #[serde(rename = "Device", default)]
+    devices: Vec<Device>,
+}
+
+impl Root {
+    // Flattens Devices nested inside other Devices, parents come first.
+    fn into_devices(self) -> Vec<Device> {
+        let mut flat = Vec::new();
+        let mut pending: Vec<Device> = self.devices.into_iter().rev().collect();
+        while let Some(mut device) = pending.pop() {
+            pending.extend(std::mem::take(&mut device.devices).into_iter().rev());
+            let loose = std::mem::take(&mut device.loose_measurements);
+            device.measurements.measurement.extend(loose);
+            flat.push(device);
+        }
+        flat
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Device {
+    #[serde(rename = "@Name")]
+    name: String,
+    #[serde(rename = "@Serial")]
+    serial: String,
+    // Solbrid firmware sends the local time as DateTime='2024-05-29T12:51:27'.
+    #[serde(rename = "@DateTime")]
+    date_time: Option<String>,
+    #[serde(rename = "@Timestamp")]
+    timestamp: Option<String>,
+    // A master that only groups its slaves may have no Measurements.
+    #[serde(rename = "Measurements", default)]
+    measurements: Measurements,
+    #[serde(rename = "Device", default)]
+    devices: Vec<Device>,
+    // Older firmwares put the Measurement elements right into the Device.
+    #[serde(rename = "Measurement", default)]
+    loose_measurements: Vec<Measurement>,
+    // Taken from the inverter config after parsing.
+    #[serde(skip)]
+    timezone: Option<Tz>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Measurements {
+    #[serde(rename = "@Timestamp")]
+    timestamp: Option<String>,
+    #[serde(rename = "Measurement", default)]
+    measurement: Vec<Measurement>,
+}
+
+// Accepts epoch seconds, RFC 3339 and ISO-8601 without offset, which is read
+// as local time of `timezone`, or as UTC without one.
+fn parse_timestamp(value: &str, timezone: Option<Tz>) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse::<i64>() {
+        return DateTime::from_timestamp(secs, 0);
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f"))
+        .ok()?;
+    match timezone {
+        // The hour repeated when DST ends resolves to its first pass, a time
+        // in the hour skipped when it starts does not exist and is ignored.
+        Some(timezone) => timezone.from_local_datetime(&naive).earliest().map(|time| time.with_timezone(&Utc)),
+        None => Some(naive.and_utc()),
+    }
+}
+
+impl Device {
+    // The most specific timestamp wins, unparseable values are ignored.
+    fn timestamp(&self) -> Option<DateTime<Utc>> {
+        [&self.measurements.timestamp, &self.timestamp, &self.date_time]
+            .into_iter()
+            .flatten()
+            .find_map(|value| parse_timestamp(value, self.timezone))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Measurement {
+    #[serde(rename = "@Value")]
+    value: Option<String>,
+    #[serde(rename = "@Type")]
+    typ: String,
+    #[serde(rename = "@Unit")]
+    unit: Option<String>,
+    // Newer firmwares add a readable name or description next to @Type.
+    #[serde(rename = "@Name")]
+    name: Option<String>,
+    #[serde(rename = "@Description")]
+    description: Option<String>,
+}
+
+impl Measurement {
+    // @Name, then @Description, then @Type.
+    fn display_name(&self) -> &str {
+        [&self.name, &self.description]
+            .into_iter()
+            .flatten()
+            .map(|s| s.trim())
+            .find(|s| !s.is_empty())
+            .unwrap_or(&self.typ)
+    }
+}
+
+// --- Normalization ---
+
+fn normalize_measurements(measurements: &mut [Measurement], rules: &BTreeMap<String, NormalizeRule>) {
+    for measurement in measurements {
+        let Some(rule) = rules.get(&measurement.typ) else {
+            continue;
+        };
+        if let Some(from_unit) = &rule.from_unit
+            && Some(canonical_unit(from_unit)) != measurement.unit {
+            continue;
+        }
+
+        if let Some(factor) = rule.factor
+            && let Some(value) = clean_value(measurement.value.as_deref()).and_then(parse_value) {
+            // Rounding hides float noise like 0.38260000000000005 for 382.6 * 0.001.
+            let scaled = (value * factor * 1e9).round() / 1e9;
+            measurement.value = Some(scaled.to_string());
+        }
+        if let Some(unit) = &rule.unit {
+            measurement.unit = Some(unit.clone());
+        }
+    }
+}
+
+// --- Limits ---
+
+// Values outside the range are sensor glitches like 99999 V. Values that are
+// not numbers cannot be checked and are kept.
+fn apply_limits(device: &mut Device, limits: &BTreeMap<String, LimitRule>) {
+    if limits.is_empty() {
+        return;
+    }
+    let serial = &device.serial;
+    device.measurements.measurement.retain(|measurement| {
+        let Some(rule) = limits.get(&measurement.typ) else {
+            return true;
+        };
+        let Some(value) = clean_value(measurement.value.as_deref()).and_then(parse_value) else {
+            return true;
+        };
+        let in_range = rule.min.is_none_or(|min| value >= min) && rule.max.is_none_or(|max| value <= max);
+        if !in_range {
+            warn!(
+                "{} = {} of {} is outside its limits ({} to {}), dropped",
+                measurement.typ,
+                value,
+                serial,
+                rule.min.map_or("-inf".to_string(), |min| min.to_string()),
+                rule.max.map_or("inf".to_string(), |max| max.to_string()),
+            );
+        }
+        in_range
+    });
+}
+
+fn resolve_derived(config: &Config) -> Result<Vec<DerivedMeasurement>, String> {
+    config.derived.iter().map(DerivedMeasurement::parse).collect()
+}
+
+// Normalization runs first, so limits and derived values see rescaled ones.
+// Limits are checked again afterwards for derived measurements.
+fn prepare_measurements(device: &mut Device, config: &Config, smoothing: &Smoothing, derived: &[DerivedMeasurement]) {
+    canonicalize_units(&mut device.measurements.measurement);
+    normalize_measurements(&mut device.measurements.measurement, &config.normalize);
+    apply_limits(device, &config.limits);
+    smoothing.apply(&device.serial, &mut device.measurements.measurement);
+    if !derived.is_empty() {
+        add_derived_measurements(&mut device.measurements.measurement, derived);
+        apply_limits(device, &config.limits);
+    }
+}
+
+// --- Diagnostics ---
+
+// Time from sending the request to the parsed response, all URLs of the
+// inverter included. Published like a measurement, so every sink gets it.
+fn add_diagnostics(device: &mut Device, fetch_time: Duration) {
+    device.measurements.measurement.push(Measurement {
+        value: Some(format!("{:.1}", fetch_time.as_secs_f64() * 1000.0)),
+        typ: FETCH_MS_TYPE.to_string(),
+        unit: Some("ms".to_string()),
+        name: None,
+        description: None,
+    });
+}
+
+// --- MQTT Topic Template ---
+
+// The {type} part of a topic, renamed by [topic_map] or the raw @Type.
+fn topic_segment<'a>(topic_map: &'a BTreeMap<String, String>, typ: &'a str) -> &'a str {
+    topic_map.get(typ).map_or(typ, String::as_str)
+}
+
+#[derive(Debug)]
+struct TopicTemplate(String);
+
+impl TopicTemplate {
+    fn parse(template: &str) -> Result<Self, String> {
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("MQTT topic_template '{}' has an unclosed '{{'.", template))?;
+            let placeholder = &rest[start + 1..start + end];
+
+            if !TOPIC_PLACEHOLDERS.contains(&placeholder) {
+                return Err(format!(
+                    "MQTT topic_template '{}' references unknown placeholder '{{{}}}', allowed are {{serial}}, {{type}}, {{name}} and {{unit}}.",
+                    template, placeholder
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        if rest.contains('}') {
+            return Err(format!("MQTT topic_template '{}' has an unmatched '}}'.", template));
+        }
+
+        Ok(TopicTemplate(template.to_string()))
+    }
+
+    fn render(&self, serial: &str, typ: &str, name: &str, unit: &str) -> String {
+        self.0
+            .replace("{serial}", serial)
+            .replace("{type}", typ)
+            .replace("{name}", &sanitize_topic_level(name))
+            .replace("{unit}", unit)
+    }
+}
+
+// Device names like "SolBrid 10-3-4" may contain spaces, and a '/' or an MQTT
+// wildcard would change the topic structure, so all of them become '_'.
+fn sanitize_topic_level(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| if c.is_whitespace() || matches!(c, '/' | '+' | '#') { '_' } else { c })
+        .collect()
+}
+
+// --- MQTT Bridge Health Payload ---
+
+#[derive(Debug, Serialize)]
+struct HealthPayload {
+    version: &'static str,
+    uptime_secs: u64,
+    last_success: Option<String>,
+    consecutive_errors: u32,
+    // Inverter timestamp the device has been stuck at, only while stale.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stale_since: Option<String>,
+}
+
+// --- Stale Data ---
+
+// Frozen inverter web servers keep answering 200 with the same cached XML.
+// Counts per serial how many polls in a row returned one timestamp.
+#[derive(Debug, Default)]
+struct StaleData {
+    after_polls: Option<u32>,
+    seen: BTreeMap<String, (DateTime<Utc>, u32)>,
+}
+
+impl StaleData {
+    fn new(after_polls: Option<u32>) -> Self {
+        StaleData { after_polls, seen: BTreeMap::new() }
+    }
+
+    // Whether the device is stale now. Devices without a timestamp never are.
+    fn observe(&mut self, device: &Device) -> bool {
+        let Some(after_polls) = self.after_polls else {
+            return false;
+        };
+        let Some(timestamp) = device.timestamp() else {
+            self.seen.remove(&device.serial);
+            return false;
+        };
+
+        let (last, polls) = self.seen.entry(device.serial.clone()).or_insert((timestamp, 0));
+        if *last == timestamp {
+            *polls += 1;
+        } else {
+            if *polls > after_polls {
+                info!("Inverter timestamp of {} moves again, data is current", device.serial);
+            }
+            *last = timestamp;
+            *polls = 1;
+        }
+        if *polls == after_polls + 1 {
+            warn!(
+                "Inverter timestamp of {} stuck at {} for {} polls, data is stale",
+                device.serial,
+                timestamp.to_rfc3339(),
+                polls
+            );
+        }
+        *polls > after_polls
+    }
+
+    fn stale_since(&self, serial: &str) -> Option<DateTime<Utc>> {
+        let after_polls = self.after_polls?;
+        self.seen.get(serial).filter(|(_, polls)| *polls > after_polls).map(|(timestamp, _)| *timestamp)
+    }
+}
+
+// --- Night Mode ---
+
+// Inverters switch off after sunset and refuse connections until morning.
+// After after_errors polls in a row that could not connect at all, the
+// inverter is polled at the night interval, and further connection errors
+// neither count towards max_errors nor fill the log, until a poll gets an
+// answer again. Any other error means the inverter is back.
+struct NightMode {
+    after_errors: u32,
+    interval_secs: u64,
+    unreachable_polls: u32,
+    active: bool,
+}
+
+impl NightMode {
+    fn new(conf: &NightModeConfig) -> Self {
+        NightMode {
+            after_errors: conf.after_errors.unwrap_or(DEFAULT_NIGHT_MODE_AFTER_ERRORS),
+            interval_secs: conf.poll_interval_secs.unwrap_or(DEFAULT_NIGHT_MODE_POLL_INTERVAL_SECS),
+            unreachable_polls: 0,
+            active: false,
+        }
+    }
+
+    // Whether night mode started or ended with this poll.
+    fn observe(&mut self, unreachable: bool) -> bool {
+        self.unreachable_polls = if unreachable { self.unreachable_polls + 1 } else { 0 };
+        let active = self.unreachable_polls >= self.after_errors;
+        let changed = active != self.active;
+        self.active = active;
+        changed
+    }
+}
+
+fn parse_value(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+// Empty, whitespace-only, NaN and infinite values carry no measurement and are
+// treated like a missing @Value by every sink.
+fn clean_value(value: Option<&str>) -> Option<&str> {
+    let value = value?.trim();
+    if value.is_empty() {
+        return None;
+    }
+    match value.parse::<f64>() {
+        Ok(number) if !number.is_finite() => None,
+        _ => Some(value),
+    }
+}
+
+fn json_value(value: &str) -> serde_json::Value {
+    // Numeric values become JSON numbers, anything else is kept as a string.
+    parse_value(value)
+        .and_then(serde_json::Number::from_f64)
+        .map(serde_json::Value::Number)
+        .unwrap_or_else(|| serde_json::Value::String(value.to_string()))
+}
+
+// --- Log Timestamps ---
+
+// Without a timezone this prints UTC like the tracing default, with one the
+// local time and its offset.
+struct LogTimer(Option<Tz>);
+
+impl FormatTime for LogTimer {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        let now = Utc::now();
+        match self.0 {
+            Some(timezone) => write!(w, "{}", now.with_timezone(&timezone).format("%Y-%m-%dT%H:%M:%S%.6f%:z")),
+            None => write!(w, "{}", now.format("%Y-%m-%dT%H:%M:%S%.6fZ")),
+        }
+    }
+}
+
+// --- Bridge Metrics ---
+
+#[derive(Debug, Default)]
+struct Metrics {
+    polls_total: AtomicU64,
+    poll_errors_total: AtomicU64,
+    mqtt_publishes_total: AtomicU64,
+    mqtt_publishes_dropped_total: AtomicU64,
+    influx_writes_total: AtomicU64,
+    influx_rate_limited_total: AtomicU64,
+    influx_points_dropped_total: AtomicU64,
+    webhook_posts_total: AtomicU64,
+    poll_overruns_total: AtomicU64,
+    http_client_rebuilds_total: AtomicU64,
+    last_poll_timestamp: AtomicU64,
+    // 1 while the MQTT connection is up, 0 otherwise.
+    mqtt_connected: AtomicU64,
+}
+
+impl Metrics {
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let entries = [
+            ("polls_total", "counter", "Number of inverter polls.", &self.polls_total),
+            ("poll_errors_total", "counter", "Number of failed inverter polls.", &self.poll_errors_total),
+            ("mqtt_publishes_total", "counter", "Number of successful MQTT publishes.", &self.mqtt_publishes_total),
+            (
+                "mqtt_publishes_dropped_total",
+                "counter",
+                "Number of MQTT publishes dropped because the request queue was full.",
+                &self.mqtt_publishes_dropped_total,
+            ),
+            ("influx_writes_total", "counter", "Number of successful InfluxDB batch writes.", &self.influx_writes_total),
+            (
+                "influx_rate_limited_total",
+                "counter",
+                "Number of InfluxDB writes rate limited with HTTP 429.",
+                &self.influx_rate_limited_total,
+            ),
+            (
+                "influx_points_dropped_total",
+                "counter",
+                "Number of buffered InfluxDB points dropped because the buffer was full.",
+                &self.influx_points_dropped_total,
+            ),
+            ("webhook_posts_total", "counter", "Number of successful webhook POSTs.", &self.webhook_posts_total),
+            (
+                "poll_overruns_total",
+                "counter",
+                "Number of polls that took longer than the poll interval.",
+                &self.poll_overruns_total,
+            ),
+            (
+                "http_client_rebuilds_total",
+                "counter",
+                "Number of HTTP clients rebuilt after failed inverter requests.",
+                &self.http_client_rebuilds_total,
+            ),
+            ("last_poll_timestamp", "gauge", "Unix time of the last successful poll.", &self.last_poll_timestamp),
+            ("mqtt_connected", "gauge", "1 while connected to the MQTT broker.", &self.mqtt_connected),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in entries {
+            out.push_str(&format!(
+                "# HELP solbridxml2mqtt_{name} {help}\n# TYPE solbridxml2mqtt_{name} {kind}\nsolbridxml2mqtt_{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// --- HTTP Endpoints ---
+
+fn text_response(status: StatusCode, content_type: &str, body: String) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    if let Ok(value) = content_type.parse() {
+        response.headers_mut().insert(hyper::header::CONTENT_TYPE, value);
+    }
+    response
+}
+
+// 200 while the last successful poll of any inverter is at most max_age_secs old
+// and, with MQTT configured, the broker connection is up.
+fn health_response(metrics: &Metrics, max_age_secs: u64, mqtt_enabled: bool) -> Response<Full<Bytes>> {
+    if mqtt_enabled && metrics.mqtt_connected.load(Ordering::Relaxed) == 0 {
+        return text_response(StatusCode::SERVICE_UNAVAILABLE, "text/plain", "mqtt disconnected\n".to_string());
+    }
+    match metrics.last_poll_timestamp.load(Ordering::Relaxed) {
+        0 => text_response(StatusCode::SERVICE_UNAVAILABLE, "text/plain", "no successful poll yet\n".to_string()),
+        last => {
+            let age = unix_time_secs().saturating_sub(last);
+            if age <= max_age_secs {
+                text_response(StatusCode::OK, "text/plain", format!("ok, last successful poll {}s ago\n", age))
+            } else {
+                text_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "text/plain",
+                    format!("stale, last successful poll {}s ago (limit {}s)\n", age, max_age_secs),
+                )
+            }
+        }
+    }
+}
+
+async fn serve_http<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&str) -> Response<Full<Bytes>> + Clone + Send + Sync + 'static,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("HTTP Accept Error: {:?}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let response = handler(req.uri().path());
+                async move { Ok::<_, Infallible>(response) }
+            });
+
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await {
+                error!("HTTP Connection Error: {:?}", e);
+            }
+        });
+    }
+}
+
+fn backoff_delay_secs(base_secs: u64, consecutive_errors: u32, max_secs: u64) -> u64 {
+    let factor = 2u64.checked_pow(consecutive_errors).unwrap_or(u64::MAX);
+    base_secs.saturating_mul(factor).min(max_secs.max(base_secs))
+}
+
+// Polls never overlap, the next one waits for the previous one to finish.
+// A poll taking longer than poll_interval_secs has run over the polls that
+// were due in the meantime, this counts them.
+fn overrun_polls(poll_time: Duration, poll_interval_secs: u64) -> u64 {
+    if poll_interval_secs == 0 {
+        return 0;
+    }
+    (poll_time.as_secs_f64() / poll_interval_secs as f64) as u64
+}
+
+// initial_delay_secs, plus one interval when poll_on_start is off.
+fn first_poll_delay_secs(config: &Config, inverter: &Inverter) -> u64 {
+    let interval_secs = if config.poll_on_start.unwrap_or(true) { 0 } else { inverter.poll_interval_secs };
+    config.initial_delay_secs.unwrap_or(0) + interval_secs
+}
+
+// Spreads the polls of many bridges so they do not hit the inverters in lockstep.
+fn jittered_delay(delay_secs: u64, jitter_secs: f64) -> Duration {
+    if jitter_secs <= 0.0 {
+        return Duration::from_secs(delay_secs);
+    }
+    let offset = rand::rng().random_range(-jitter_secs..=jitter_secs);
+    Duration::from_secs_f64((delay_secs as f64 + offset).max(0.0))
+}
+
+// Broker and transport, shared by both MQTT versions and --purge-retained.
+// None keeps the plain TCP transport.
+fn mqtt_endpoint(mqtt_conf: &MqttConfig) -> Result<(String, Option<Transport>), String> {
+    let transport = parse_mqtt_transport(mqtt_conf)?;
+    // For WebSockets rumqttc expects the whole URL in place of the host.
+    let broker = match transport {
+        MqttTransport::Ws | MqttTransport::Wss => format!(
+            "{}://{}:{}{}",
+            if transport == MqttTransport::Ws { "ws" } else { "wss" },
+            mqtt_conf.broker,
+            mqtt_conf.port,
+            mqtt_conf.path.as_deref().unwrap_or(DEFAULT_MQTT_WS_PATH)
+        ),
+        MqttTransport::Tcp | MqttTransport::Tls => mqtt_conf.broker.clone(),
+    };
+
+    if transport == MqttTransport::Tcp {
+        return Ok((broker, None));
+    }
+    let tls_config = || -> Result<TlsConfiguration, String> {
+        Ok(match &mqtt_conf.ca_cert_path {
+            Some(path) => TlsConfiguration::Simple {
+                ca: load_ca_cert(path, "MQTT")?,
+                alpn: None,
+                client_auth: None,
+            },
+            None => TlsConfiguration::default(),
+        })
+    };
+    let transport = match transport {
+        MqttTransport::Ws => Transport::Ws,
+        MqttTransport::Wss => Transport::Wss(tls_config()?),
+        MqttTransport::Tcp | MqttTransport::Tls => Transport::Tls(tls_config()?),
+    };
+    Ok((broker, Some(transport)))
+}
+
+// MQTT 3.1.1 options, shared by the bridge and --purge-retained.
+fn mqtt_options(mqtt_conf: &MqttConfig, client_id: &str) -> Result<MqttOptions, String> {
+    let (broker, transport) = mqtt_endpoint(mqtt_conf)?;
+    let mut mqttoptions = MqttOptions::new(client_id, broker, mqtt_conf.port);
+    mqttoptions.set_keep_alive(Duration::from_secs(MQTT_KEEP_ALIVE_SECS));
+    mqttoptions.set_clean_session(mqtt_conf.clean_session.unwrap_or(true));
+
+    if let Some((username, password)) = mqtt_credentials(mqtt_conf)? {
+        mqttoptions.set_credentials(username, password);
+    }
+    if let Some(transport) = transport {
+        mqttoptions.set_transport(transport);
+    }
+
+    Ok(mqttoptions)
+}
+
+fn mqtt_credentials(mqtt_conf: &MqttConfig) -> Result<Option<(String, String)>, String> {
+    let password = match (&mqtt_conf.password, &mqtt_conf.password_env, &mqtt_conf.password_file) {
+        (Some(password), None, None) => Some(password.clone()),
+        (None, Some(var), None) => Some(std::env::var(var).map_err(|_| {
+            format!("MQTT password_env refers to '{}', but that environment variable is not set.", var)
+        })?),
+        (None, None, Some(path)) => Some(read_secret_file(path, "MQTT password_file")?),
+        (None, None, None) => None,
+        _ => {
+            return Err(
+                "MQTT config sets more than one of password, password_env and password_file, use only one.".to_string(),
+            );
+        }
+    };
+
+    match (&mqtt_conf.username, password) {
+        (Some(username), Some(password)) => Ok(Some((username.clone(), password))),
+        (None, None) => Ok(None),
+        (Some(_), None) => Err("MQTT username is set but no password was given.".to_string()),
+        (None, Some(_)) => Err("MQTT password is set but no username was given.".to_string()),
+    }
+}
+
+// A secret mounted as a file, without the trailing newline most tools add.
+fn read_secret_file(path: &str, field: &str) -> Result<String, String> {
+    let secret = fs::read_to_string(path).map_err(|e| format!("Could not read {} '{}': {}", field, path, e))?;
+    let secret = secret.trim_end();
+    if secret.is_empty() {
+        return Err(format!("{} '{}' is empty.", field, path));
+    }
+    Ok(secret.to_string())
+}
+
+fn influx_token(influx_conf: &InfluxDbConfig) -> Result<String, String> {
+    match (&influx_conf.token_file, &influx_conf.token) {
+        (Some(path), _) => read_secret_file(path, "InfluxDB token_file"),
+        (None, Some(token)) if !token.trim().is_empty() => Ok(token.clone()),
+        (None, Some(_)) => Err("InfluxDB token must not be empty.".to_string()),
+        (None, None) => Err("InfluxDB token is not set, add token or token_file.".to_string()),
+    }
+}
+
+// `peer` is "MQTT" or "Inverter", only used in the error messages.
+fn load_ca_cert(path: &str, peer: &str) -> Result<Vec<u8>, String> {
+    let ca = fs::read(path)
+        .map_err(|e| format!("Failed to read {} CA certificate '{}': {}", peer, path, e))?;
+
+    let certs = rustls_pemfile::certs(&mut ca.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to parse {} CA certificate '{}': {}", peer, path, e))?;
+
+    if certs.is_empty() {
+        return Err(format!("{} CA certificate '{}' contains no PEM certificates.", peer, path));
+    }
+
+    Ok(ca)
+}
+
+// --- Shared Bridge State ---
+
+// Everything the polling tasks share: the parsed config and the sink clients.
+struct Bridge {
+    config: Config,
+    http_client: Client,
+    // Kept besides the sinks for the availability and health messages.
+    mqtt_client: Option<MqttClient>,
+    mqtt_qos: QoS,
+    sinks: Vec<Box<dyn MeasurementSink>>,
+    raw_dump: Option<RawDump>,
+    derived: Vec<DerivedMeasurement>,
+    // Moving averages across polls, shared by all inverters.
+    smoothing: Smoothing,
+    // --once: every inverter task returns after its first poll.
+    once: bool,
+    // Set when bridge_health is enabled.
+    health_topic: Option<String>,
+    started: Instant,
+    metrics: Arc<Metrics>,
+    readiness: Readiness,
+}
+
+// READY is logged once every inverter had one successful poll.
+struct Readiness {
+    pending: AtomicUsize,
+    ready: watch::Sender<bool>,
+}
+
+impl Readiness {
+    fn new(inverters: usize) -> Self {
+        Readiness { pending: AtomicUsize::new(inverters), ready: watch::Sender::new(false) }
+    }
+
+    // Called once per inverter, on its first successful poll.
+    fn inverter_ready(&self) {
+        if self.pending.fetch_sub(1, Ordering::Relaxed) == 1 {
+            info!(target: READY_TARGET, "READY: every inverter was polled successfully");
+            self.ready.send_replace(true);
+        }
+    }
+}
+
+// --wait-ready ran out of time, exits with EXIT_NOT_READY.
+#[derive(Debug)]
+struct NotReady(String);
+
+impl std::fmt::Display for NotReady {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotReady {}
+
+// --- XML Decoding ---
+
+// Some firmwares send ISO-8859-1 instead of UTF-8. The charset of the
+// Content-Type wins over the XML declaration, without either UTF-8 is assumed.
+fn decode_xml(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = content_type
+        .and_then(charset_from_content_type)
+        .or_else(|| encoding_from_declaration(bytes))
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(UTF_8);
+    let (text, _, _) = encoding.decode(bytes);
+
+    // The text is UTF-8 now, a leftover encoding="ISO-8859-1" would make the
+    // XML parser decode it a second time.
+    match text.trim_start().strip_prefix("<?xml").and_then(|rest| rest.split_once("?>")) {
+        Some((_, body)) => body.to_string(),
+        None => text.into_owned(),
+    }
+}
+
+fn charset_from_content_type(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn encoding_from_declaration(bytes: &[u8]) -> Option<String> {
+    // The declaration is plain ASCII in every encoding we can decode.
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(256)]);
+    let declaration = head.trim_start().strip_prefix("<?xml")?.split("?>").next()?;
+    let rest = declaration[declaration.find("encoding")? + "encoding".len()..]
+        .trim_start()
+        .strip_prefix('=')?
+        .trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    rest[1..].split(quote).next().map(|label| label.to_string())
+}
+
+fn build_http_client(config: &Config) -> Result<Client, String> {
+    let timeout_secs = config.http_timeout_secs.unwrap_or(DEFAULT_HTTP_TIMEOUT_SECS);
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(timeout_secs))
+        .connect_timeout(Duration::from_secs(config.http_connect_timeout_secs.unwrap_or(timeout_secs)))
+        .tcp_keepalive(config.http_tcp_keepalive_secs.map(Duration::from_secs));
+
+    if config.http_keep_alive.unwrap_or(true) {
+        if let Some(max_idle) = config.http_pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout_secs) = config.http_pool_idle_timeout_secs {
+            builder = builder.pool_idle_timeout(Duration::from_secs(idle_timeout_secs));
+        }
+    } else {
+        // Without the header the inverter would still keep its end open.
+        builder = builder
+            .pool_max_idle_per_host(0)
+            .default_headers(HeaderMap::from_iter([(CONNECTION, HeaderValue::from_static("close"))]));
+    }
+
+    if let Some(path) = &config.http_ca_cert_path {
+        let pem = load_ca_cert(path, "Inverter")?;
+        for cert in Certificate::from_pem_bundle(&pem).map_err(|e| format!("Invalid inverter CA certificate '{}': {}", path, e))? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if config.http_danger_accept_invalid_certs.unwrap_or(false) {
+        warn!("!!! http_danger_accept_invalid_certs is set: inverter certificates are NOT verified, anyone on the network can impersonate the inverter !!!");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build().map_err(|e| format!("Failed to build HTTP client: {}", e))
+}
+
+// After an inverter reboot the connection pool of a client can end up with
+// connections that fail every request until the bridge restarts. Once
+// after_errors requests in a row failed, the inverter gets a client of its
+// own with an empty pool. Errors in night mode do not count, the inverter is off.
+struct ClientRebuild {
+    after_errors: u32,
+    failed_requests: u32,
+}
+
+impl ClientRebuild {
+    fn new(config: &Config) -> Self {
+        ClientRebuild {
+            after_errors: config.http_rebuild_after_errors.unwrap_or(DEFAULT_HTTP_REBUILD_AFTER_ERRORS),
+            failed_requests: 0,
+        }
+    }
+
+    // Whether the client should be rebuilt after this poll.
+    fn observe(&mut self, fetched: &Result<Vec<Device>, FetchError>, night: bool) -> bool {
+        // An HTTP error status or a parse error came over a connection that works.
+        let failed = matches!(fetched, Err(e) if matches!(e.kind, FetchFailure::Connect | FetchFailure::Request));
+        self.failed_requests = if failed && !night { self.failed_requests + 1 } else { 0 };
+        if self.after_errors == 0 || self.failed_requests < self.after_errors {
+            return false;
+        }
+        self.failed_requests = 0;
+        true
+    }
+}
+
+// Why a fetch failed, night mode and the client rebuild go by this rather
+// than by the message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FetchFailure {
+    // The inverter did not even accept the connection.
+    Connect,
+    // The request failed on the way, e.g. a timeout or a reset.
+    Request,
+    // Anything after an answer, like an HTTP error status or unparsable XML.
+    Other,
+}
+
+#[derive(Debug)]
+struct FetchError {
+    kind: FetchFailure,
+    message: String,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for FetchError {
+    fn from(message: String) -> Self {
+        FetchError { kind: FetchFailure::Other, message }
+    }
+}
+
+impl From<&str> for FetchError {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+impl From<FetchError> for String {
+    fn from(e: FetchError) -> Self {
+        e.message
+    }
+}
+
+fn request_error(e: reqwest::Error) -> FetchError {
+    if e.is_connect() {
+        FetchError { kind: FetchFailure::Connect, message: format!("Connection Error: {:?}", e) }
+    } else {
+        FetchError { kind: FetchFailure::Request, message: format!("Request Error: {:?}", e) }
+    }
+}
+
+async fn send_request(http_client: &Client, url: &str, inverter: &Inverter) -> Result<reqwest::Response, FetchError> {
+    let request = http_client.get(url).headers(inverter.headers.clone());
+    let request = match &inverter.auth {
+        InverterAuth::Basic { username, password } => request.basic_auth(username, Some(password)),
+        _ => request,
+    };
+    let resp = request.send().await.map_err(request_error)?;
+
+    let InverterAuth::Digest { username, password } = &inverter.auth else {
+        return Ok(resp);
+    };
+    if resp.status() != StatusCode::UNAUTHORIZED {
+        return Ok(resp);
+    }
+
+    // The nonce is not kept between polls, every poll does the full handshake.
+    let challenge = resp
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+        .ok_or("Digest Auth Error: 401 without WWW-Authenticate header")?;
+    let challenge = DigestChallenge::parse(challenge).map_err(|e| format!("Digest Auth Error: {}", e))?;
+
+    let parsed_url = resp.url().clone();
+    let uri = match parsed_url.query() {
+        Some(query) => format!("{}?{}", parsed_url.path(), query),
+        None => parsed_url.path().to_string(),
+    };
+    let cnonce = format!("{:016x}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    let authorization = challenge
+        .authorization(username, password, "GET", &uri, &cnonce)
+        .map_err(|e| format!("Digest Auth Error: {}", e))?;
+
+    http_client
+        .get(url)
+        .headers(inverter.headers.clone())
+        .header(reqwest::header::AUTHORIZATION, authorization)
+        .send()
+        .await
+        .map_err(request_error)
+}
+
+// --- Raw XML Dump ---
+
+struct RawDump {
+    dir: PathBuf,
+    max_files: usize,
+    max_age: Option<Duration>,
+}
+
+impl RawDump {
+    fn from_config(config: &Config) -> Option<Self> {
+        config.raw_dump_dir.as_ref().map(|dir| RawDump {
+            dir: PathBuf::from(dir),
+            max_files: config.raw_dump_max_files.unwrap_or(DEFAULT_RAW_DUMP_MAX_FILES),
+            max_age: config.raw_dump_max_age_secs.map(Duration::from_secs),
+        })
+    }
+
+    // A failing dump is only logged, it must never cost a poll.
+    async fn write(&self, label: &str, bytes: &[u8]) {
+        // Names start with the UTC time, so sorting them sorts by age.
+        let label: String = label
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let name = format!("{}_{}.xml", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"), label);
+
+        if let Err(e) = tokio::fs::write(self.dir.join(&name), bytes).await {
+            warn!("Raw Dump Error ({}): {}", self.dir.join(&name).display(), e);
+            return;
+        }
+        if let Err(e) = self.cleanup() {
+            warn!("Raw Dump Cleanup Error ({}): {}", self.dir.display(), e);
+        }
+    }
+
+    fn cleanup(&self) -> std::io::Result<()> {
+        let mut files: Vec<(String, SystemTime)> = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".xml") {
+                files.push((name, entry.metadata()?.modified()?));
+            }
+        }
+        files.sort();
+
+        let excess = files.len().saturating_sub(self.max_files);
+        for (index, (name, modified)) in files.iter().enumerate() {
+            let expired = self
+                .max_age
+                .is_some_and(|max_age| modified.elapsed().is_ok_and(|age| age > max_age));
+            if index < excess || expired {
+                fs::remove_file(self.dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// First characters of an error response, whitespace collapsed to fit one log line.
+fn body_snippet(bytes: &[u8]) -> String {
+    const MAX_CHARS: usize = 200;
+    let text = String::from_utf8_lossy(bytes);
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.is_empty() {
+        return "(empty body)".to_string();
+    }
+    match collapsed.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}...", &collapsed[..end]),
+        None => collapsed,
+    }
+}
+
+// Explains an undecodable body. reqwest removes Content-Encoding once it has
+// decompressed gzip/deflate, so a remaining header means an unsupported encoding.
+fn encoding_hint(content_encoding: Option<&str>, bytes: &[u8]) -> String {
+    match content_encoding {
+        Some(encoding) => format!(" (Content-Encoding: {})", encoding),
+        None if bytes.starts_with(&[0x1f, 0x8b]) => " (body is gzip data without Content-Encoding header)".to_string(),
+        None => String::new(),
+    }
+}
+
+// Tells a body cut off mid-transfer from one that is complete but malformed.
+// Returns why the document looks truncated: its root element is never closed,
+// or the body ends before the root element even starts.
+fn truncation(xml: &str) -> Option<String> {
+    let mut rest = xml.trim();
+    loop {
+        if rest.is_empty() {
+            return Some("the XML ends before its root element".to_string());
+        }
+        if !rest.starts_with('<') {
+            return None;
+        }
+        let skip_to = if rest.starts_with("<?") {
+            "?>"
+        } else if rest.starts_with("<!--") {
+            "-->"
+        } else if rest.starts_with("<!") {
+            ">"
+        } else {
+            break;
+        };
+        match rest.find(skip_to) {
+            Some(end) => rest = rest[end + skip_to.len()..].trim_start(),
+            None => return Some("the XML ends before its root element".to_string()),
+        }
+    }
+
+    let name_end = rest[1..].find(|c: char| c.is_whitespace() || c == '/' || c == '>').map_or(rest.len(), |end| end + 1);
+    let name = &rest[1..name_end];
+    let Some(tag_end) = rest.find('>') else {
+        return Some(format!("the XML ends inside <{}>", name));
+    };
+    if rest[..tag_end].ends_with('/') {
+        return None;
+    }
+    if rest.ends_with('>') && rest[tag_end..].contains(&format!("</{}", name)) {
+        return None;
+    }
+    Some(format!("the XML ends before </{}>", name))
+}
+
+async fn fetch_source(
+    http_client: &Client,
+    inverter: &Inverter,
+    source: &Source,
+    dump_label: &str,
+    raw_dump: Option<&RawDump>,
+) -> Result<Vec<Device>, FetchError> {
+    let mut retried_truncated = false;
+    let mut parse_retries = 0;
+    loop {
+        let (xml_str, hint, len) = read_source(http_client, inverter, source, dump_label, raw_dump).await?;
+        let xml_str = inverter.xml_shape.adapt(&xml_str)?;
+        let e = match from_str::<Root>(&xml_str) {
+            Ok(root) => {
+                if parse_retries > 0 {
+                    info!("XML of {} parsed on retry {}/{}", dump_label, parse_retries, inverter.parse_retries);
+                }
+                return Ok(root.into_devices());
+            }
+            Err(e) => e,
+        };
+        match truncation(&xml_str) {
+            // Usually a dropped connection, the next request tends to get through.
+            Some(reason) => {
+                let error = format!("Truncated Response ({} bytes): {}", len, reason);
+                if retried_truncated || !inverter.retry_truncated {
+                    return Err(error.into());
+                }
+                warn!("{}, retrying once", error);
+                retried_truncated = true;
+            }
+            // Some firmwares serve garbage now and then that parses fine right after.
+            None => {
+                let error = format!("XML Parse Error{}: {:?}", hint, e);
+                if parse_retries == inverter.parse_retries {
+                    return Err(match parse_retries {
+                        0 => error,
+                        retries => format!("{} (still failing on retry {}/{})", error, retries, retries),
+                    }
+                    .into());
+                }
+                parse_retries += 1;
+                warn!("{}, retrying ({}/{})", error, parse_retries, inverter.parse_retries);
+            }
+        }
+    }
+}
+
+// The decoded body, the hint for decoding errors and the size as received.
+async fn read_source(
+    http_client: &Client,
+    inverter: &Inverter,
+    source: &Source,
+    dump_label: &str,
+    raw_dump: Option<&RawDump>,
+) -> Result<(String, String, usize), FetchError> {
+    Ok(match source {
+        Source::Http(url) => {
+            let resp = send_request(http_client, url, inverter).await?;
+            let header = |name| {
+                resp.headers()
+                    .get(name)
+                    .and_then(|v: &HeaderValue| v.to_str().ok())
+                    .map(|v| v.to_string())
+            };
+            let content_type = header(CONTENT_TYPE);
+            let content_encoding = header(CONTENT_ENCODING);
+            let status = resp.status();
+            // Usually the connection dropped mid-transfer.
+            let bytes = resp.bytes().await.map_err(|e| FetchError {
+                kind: FetchFailure::Request,
+                message: format!("Response Text Error{}: {:?}", encoding_hint(content_encoding.as_deref(), &[]), e),
+            })?;
+            if let Some(raw_dump) = raw_dump {
+                raw_dump.write(dump_label, &bytes).await;
+            }
+            // An error page would only fail as "XML Parse Error" and hide the status.
+            if !status.is_success() {
+                return Err(format!("Request Error: HTTP {} from {}: {}", status, url, body_snippet(&bytes)).into());
+            }
+            (
+                decode_xml(&bytes, content_type.as_deref()),
+                encoding_hint(content_encoding.as_deref(), &bytes),
+                bytes.len(),
+            )
+        }
+        Source::File(path) => {
+            let bytes = tokio::fs::read(path)
+                .await
+                .map_err(|e| format!("File Read Error ({}): {}", path.display(), e))?;
+            (decode_xml(&bytes, None), encoding_hint(None, &bytes), bytes.len())
+        }
+    })
+}
+
+// Devices are keyed by serial. A device reported by several endpoints keeps
+// the name and time of the first one, a measurement type reported again
+// replaces the earlier value. Repeats of a type are matched in order, the
+// n-th one replaces the n-th, so they stay apart for disambiguate_types.
+fn merge_devices(responses: Vec<Vec<Device>>) -> Vec<Device> {
+    let mut merged: Vec<Device> = Vec::new();
+    for device in responses.into_iter().flatten() {
+        let Some(existing) = merged.iter_mut().find(|d| d.serial == device.serial) else {
+            merged.push(device);
+            continue;
+        };
+        if existing.name.is_empty() {
+            existing.name = device.name;
+        }
+        let mut repeats: BTreeMap<String, usize> = BTreeMap::new();
+        for measurement in device.measurements.measurement {
+            let measurements = &mut existing.measurements.measurement;
+            let nth = repeats.entry(measurement.typ.clone()).or_default();
+            match measurements.iter_mut().filter(|m| m.typ == measurement.typ).nth(*nth) {
+                Some(previous) => *previous = measurement,
+                None => measurements.push(measurement),
+            }
+            *nth += 1;
+        }
+    }
+    merged
+}
+
+// Fetches all URLs of the inverter at once. A failing endpoint fails the
+// whole poll, publishing only part of the measurements would look like
+// values disappearing.
+async fn fetch_devices(
+    http_client: &Client,
+    inverter: &Inverter,
+    raw_dump: Option<&RawDump>,
+) -> Result<Vec<Device>, FetchError> {
+    let responses = if let [source] = inverter.sources.as_slice() {
+        vec![fetch_source(http_client, inverter, source, &inverter.label, raw_dump).await?]
+    } else {
+        try_join_all(inverter.sources.iter().enumerate().map(|(index, source)| async move {
+            let dump_label = format!("{}_{}", inverter.label, index + 1);
+            fetch_source(http_client, inverter, source, &dump_label, raw_dump)
+                .await
+                .map_err(|e| FetchError { message: format!("{} [{}]", e, inverter.urls[index]), ..e })
+        }))
+        .await?
+    };
+
+    let mut devices = merge_devices(responses);
+    if devices.is_empty() {
+        return Err("XML Parse Error: no Device element found".into());
+    }
+    for device in &mut devices {
+        // An empty serial would publish to topics like inverter//AC_Power.
+        if device.serial.trim().is_empty() {
+            match &inverter.serial_override {
+                Some(serial) => device.serial = serial.clone(),
+                None => {
+                    return Err(format!(
+                        "Device '{}' has no Serial, set serial_override for inverter {}. Poll skipped.",
+                        device.name, inverter.label
+                    )
+                    .into());
+                }
+            }
+        }
+        device.timezone = inverter.timezone;
+        log_discovered_types(device);
+        device.measurements.measurement.retain(|m| inverter.filter.allows(&m.typ));
+        disambiguate_types(device, inverter.duplicate_types, &inverter.label)?;
+    }
+    Ok(devices)
+}
+
+// --- Discovery ---
+
+// Devices whose types were already logged, once per run is enough to pick
+// names for include_types or [topic_map].
+static DISCOVERED_DEVICES: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn log_discovered_types(device: &Device) {
+    if !DISCOVERED_DEVICES.lock().unwrap().insert(device.serial.clone()) {
+        return;
+    }
+    let types: Vec<String> = device
+        .measurements
+        .measurement
+        .iter()
+        .map(|m| match m.unit.as_deref().filter(|unit| !unit.is_empty()) {
+            Some(unit) => format!("{} ({})", m.typ, unit),
+            None => m.typ.clone(),
+        })
+        .collect();
+    info!("Device {} sends {} measurement types: {}", device.serial, types.len(), types.join(", "));
+}
+
+// The --discover table of one device, before include_types and exclude_types.
+// The last line is an include_types entry with every type, to be shortened.
+fn discovery_table(device: &Device, filter: &TypeFilter) -> String {
+    let measurements = &device.measurements.measurement;
+    let type_width = measurements.iter().map(|m| m.typ.len()).chain([4]).max().unwrap_or(4);
+    let unit_width = measurements.iter().map(|m| m.unit.as_deref().unwrap_or("").len()).chain([4]).max().unwrap_or(4);
+
+    let mut table = format!("Device: {} (Serial {})\n", device.name, device.serial);
+    table += &format!("{:<type_width$}  {:<unit_width$}  Filter  Sample\n", "Type", "Unit");
+    table += &format!("{}\n", "-".repeat(type_width + unit_width + 18));
+    for m in measurements {
+        table += &format!(
+            "{:<type_width$}  {:<unit_width$}  {:<6}  {}\n",
+            m.typ,
+            m.unit.as_deref().unwrap_or(""),
+            if filter.allows(&m.typ) { "yes" } else { "no" },
+            clean_value(m.value.as_deref()).unwrap_or("-")
+        );
+    }
+    let types: Vec<String> = measurements.iter().map(|m| serde_json::Value::from(m.typ.as_str()).to_string()).collect();
+    table += &format!("include_types = [{}]\n", types.join(", "));
+    table
+}
+
+async fn discover(config: &Config, inverters: &[Inverter]) -> Result<(), String> {
+    let http_client = build_http_client(config)?;
+    let mut failed = false;
+    for inverter in inverters {
+        println!("--- {} ---", inverter.url);
+        // Fetched without the type filter, the table shows what it lets through.
+        let unfiltered = Inverter { filter: TypeFilter::default(), ..inverter.clone() };
+        match fetch_devices(&http_client, &unfiltered, None).await {
+            Ok(devices) => {
+                for device in &devices {
+                    print!("{}", discovery_table(device, &inverter.filter));
+                }
+            }
+            Err(e) => {
+                failed = true;
+                error!("{}", e);
+            }
+        }
+    }
+    if failed { Err("Discovery failed for at least one inverter.".to_string()) } else { Ok(()) }
+}
+
+// --- Duplicate Types ---
+
+// Duplicates already warned about, so each device logs them once per run.
+static DUPLICATE_TYPES_LOGGED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+// A topic level or tag value from @Name, e.g. "Phase 1" becomes "Phase_1".
+fn type_suffix(name: &str) -> String {
+    let suffix: String = name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+    suffix.trim_matches('_').to_string()
+}
+
+// Measurements sharing a @Type would overwrite each other's topic and share
+// one InfluxDB series. With "index" the repeats become Type_2, Type_3, ...
+// in XML order, with "name" every one of them gets its @Name appended.
+fn disambiguate_types(device: &mut Device, mode: DuplicateTypes, label: &str) -> Result<(), String> {
+    let measurements = &mut device.measurements.measurement;
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for measurement in measurements.iter() {
+        *counts.entry(measurement.typ.as_str()).or_default() += 1;
+    }
+    let duplicated: Vec<String> =
+        counts.into_iter().filter(|(_, count)| *count > 1).map(|(typ, _)| typ.to_string()).collect();
+    if duplicated.is_empty() {
+        return Ok(());
+    }
+
+    let mut taken: BTreeSet<String> = measurements.iter().map(|m| m.typ.clone()).collect();
+    let mut next_index: BTreeMap<String, usize> = BTreeMap::new();
+    let mut unnamed = BTreeSet::new();
+    let mut renamed = Vec::new();
+    for measurement in measurements.iter_mut().filter(|m| duplicated.contains(&m.typ)) {
+        let typ = match mode {
+            DuplicateTypes::Index => {
+                let index = next_index.entry(measurement.typ.clone()).or_insert(1);
+                if *index == 1 {
+                    *index = 2;
+                    continue;
+                }
+                loop {
+                    let typ = format!("{}_{}", measurement.typ, index);
+                    *index += 1;
+                    if taken.insert(typ.clone()) {
+                        break typ;
+                    }
+                }
+            }
+            DuplicateTypes::Name => {
+                let suffix = measurement.name.as_deref().map(type_suffix).unwrap_or_default();
+                let typ = format!("{}_{}", measurement.typ, suffix);
+                if suffix.is_empty() || !taken.insert(typ.clone()) {
+                    unnamed.insert(measurement.typ.clone());
+                    continue;
+                }
+                typ
+            }
+        };
+        renamed.push(format!("{} -> {}", measurement.typ, typ));
+        measurement.typ = typ;
+    }
+
+    if !unnamed.is_empty() {
+        return Err(format!(
+            "Device '{}' repeats measurement types without a distinct Name: {}, set duplicate_types = \"index\" for inverter {}. Poll skipped.",
+            device.name,
+            unnamed.into_iter().collect::<Vec<_>>().join(", "),
+            label
+        ));
+    }
+    if DUPLICATE_TYPES_LOGGED.lock().unwrap().insert(format!("{} {}", device.serial, duplicated.join(","))) {
+        warn!(
+            "Device '{}' (Serial {}) repeats measurement types {}, published as {}",
+            device.name,
+            device.serial,
+            duplicated.join(", "),
+            renamed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// Prints what a poll would publish, used by --dry-run.
+fn print_measurements(device: &Device) {
+    let rows: Vec<(&str, &str, &str)> = device
+        .measurements
+        .measurement
+        .iter()
+        .map(|m| {
+            (
+                m.typ.as_str(),
+                m.value.as_deref().unwrap_or("-"),
+                m.unit.as_deref().unwrap_or(""),
+            )
+        })
+        .collect();
+
+    let type_width = rows.iter().map(|r| r.0.len()).chain([4]).max().unwrap_or(4);
+    let value_width = rows.iter().map(|r| r.1.len()).chain([5]).max().unwrap_or(5);
+
+    println!("Device: {} (Serial {})", device.name, device.serial);
+    if let Some(timestamp) = device.timestamp() {
+        println!("Timestamp: {}", timestamp.to_rfc3339());
+    }
+    println!("{:<type_width$}  {:>value_width$}  Unit", "Type", "Value");
+    println!("{}", "-".repeat(type_width + value_width + 8));
+    for (typ, value, unit) in rows {
+        println!("{:<type_width$}  {:>value_width$}  {}", typ, value, unit);
+    }
+}
+
+fn measurement_count(devices: &[Device]) -> usize {
+    devices.iter().map(|d| d.measurements.measurement.len()).sum()
+}
+
+// The sinks run side by side, so a slow broker does not delay the InfluxDB
+// write. Each sink still gets the devices in order. Returns whether any failed.
+async fn publish_to_sinks(sinks: &[Box<dyn MeasurementSink>], devices: &[Device]) -> bool {
+    let results = join_all(sinks.iter().map(|sink| async move {
+        let mut failed = false;
+        for device in devices {
+            if let Err(e) = sink.publish(device, &device.measurements.measurement).await {
+                failed = true;
+                error!("{}", e);
+            }
+        }
+        failed
+    }))
+    .await;
+    results.contains(&true)
+}
+
+async fn poll_inverter(
+    bridge: Arc<Bridge>,
+    inverter: Inverter,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), String> {
+    let mut error_count = 0;
+    // The serials are only known from the XML, the health message needs them
+    // even when the inverter stops answering.
+    let mut last_serials: Vec<String> = Vec::new();
+    let mut last_success: Option<DateTime<Utc>> = None;
+    let mut stale_data = StaleData::new(bridge.config.stale_after_polls);
+    let skip_stale_data = bridge.config.skip_stale_data.unwrap_or(false);
+    let mut ready = false;
+    let mut night_mode = bridge.config.night_mode.as_ref().map(NightMode::new);
+    // Shared with the other inverters until it is rebuilt for this one.
+    let mut http_client = bridge.http_client.clone();
+    let mut client_rebuild = ClientRebuild::new(&bridge.config);
+
+    // --once is a check run by hand, it does not wait for a boot to settle.
+    let first_delay_secs = first_poll_delay_secs(&bridge.config, &inverter);
+    if first_delay_secs > 0 && !bridge.once {
+        info!("First poll in {}s", first_delay_secs);
+        tokio::select! {
+            _ = sleep(Duration::from_secs(first_delay_secs)) => {}
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+
+    loop {
+        Metrics::inc(&bridge.metrics.polls_total);
+
+        let fetch_started = Instant::now();
+        let fetched = fetch_devices(&http_client, &inverter, bridge.raw_dump.as_ref()).await;
+        let unreachable = matches!(&fetched, Err(e) if e.kind == FetchFailure::Connect);
+        let mut night = false;
+        if let Some(night_mode) = &mut night_mode {
+            match (night_mode.observe(unreachable), night_mode.active) {
+                (true, true) => {
+                    info!(
+                        "Inverter {} unreachable for {} polls, night mode: polling every {}s until it answers",
+                        inverter.label, night_mode.unreachable_polls, night_mode.interval_secs
+                    );
+                    // The errors that led here were the inverter switching off.
+                    error_count = 0;
+                }
+                (true, false) => info!("Inverter {} answers again, night mode ended", inverter.label),
+                _ => {}
+            }
+            night = night_mode.active;
+        }
+        if client_rebuild.observe(&fetched, night) {
+            match build_http_client(&bridge.config) {
+                Ok(client) => {
+                    warn!(
+                        "{} requests in a row to inverter {} failed, rebuilding its HTTP client",
+                        client_rebuild.after_errors, inverter.label
+                    );
+                    http_client = client;
+                    Metrics::inc(&bridge.metrics.http_client_rebuilds_total);
+                }
+                Err(e) => error!("{}", e),
+            }
+        }
+        match fetched {
+            // Publishing a corrupt response would flood the sinks with bogus series,
+            // so the poll is dropped without touching the error count.
+            Ok(devices) if inverter.max_measurements.is_some_and(|max| measurement_count(&devices) > max) => {
+                warn!(
+                    "Skipping poll: {} measurements exceed max_measurements ({})",
+                    measurement_count(&devices),
+                    inverter.max_measurements.unwrap_or_default()
+                );
+            }
+            Ok(mut devices) => {
+                error_count = 0;
+                bridge.metrics.last_poll_timestamp.store(unix_time_secs(), Ordering::Relaxed);
+                last_success = Some(Utc::now());
+                last_serials = devices.iter().map(|d| d.serial.clone()).collect();
+                devices.retain(|device| !(stale_data.observe(device) && skip_stale_data));
+                let fetch_time = fetch_started.elapsed();
+                for device in &mut devices {
+                    prepare_measurements(device, &bridge.config, &bridge.smoothing, &bridge.derived);
+                    if bridge.config.publish_diagnostics.unwrap_or(false) {
+                        add_diagnostics(device, fetch_time);
+                    }
+                }
+
+                for device in &devices {
+                    debug!("Device: {:?}", device.name);
+                }
+                // A failing sink counts once per poll, no matter how many devices it missed.
+                if publish_to_sinks(&bridge.sinks, &devices).await {
+                    error_count += 1;
+                } else if !ready {
+                    ready = true;
+                    bridge.readiness.inverter_ready();
+                }
+            }
+            Err(e) if night => {
+                Metrics::inc(&bridge.metrics.poll_errors_total);
+                debug!("{}", e);
+            }
+            Err(e) => {
+                error_count += 1;
+                Metrics::inc(&bridge.metrics.poll_errors_total);
+                error!("{}", e);
+            }
+        }
+
+        // 4. MQTT Bridge Health
+        if let Some(topic) = &bridge.health_topic
+            && let Some(mqtt_client) = &bridge.mqtt_client {
+            for serial in &last_serials {
+                let health = HealthPayload {
+                    version: env!("CARGO_PKG_VERSION"),
+                    uptime_secs: bridge.started.elapsed().as_secs(),
+                    last_success: last_success.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, false)),
+                    consecutive_errors: error_count,
+                    stale_since: stale_data
+                        .stale_since(serial)
+                        .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, false)),
+                };
+
+                match serde_json::to_vec(&health) {
+                    Ok(payload) => {
+                        let topic = topic.replace("{serial}", serial);
+                        if let Err(e) = mqtt_client.publish(&topic, bridge.mqtt_qos, true, payload).await {
+                            error!("MQTT Publish Error: {:?}", e);
+                        } else {
+                            Metrics::inc(&bridge.metrics.mqtt_publishes_total);
+                            debug!("MQTT Published: {}", topic);
+                        }
+                    }
+                    Err(e) => error!("MQTT JSON Serialize Error: {:?}", e),
+                }
+            }
+        }
+
+        // The fetch and the sinks together, e.g. a slow link or a full MQTT queue.
+        let poll_time = fetch_started.elapsed();
+        let skipped = overrun_polls(poll_time, inverter.poll_interval_secs);
+        if skipped > 0 {
+            Metrics::inc(&bridge.metrics.poll_overruns_total);
+            warn!(
+                "Poll overrun: the poll took {:.1}s, longer than poll_interval_secs ({}s), {} scheduled polls skipped",
+                poll_time.as_secs_f64(),
+                inverter.poll_interval_secs,
+                skipped
+            );
+        }
+
+        if bridge.once {
+            return match (last_success, error_count) {
+                (Some(_), 0) => Ok(()),
+                _ => Err(format!("Poll failed for inverter {}.", inverter.label)),
+            };
+        }
+
+        if error_count >= inverter.max_errors {
+            if bridge.config.restart_on_max_errors.unwrap_or(true) {
+                return Err(format!("Too many errors ({}) for inverter {}, stopping.", error_count, inverter.label));
+            }
+
+            let cooldown_secs = bridge.config.error_cooldown_secs.unwrap_or(DEFAULT_ERROR_COOLDOWN_SECS);
+            warn!(
+                "Too many errors ({}) for inverter {}, pausing for {}s before trying again.",
+                error_count, inverter.label, cooldown_secs
+            );
+            error_count = 0;
+            tokio::select! {
+                _ = sleep(Duration::from_secs(cooldown_secs)) => continue,
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        let delay_secs = match &night_mode {
+            Some(night_mode) if night => night_mode.interval_secs,
+            _ => backoff_delay_secs(
+                inverter.poll_interval_secs,
+                error_count,
+                bridge.config.max_backoff_secs.unwrap_or(DEFAULT_MAX_BACKOFF_SECS),
+            ),
+        };
+        if error_count > 0 {
+            info!("Retrying in {}s ({} consecutive errors)", delay_secs, error_count);
+        }
+
+        tokio::select! {
+            _ = sleep(jittered_delay(delay_secs, inverter.poll_jitter_secs)) => {}
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(if e.is::<NotReady>() { EXIT_NOT_READY } else { 1 })
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    if args.print_example_config {
+        print!("{}", EXAMPLE_CONFIG);
+        return Ok(());
+    }
+
+    // --- Configuration Loading ---
+    let config_paths = [
+        "config.toml",
+        "/etc/solbridxml2mqtt/config.toml",
+    ];
+
+    let mut config_str = None;
+    let mut used_path = String::new();
+
+    if let Some(path) = &args.config_path {
+        // An explicitly given file must exist, never fall back to the defaults.
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config file '{}': {}", path, e))?;
+        config_str = Some(content);
+        used_path = path.clone();
+    } else {
+        for path in &config_paths {
+            if let Ok(content) = fs::read_to_string(path) {
+                config_str = Some(content);
+                used_path = path.to_string();
+                break;
+            }
+        }
+    }
+
+    let config_str = config_str
+        .ok_or("Could not find config.toml in any of these locations.")?;
+
+    let config = load_config(&config_str, |name| std::env::var(name).ok())?;
+
+    let quiet_mode = config.quiet_mode.unwrap_or(false);
+    let show_banner = config.show_banner.unwrap_or(!quiet_mode);
+    let verbose_publishes = config.verbose_publishes.unwrap_or(false);
+
+    // RUST_LOG takes precedence, quiet_mode only changes the default level.
+    let mut filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(if quiet_mode { "warn" } else { "info" }));
+    filter = filter.add_directive(
+        format!("{}={}", BANNER_TARGET, if show_banner { "info" } else { "off" }).parse()?,
+    );
+    filter = filter.add_directive(format!("{}=info", READY_TARGET).parse()?);
+    // The publish logs of the sinks are at debug level.
+    if verbose_publishes {
+        filter = filter.add_directive("SolBridXML2MQTT::sinks=debug".parse()?);
+    }
+    let log_timer = LogTimer(parse_timezone(config.timezone.as_deref())?);
+    let log_file = config.logging.as_ref().and_then(|logging_conf| {
+        let path = logging_conf.file.as_deref()?;
+        Some(RotatingFile::open(
+            path,
+            logging_conf.max_size_mb.unwrap_or(DEFAULT_LOG_MAX_SIZE_MB) * 1024 * 1024,
+            logging_conf.max_files.unwrap_or(DEFAULT_LOG_MAX_FILES),
+        ))
+    });
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).with_timer(log_timer);
+    let log_file_error = match log_file {
+        Some(Ok(log_file)) => {
+            subscriber.with_ansi(false).with_writer(Mutex::new(log_file)).init();
+            None
+        }
+        // Not a reason to stop the bridge, the warning below says where the log went.
+        Some(Err(e)) => {
+            subscriber.with_writer(std::io::stderr).init();
+            Some(e)
+        }
+        // With the stdout sink the log goes to stderr, so stdout only carries data lines.
+        None if config.stdout.is_some() => {
+            subscriber.with_writer(std::io::stderr).init();
+            None
+        }
+        None => {
+            subscriber.init();
+            None
+        }
+    };
+    if let Some(e) = log_file_error {
+        warn!("{}, logging to stderr instead.", e);
+    }
+
+    let problems = validate_config(&config);
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("Config: {}", problem);
+        }
+        return Err(format!("Invalid configuration in {} ({} problems).", used_path, problems.len()).into());
+    }
+
+    if let Some(serial) = &args.purge_serial {
+        let mqtt_conf = config.mqtt.as_ref().ok_or("--purge-retained needs an [mqtt] section.")?;
+        return Ok(purge_retained(mqtt_conf, serial).await?);
+    }
+
+    let inverters = resolve_inverters(&config)?;
+
+    if args.discover {
+        return Ok(discover(&config, &inverters).await?);
+    }
+
+    if args.validate {
+        return Ok(validate(&config, &used_path, &inverters).await?);
+    }
+
+    if args.dry_run {
+        // No sinks are set up at all, so nothing can be published by accident.
+        let http_client = build_http_client(&config)?;
+
+        let raw_dump = RawDump::from_config(&config);
+        let derived = resolve_derived(&config)?;
+        let smoothing = Smoothing::new(&config.smoothing);
+        let mut failed = false;
+        for inverter in &inverters {
+            println!("--- {} ---", inverter.url);
+            let fetch_started = Instant::now();
+            match fetch_devices(&http_client, inverter, raw_dump.as_ref()).await {
+                Ok(mut devices) => {
+                    let fetch_time = fetch_started.elapsed();
+                    for device in &mut devices {
+                        prepare_measurements(device, &config, &smoothing, &derived);
+                        if config.publish_diagnostics.unwrap_or(false) {
+                            add_diagnostics(device, fetch_time);
+                        }
+                        print_measurements(device);
+                    }
+                }
+                Err(e) => {
+                    failed = true;
+                    error!("{}", e);
+                }
+            }
+        }
+
+        return if failed {
+            Err("Dry run failed for at least one inverter.".into())
+        } else {
+            Ok(())
+        };
+    }
+
+    // --- Client Initialization ---
+
+    let http_client = build_http_client(&config)?;
+
+    let metrics = Arc::new(Metrics::default());
+
+    // MQTT Client Setup
+    // Now we just check if the `config.mqtt` struct exists
+    let (mqtt_client_option, mut mqtt_eventloop_handle) = if let Some(mqtt_conf) = &config.mqtt {
+        info!(target: BANNER_TARGET, "MQTT Configuration found: {}:{}", mqtt_conf.broker, mqtt_conf.port);
+        let (mqtt_client, mut eventloop) = mqtt_client::connect(
+            mqtt_conf,
+            mqtt_conf.channel_capacity.unwrap_or(DEFAULT_MQTT_CHANNEL_CAPACITY),
+        )?;
+
+        let connect_client = mqtt_client.clone();
+        let availability_topic = mqtt_conf.availability_topic.as_deref().map(|topic| mqtt_conf.topic(topic));
+        let birth = mqtt_conf.birth_topic.as_deref().map(|topic| {
+            (mqtt_conf.topic(topic), mqtt_conf.birth_payload.clone().unwrap_or_else(|| DEFAULT_MQTT_BIRTH_PAYLOAD.to_string()))
+        });
+        let eventloop_metrics = metrics.clone();
+        let reconnect_max_delay_secs = mqtt_conf
+            .reconnect_max_delay_secs
+            .unwrap_or(DEFAULT_MQTT_RECONNECT_MAX_DELAY_SECS);
+        let max_reconnect_attempts = mqtt_conf.max_reconnect_attempts;
+
+        let eventloop_handle = tokio::spawn(async move {
+            let mut failed_attempts: u32 = 0;
+            loop {
+                match eventloop.poll().await {
+                    Ok(MqttEvent::Connected) => {
+                        if failed_attempts > 0 {
+                            info!("MQTT reconnected after {} failed attempts", failed_attempts);
+                        }
+                        failed_attempts = 0;
+                        eventloop_metrics.mqtt_connected.store(1, Ordering::Relaxed);
+
+                        // Announce ourselves on every (re)connect. try_publish is used because
+                        // awaiting the request channel from inside the eventloop could deadlock.
+                        if let Some(topic) = &availability_topic
+                            && let Err(e) = connect_client.try_publish(topic, QoS::AtLeastOnce, true, "online") {
+                            error!("MQTT Availability Publish Error: {:?}", e);
+                        }
+                        if let Some((topic, payload)) = &birth
+                            && let Err(e) = connect_client.try_publish(topic, QoS::AtLeastOnce, false, payload.clone()) {
+                            error!("MQTT Birth Publish Error: {:?}", e);
+                        }
+                    }
+                    Ok(MqttEvent::Disconnected) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        eventloop_metrics.mqtt_connected.store(0, Ordering::Relaxed);
+                        failed_attempts = failed_attempts.saturating_add(1);
+                        // A broker that never accepts us is a misconfiguration, not an outage.
+                        if max_reconnect_attempts.is_some_and(|max| failed_attempts >= max) {
+                            return Err(format!(
+                                "MQTT connection failed {} times in a row, giving up: {}",
+                                failed_attempts, e
+                            ));
+                        }
+                        let delay_secs = backoff_delay_secs(1, failed_attempts - 1, reconnect_max_delay_secs);
+                        error!("MQTT Eventloop Error: {} (reconnecting in {}s)", e, delay_secs);
+                        sleep(Duration::from_secs(delay_secs)).await;
+                    }
+                }
+            }
+            Ok(())
+        });
+        (Some(mqtt_client), Some(eventloop_handle))
+    } else {
+        (None, None)
+    };
+
+    // --- Sink Setup ---
+
+    let mut sinks: Vec<Box<dyn MeasurementSink>> = Vec::new();
+
+    if let (Some(mqtt_client), Some(mqtt_conf)) = (&mqtt_client_option, &config.mqtt) {
+        sinks.push(Box::new(MqttSink::new(
+            mqtt_client.clone(),
+            mqtt_conf,
+            &config,
+            metrics.clone(),
+        )?));
+    }
+
+    if let Some(influx_conf) = &config.influxdb {
+        info!(target: BANNER_TARGET, "InfluxDB Configuration found: {}", influx_conf.url);
+        sinks.push(Box::new(InfluxSink::new(influx_conf, &config.topic_map, metrics.clone())?));
+    }
+
+    if let Some(influx_conf) = &config.influxdb_v1 {
+        info!(target: BANNER_TARGET, "InfluxDB 1.x Configuration found: {}", influx_conf.url);
+        sinks.push(Box::new(InfluxSink::new_v1(influx_conf, &config.topic_map, metrics.clone())?));
+    }
+
+    if let Some(stdout_conf) = &config.stdout {
+        sinks.push(Box::new(StdoutSink::new(parse_stdout_format(stdout_conf.format.as_deref())?)));
+    }
+
+    if let Some(sqlite_conf) = &config.sqlite {
+        info!(target: BANNER_TARGET, "SQLite database: {}", sqlite_conf.path);
+        sinks.push(Box::new(SqliteSink::new(sqlite_conf)?));
+    }
+
+    if let Some(webhook_conf) = &config.webhook {
+        info!(target: BANNER_TARGET, "Webhook: {}", webhook_conf.url);
+        sinks.push(Box::new(WebhookSink::new(http_client.clone(), webhook_conf, metrics.clone())?));
+    }
+
+    if let Some(prometheus_conf) = &config.prometheus {
+        let listener = TcpListener::bind(&prometheus_conf.listen)
+            .await
+            .map_err(|e| format!("Failed to listen on {} for Prometheus metrics: {}", prometheus_conf.listen, e))?;
+        info!("Prometheus metrics on http://{}/metrics", prometheus_conf.listen);
+
+        let metrics = metrics.clone();
+        tokio::spawn(serve_http(listener, move |path| match path {
+            "/metrics" => text_response(StatusCode::OK, "text/plain; version=0.0.4", metrics.render()),
+            _ => text_response(StatusCode::NOT_FOUND, "text/plain", "Not Found\n".to_string()),
+        }));
+    }
+
+    if let Some(measurements_conf) = &config.prometheus_measurements {
+        let listener = TcpListener::bind(&measurements_conf.listen).await.map_err(|e| {
+            format!("Failed to listen on {} for Prometheus measurements: {}", measurements_conf.listen, e)
+        })?;
+        info!("Prometheus measurements on http://{}/metrics", measurements_conf.listen);
+
+        let sink = PrometheusSink::new(measurements_conf);
+        let gauges = sink.gauges();
+        sinks.push(Box::new(sink));
+        tokio::spawn(serve_http(listener, move |path| match path {
+            "/metrics" => text_response(StatusCode::OK, "text/plain; version=0.0.4", gauges.render()),
+            _ => text_response(StatusCode::NOT_FOUND, "text/plain", "Not Found\n".to_string()),
+        }));
+    }
+
+    if let Some(health_conf) = &config.health {
+        let listener = TcpListener::bind(&health_conf.listen)
+            .await
+            .map_err(|e| format!("Failed to listen on {} for the health check: {}", health_conf.listen, e))?;
+        info!("Health check on http://{}/healthz", health_conf.listen);
+
+        let metrics = metrics.clone();
+        let max_age_secs = health_conf.max_age_secs.unwrap_or(DEFAULT_HEALTH_MAX_AGE_SECS);
+        let mqtt_enabled = mqtt_client_option.is_some();
+        tokio::spawn(serve_http(listener, move |path| match path {
+            "/healthz" => health_response(&metrics, max_age_secs, mqtt_enabled),
+            _ => text_response(StatusCode::NOT_FOUND, "text/plain", "Not Found\n".to_string()),
+        }));
+    }
+
+    if sinks.is_empty() {
+        return Err("No valid MQTT, InfluxDB, SQLite, webhook, stdout or Prometheus measurements configuration found. Please check your config.toml.".into());
+    }
+
+    // Outputs that are down keep retrying on their own, so by default the
+    // bridge starts with the others instead of failing as a whole.
+    let timeout = Duration::from_secs(SINK_CHECK_TIMEOUT_SECS);
+    let down: Vec<String> = join_all(sinks.iter().map(|sink| sink.check(timeout)))
+        .await
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+    if !down.is_empty() {
+        if config.require_all_sinks.unwrap_or(false) {
+            return Err(format!("Not every output is reachable and require_all_sinks is set: {}", down.join(" ")).into());
+        }
+        for problem in &down {
+            warn!("{} Starting with the other outputs, it is retried on every poll.", problem);
+        }
+    }
+
+    info!(target: BANNER_TARGET, "--- Startup Configuration ---");
+    info!(target: BANNER_TARGET, "Using configuration from: {}", used_path);
+    for inverter in &inverters {
+        info!(target: BANNER_TARGET, "Inverter URL: {}", inverter.url);
+        info!(target: BANNER_TARGET, "Poll Interval: {}s", inverter.poll_interval_secs);
+    }
+    info!(target: BANNER_TARGET, "-----------------------------");
+
+    let mqtt_qos = match &config.mqtt {
+        Some(mqtt_conf) => parse_qos(mqtt_conf.qos)?,
+        None => QoS::AtLeastOnce,
+    };
+
+    let health_topic = config
+        .mqtt
+        .as_ref()
+        .filter(|m| m.bridge_health.unwrap_or(false))
+        .map(|m| m.topic(m.bridge_health_topic.as_deref().unwrap_or(DEFAULT_BRIDGE_HEALTH_TOPIC)));
+
+    let raw_dump = RawDump::from_config(&config);
+    let derived = resolve_derived(&config)?;
+    let smoothing = Smoothing::new(&config.smoothing);
+    let readiness = Readiness::new(inverters.len());
+    let bridge = Arc::new(Bridge {
+        config,
+        http_client,
+        mqtt_client: mqtt_client_option,
+        mqtt_qos,
+        raw_dump,
+        smoothing,
+        derived,
+        once: args.once,
+        sinks,
+        health_topic,
+        started: Instant::now(),
+        metrics,
+        readiness,
+    });
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut tasks = JoinSet::new();
+    for inverter in inverters {
+        // The span tags every log line of this task with the inverter it belongs to.
+        let span = info_span!("inverter", label = %inverter.label);
+        tasks.spawn(poll_inverter(bridge.clone(), inverter, shutdown_rx.clone()).instrument(span));
+    }
+
+    // Run until a signal arrives or one inverter gives up after max_errors.
+    // With --once every task ends after one poll and all of them are awaited.
+    let mut result = Ok(());
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut ready_rx = bridge.readiness.ready.subscribe();
+    let wait_ready = async {
+        let secs = args.wait_ready_secs.unwrap_or_default();
+        tokio::time::timeout(Duration::from_secs(secs), ready_rx.wait_for(|ready| *ready)).await.is_ok()
+    };
+    tokio::pin!(wait_ready);
+    let mut waiting_ready = args.wait_ready_secs.is_some();
+    let mut not_ready = false;
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            ready = &mut wait_ready, if waiting_ready => {
+                waiting_ready = false;
+                if !ready {
+                    not_ready = true;
+                    break;
+                }
+            }
+            Some(joined) = async { Some(mqtt_eventloop_handle.as_mut()?.await) } => {
+                mqtt_eventloop_handle = None;
+                if let Err(e) = joined.map_err(|e| e.to_string()).and_then(|r| r) {
+                    result = Err(e);
+                    break;
+                }
+            }
+            joined = tasks.join_next() => {
+                let Some(joined) = joined else { break };
+                let task_result = joined.map_err(|e| e.to_string()).and_then(|r| r);
+                if !bridge.once {
+                    result = task_result;
+                    break;
+                }
+                if let Err(e) = task_result {
+                    error!("{}", e);
+                    result = Err("At least one inverter failed.".to_string());
+                }
+            }
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    // A task waiting for room in a full MQTT request queue (broker unreachable,
+    // channel_full = "wait") would never see the shutdown.
+    let stop_tasks = async { while tasks.join_next().await.is_some() {} };
+    if tokio::time::timeout(Duration::from_secs(MQTT_SHUTDOWN_TIMEOUT_SECS), stop_tasks).await.is_err() {
+        warn!("Inverter tasks did not stop within {}s, aborting them", MQTT_SHUTDOWN_TIMEOUT_SECS);
+        tasks.shutdown().await;
+    }
+
+    // --- Shutdown ---
+    info!("Shutting down...");
+
+    for sink in &bridge.sinks {
+        if let Err(e) = sink.flush().await {
+            error!("{}", e);
+            if bridge.once && result.is_ok() {
+                result = Err("Buffered measurements could not be written before exit.".to_string());
+            }
+        }
+    }
+
+    // The eventloop only stops once it has sent the disconnect, i.e. after every
+    // publish queued before it, which is the flush --once relies on.
+    let flush = async {
+        if let Some(mqtt_client) = &bridge.mqtt_client {
+            // Publish "offline" ourselves, a clean disconnect does not trigger the Last Will.
+            if let Some(mqtt_conf) = &bridge.config.mqtt
+                && let Some(topic) = &mqtt_conf.availability_topic
+                && let Err(e) = mqtt_client.publish(&mqtt_conf.topic(topic), QoS::AtLeastOnce, true, "offline").await {
+                error!("MQTT Availability Publish Error: {:?}", e);
+            }
+            if let Err(e) = mqtt_client.disconnect().await {
+                error!("MQTT Disconnect Error: {:?}", e);
+            }
+        }
+        if let Some(handle) = mqtt_eventloop_handle {
+            let _ = handle.await;
+        }
+    };
+    if bridge.mqtt_client.is_some()
+        && tokio::time::timeout(Duration::from_secs(MQTT_SHUTDOWN_TIMEOUT_SECS), flush).await.is_err() {
+        warn!("MQTT Flush timed out after {}s, queued messages may be lost", MQTT_SHUTDOWN_TIMEOUT_SECS);
+        if bridge.once && result.is_ok() {
+            result = Err("MQTT messages could not be delivered before exit.".to_string());
+        }
+    }
+
+    if not_ready {
+        let secs = args.wait_ready_secs.unwrap_or_default();
+        return Err(NotReady(format!("Not every inverter was polled successfully within {}s.", secs)).into());
+    }
+    result.map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const MEASUREMENTS_XML: &str = include_str!("../tests/fixtures/measurements.xml");
+    const MULTI_DEVICE_XML: &str = include_str!("../tests/fixtures/multi_device.xml");
+    const LATIN1_XML: &[u8] = include_bytes!("../tests/fixtures/latin1.xml");
+    const ENERGY_XML: &str = include_str!("../tests/fixtures/energy.xml");
+    const TRUNCATED_XML: &str = include_str!("../tests/fixtures/truncated.xml");
+    const DUPLICATE_TYPES_XML: &str = include_str!("../tests/fixtures/duplicate_types.xml");
+    const SOLBRID_LEGACY_XML: &str = include_str!("../tests/fixtures/solbrid_legacy.xml");
+    const NAMESPACED_XML: &str = include_str!("../tests/fixtures/namespaced.xml");
+
+    fn test_config(url: &str) -> Config {
+        toml::from_str(&format!("inverter_url = \"{}\"\npoll_interval_secs = 5\nmax_errors = 3\n", url)).unwrap()
+    }
+
+    async fn serve(response: ResponseTemplate) -> MockServer {
+        let server = MockServer::start().await;
+        mount(&server, "/measurements.xml", response).await;
+        server
+    }
+
+    async fn mount(server: &MockServer, route: &str, response: ResponseTemplate) {
+        Mock::given(method("GET")).and(path(route)).respond_with(response).mount(server).await;
+    }
+
+    async fn fetch_from(server: &MockServer) -> Result<Vec<Device>, String> {
+        let config = test_config(&format!("{}/measurements.xml", server.uri()));
+        let inverters = resolve_inverters(&config).unwrap();
+        fetch_devices(&build_http_client(&config).unwrap(), &inverters[0], None).await.map_err(String::from)
+    }
+
+    // --- Command Line Arguments ---
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_wait_ready() {
+        assert_eq!(args(&["--wait-ready", "30"]).unwrap().wait_ready_secs, Some(30));
+        for bad in [&["--wait-ready"][..], &["--wait-ready", "0"], &["--wait-ready", "soon"]] {
+            assert_eq!(args(bad).unwrap_err(), "--wait-ready requires a number of seconds greater than 0.");
+        }
+        assert!(args(&["--wait-ready", "30", "--once"]).is_err());
+    }
+
+    #[test]
+    fn parses_validate_only_on_its_own() {
+        assert!(args(&["--validate", "-c", "/etc/solbrid.toml"]).unwrap().validate);
+        assert!(!args(&[]).unwrap().validate);
+        for other in [&["--once"][..], &["--discover"], &["--purge-retained", "7799"], &["--wait-ready", "5"]] {
+            let mut combined = vec!["--validate"];
+            combined.extend_from_slice(other);
+            assert!(args(&combined).unwrap_err().starts_with("--validate cannot be combined"), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn discover_lists_all_types_and_what_the_filter_keeps() {
+        assert!(args(&["--discover"]).unwrap().discover);
+        assert!(args(&["--discover", "--dry-run"]).is_err());
+        let filter = TypeFilter::new(Some(&vec!["AC_*".to_string()]), None).unwrap();
+
+        let table = discovery_table(&parse_device(MEASUREMENTS_XML), &filter);
+
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "Device: SolBrid 10-3-4 (Serial 7799ABCDEXXXXXX000)");
+        assert_eq!(lines[1], "Type             Unit  Filter  Sample");
+        assert_eq!(lines[5], "AC_Power         W     yes     382.6");
+        assert_eq!(lines[9], "DC_Power         W     no      -");
+        assert_eq!(
+            lines.last().unwrap(),
+            &r#"include_types = ["AC_Voltage1", "AC_Current1", "AC_Power", "AC_Frequency", "BDC_BAT_Voltage", "Derating", "DC_Power", "DC_Current"]"#
+        );
+    }
+
+    #[test]
+    fn is_ready_once_every_inverter_was() {
+        let readiness = Readiness::new(2);
+        let ready = readiness.ready.subscribe();
+
+        readiness.inverter_ready();
+        assert!(!*ready.borrow());
+        readiness.inverter_ready();
+        assert!(*ready.borrow());
+    }
+
+    // --- Fetch and Parse ---
+
+    #[tokio::test]
+    async fn fetches_and_parses_the_fixture() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml")).await;
+
+        let devices = fetch_from(&server).await.unwrap();
+
+        assert_eq!(devices.len(), 1);
+        let device = &devices[0];
+        assert_eq!(device.name, "SolBrid 10-3-4");
+        assert_eq!(device.serial, "7799ABCDEXXXXXX000");
+        assert_eq!(device.timestamp().unwrap().to_rfc3339(), "2026-10-14T12:00:00+00:00");
+
+        let measurements = &device.measurements.measurement;
+        assert_eq!(measurements.len(), 8);
+        assert_eq!(measurements[0].typ, "AC_Voltage1");
+        assert_eq!(measurements[0].value.as_deref(), Some("237.3"));
+        assert_eq!(measurements[0].unit.as_deref(), Some("V"));
+        assert_eq!(measurements[4].typ, "BDC_BAT_Voltage");
+        assert_eq!(measurements[4].value, None);
+    }
+
+    #[tokio::test]
+    async fn reports_the_status_of_error_responses() {
+        let body = "<html><body><h1>503 Service Unavailable</h1></body></html>";
+        let server = serve(ResponseTemplate::new(503).set_body_raw(body, "text/html")).await;
+
+        let error = fetch_from(&server).await.unwrap_err();
+
+        assert!(error.starts_with("Request Error: HTTP 503 Service Unavailable"), "{}", error);
+        assert!(error.contains("503 Service Unavailable</h1>"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn decompresses_gzip_responses() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(MEASUREMENTS_XML.as_bytes()).unwrap();
+        let response = ResponseTemplate::new(200)
+            .insert_header("Content-Encoding", "gzip")
+            .set_body_raw(encoder.finish().unwrap(), "text/xml");
+        let server = serve(response).await;
+
+        let devices = fetch_from(&server).await.unwrap();
+
+        assert_eq!(devices[0].measurements.measurement.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn decodes_the_encoding_of_the_xml_declaration() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(LATIN1_XML, "text/xml")).await;
+
+        let devices = fetch_from(&server).await.unwrap();
+
+        assert_eq!(devices[0].measurements.measurement[0].unit.as_deref(), Some("°C"));
+    }
+
+    #[tokio::test]
+    async fn rejects_responses_without_a_device() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw("<root></root>", "text/xml")).await;
+
+        let error = fetch_from(&server).await.unwrap_err();
+
+        assert_eq!(error, "XML Parse Error: no Device element found");
+    }
+
+    #[tokio::test]
+    async fn replaces_an_empty_serial_with_serial_override() {
+        let xml = MEASUREMENTS_XML.replace("Serial='7799ABCDEXXXXXX000'", "Serial=' '");
+        let server = serve(ResponseTemplate::new(200).set_body_raw(xml, "text/xml")).await;
+        let mut config = test_config(&format!("{}/measurements.xml", server.uri()));
+        let http_client = build_http_client(&config).unwrap();
+
+        let error = fetch_devices(&http_client, &resolve_inverters(&config).unwrap()[0], None).await.unwrap_err();
+        assert!(error.message.starts_with("Device 'SolBrid 10-3-4' has no Serial, set serial_override"), "{}", error);
+
+        config.serial_override = Some("roof".to_string());
+        let devices = fetch_devices(&http_client, &resolve_inverters(&config).unwrap()[0], None).await.unwrap();
+        assert_eq!(devices[0].serial, "roof");
+    }
+
+    fn parse_device(xml: &str) -> Device {
+        from_str::<Root>(xml).unwrap().into_devices().remove(0)
+    }
+
+    #[test]
+    fn numbers_repeated_types_in_xml_order() {
+        let mut device = parse_device(DUPLICATE_TYPES_XML);
+
+        disambiguate_types(&mut device, DuplicateTypes::Index, "inv").unwrap();
+
+        assert_eq!(
+            types(&device),
+            ["AC_Voltage", "AC_Voltage_2", "AC_Voltage_3", "AC_Power", "Temperature", "Temperature_2"]
+        );
+        let xml = DUPLICATE_TYPES_XML.replace("Type='AC_Power'", "Type='AC_Voltage_2'");
+        let mut device = parse_device(&xml);
+        disambiguate_types(&mut device, DuplicateTypes::Index, "inv").unwrap();
+        assert_eq!(types(&device)[..4], ["AC_Voltage", "AC_Voltage_3", "AC_Voltage_4", "AC_Voltage_2"]);
+    }
+
+    #[test]
+    fn appends_the_name_to_repeated_types() {
+        let mut device = parse_device(DUPLICATE_TYPES_XML);
+
+        let error = disambiguate_types(&mut device, DuplicateTypes::Name, "inv").unwrap_err();
+        assert_eq!(
+            error,
+            "Device 'SolBrid 10-3-4' repeats measurement types without a distinct Name: Temperature, set duplicate_types = \"index\" for inverter inv. Poll skipped."
+        );
+
+        let xml = DUPLICATE_TYPES_XML.replace("Type='Temperature'/>", "Type='Temperature' Name='Heat sink'/>");
+        let xml = xml.replacen("Name='Heat sink'", "Name='Board'", 1);
+        let mut device = parse_device(&xml);
+        disambiguate_types(&mut device, DuplicateTypes::Name, "inv").unwrap();
+        assert_eq!(
+            types(&device),
+            ["AC_Voltage_L1", "AC_Voltage_L2", "AC_Voltage_L3", "AC_Power", "Temperature_Board", "Temperature_Heat_sink"]
+        );
+    }
+
+    fn parse_with(shape: &XmlShape, xml: &str) -> Result<Vec<Device>, String> {
+        let xml = shape.adapt(xml)?;
+        Ok(from_str::<Root>(&xml).map_err(|e| e.to_string())?.into_devices())
+    }
+
+    #[test]
+    fn parses_the_legacy_firmware_profile() {
+        let shape = XmlShape::resolve(Some("solbrid-legacy"), None, None, false).unwrap();
+
+        let devices = parse_with(&shape, SOLBRID_LEGACY_XML).unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].serial, "7799ABCDEXXXXXX100");
+        assert_eq!(devices[0].timestamp().unwrap().to_rfc3339(), "2026-10-14T12:00:00+00:00");
+        assert_eq!(types(&devices[0]), ["AC_Voltage1", "AC_Current1", "AC_Power", "AC_Frequency"]);
+        assert_eq!(
+            parse_with(&shape, MEASUREMENTS_XML).unwrap_err(),
+            "XML root element is <root>, expected <SolBrid>. Check firmware_profile and xml_root."
+        );
+    }
+
+    #[test]
+    fn strips_namespaces_only_when_asked_to() {
+        let shape = XmlShape::resolve(None, None, None, true).unwrap();
+
+        let devices = parse_with(&shape, NAMESPACED_XML).unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].serial, "7799ABCDEXXXXXX000");
+        assert_eq!(devices[0].name, "SolBrid 10-3-4");
+        assert_eq!(types(&devices[0]), ["AC_Voltage1", "AC_Power", "AC_Frequency"]);
+        assert_eq!(devices[0].measurements.measurement[1].description.as_deref(), Some("Grid feed-in > 0"));
+        let without = parse_with(&XmlShape::resolve(None, None, None, false).unwrap(), NAMESPACED_XML);
+        assert!(without.map_or(true, |devices| devices.is_empty()));
+        let default_namespace = MEASUREMENTS_XML.replace("<root>", "<root xmlns='http://www.solbrid.example/xml'>");
+        assert_eq!(parse_with(&XmlShape::resolve(None, None, None, false).unwrap(), &default_namespace).unwrap().len(), 1);
+        // Plain XML is passed through as it is.
+        assert_eq!(strip_namespaces(MEASUREMENTS_XML), MEASUREMENTS_XML);
+        assert_eq!(strip_namespaces(SOLBRID_LEGACY_XML), SOLBRID_LEGACY_XML);
+        assert_eq!(
+            strip_namespaces("<a:b xmlns:a=\"urn:a\" a:c = 'x:y'><!-- a:b --><![CDATA[<a:b>]]></a:b >"),
+            "<b c = 'x:y'><!-- a:b --><![CDATA[<a:b>]]></b >"
+        );
+    }
+
+    #[test]
+    fn element_names_can_be_overridden() {
+        let xml = MEASUREMENTS_XML.replace("<root>", "<status>").replace("</root>", "</status>");
+        let xml = xml.replace("<Device ", "<Unit ").replace("</Device>", "</Unit>");
+        let shape = XmlShape::resolve(None, Some("status"), Some("Unit"), false).unwrap();
+
+        let devices = parse_with(&shape, &xml).unwrap();
+
+        assert_eq!(devices[0].serial, "7799ABCDEXXXXXX000");
+        assert_eq!(devices[0].measurements.measurement.len(), 8);
+        assert_eq!(parse_with(&XmlShape::resolve(None, None, None, false).unwrap(), MEASUREMENTS_XML).unwrap().len(), 1);
+        assert!(XmlShape::resolve(Some("sunspec"), None, None, false).is_err());
+        assert!(XmlShape::resolve(None, None, Some("My Device"), false).is_err());
+    }
+
+    #[test]
+    fn delays_the_first_poll_by_initial_delay_and_optionally_one_interval() {
+        let delay = |toml: &str| {
+            let config: Config = toml::from_str(&format!(
+                "inverter_url = \"http://inverter/measurements.xml\"\npoll_interval_secs = 5\nmax_errors = 3\n{}",
+                toml
+            ))
+            .unwrap();
+            first_poll_delay_secs(&config, &resolve_inverters(&config).unwrap()[0])
+        };
+
+        assert_eq!(delay(""), 0);
+        assert_eq!(delay("initial_delay_secs = 30"), 30);
+        assert_eq!(delay("poll_on_start = false"), 5);
+        assert_eq!(delay("initial_delay_secs = 30\npoll_on_start = false"), 35);
+    }
+
+    #[test]
+    fn night_mode_starts_after_unreachable_polls_and_ends_with_an_answer() {
+        let mut night_mode = NightMode::new(&toml::from_str("after_errors = 3").unwrap());
+
+        let changes: Vec<(bool, bool)> = [true, true, false, true, true, true, true, false, true]
+            .into_iter()
+            .map(|unreachable| (night_mode.observe(unreachable), night_mode.active))
+            .collect();
+
+        assert_eq!(
+            changes,
+            [
+                (false, false),
+                (false, false),
+                (false, false),
+                (false, false),
+                (false, false),
+                (true, true),
+                (false, true),
+                (true, false),
+                (false, false),
+            ]
+        );
+        assert_eq!(night_mode.interval_secs, DEFAULT_NIGHT_MODE_POLL_INTERVAL_SECS);
+    }
+
+    #[tokio::test]
+    async fn tells_connection_errors_from_other_request_errors() {
+        // Nothing listens on a port that was just released.
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut config = test_config(&format!("http://127.0.0.1:{}/measurements.xml", port));
+        let inverter = resolve_inverters(&config).unwrap().remove(0);
+
+        let error = fetch_devices(&Client::new(), &inverter, None).await.unwrap_err();
+        assert_eq!(error.kind, FetchFailure::Connect, "{}", error);
+        assert!(error.message.starts_with("Connection Error: "), "{}", error);
+        let server = serve(ResponseTemplate::new(503)).await;
+        let answering = resolve_inverters(&test_config(&format!("{}/measurements.xml", server.uri()))).unwrap().remove(0);
+        let error = fetch_devices(&Client::new(), &answering, None).await.unwrap_err();
+        assert_eq!(error.kind, FetchFailure::Other, "{}", error);
+
+        config.night_mode = Some(toml::from_str("after_errors = 3").unwrap());
+        assert_eq!(
+            validate_config(&config),
+            [format!(
+                "night_mode after_errors (3) must be smaller than max_errors (3) of inverter http://127.0.0.1:{}/measurements.xml.",
+                port
+            )]
+        );
+    }
+
+    #[test]
+    fn rebuilds_the_http_client_after_failed_requests_in_a_row() {
+        let mut config = test_config("http://192.168.1.20/measurements.xml");
+        config.http_rebuild_after_errors = Some(2);
+        let mut rebuild = ClientRebuild::new(&config);
+        let failed = |kind| Err(FetchError { kind, message: String::new() });
+        let reset = failed(FetchFailure::Request);
+
+        assert!(!rebuild.observe(&reset, false));
+        assert!(!rebuild.observe(&Ok(Vec::new()), false));
+        assert!(!rebuild.observe(&reset, false));
+        assert!(rebuild.observe(&failed(FetchFailure::Connect), false));
+        assert_eq!(rebuild.failed_requests, 0);
+        // The inverter answered, e.g. with an HTTP error or bad XML, or is off for the night.
+        assert!(!rebuild.observe(&reset, false) && !rebuild.observe(&failed(FetchFailure::Other), false));
+        assert!(!rebuild.observe(&reset, true) && !rebuild.observe(&reset, true));
+
+        config.http_rebuild_after_errors = Some(0);
+        let mut never = ClientRebuild::new(&config);
+        assert!((0..10).all(|_| !never.observe(&reset, false)));
+    }
+
+    #[test]
+    fn detects_a_timestamp_stuck_for_several_polls() {
+        let device = |date_time: &str| {
+            parse_device(&MEASUREMENTS_XML.replace("2026-10-14T12:00:00", date_time))
+        };
+        let mut stale_data = StaleData::new(Some(2));
+
+        let stale: Vec<bool> = ["12:00:00", "12:00:00", "12:00:00", "12:00:00", "12:00:05"]
+            .into_iter()
+            .map(|time| {
+                let stale = stale_data.observe(&device(&format!("2026-10-14T{}", time)));
+                if stale {
+                    assert_eq!(
+                        stale_data.stale_since("7799ABCDEXXXXXX000").unwrap().to_rfc3339(),
+                        "2026-10-14T12:00:00+00:00"
+                    );
+                }
+                stale
+            })
+            .collect();
+
+        assert_eq!(stale, [false, false, true, true, false]);
+        assert_eq!(stale_data.stale_since("7799ABCDEXXXXXX000"), None);
+        let without_timestamp = MEASUREMENTS_XML.replace(" DateTime='2026-10-14T12:00:00'", "");
+        for _ in 0..4 {
+            assert!(!stale_data.observe(&parse_device(&without_timestamp)));
+        }
+        assert!(!StaleData::new(None).observe(&device("2026-10-14T12:00:00")));
+    }
+
+    #[tokio::test]
+    async fn reports_a_truncated_response_after_one_retry() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(TRUNCATED_XML, "text/xml"))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let error = fetch_from(&server).await.unwrap_err();
+
+        assert_eq!(error, "Truncated Response (700 bytes): the XML ends before </root>");
+    }
+
+    #[tokio::test]
+    async fn retries_a_truncated_response_once() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml")).await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(TRUNCATED_XML, "text/xml"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        let devices = fetch_from(&server).await.unwrap();
+
+        assert_eq!(devices[0].measurements.measurement.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn refetches_malformed_xml_up_to_parse_retries() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml")).await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("<root><Device></Oops></root>", "text/xml"))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        let fetch = |parse_retries: u32| {
+            let mut config = test_config(&format!("{}/measurements.xml", server.uri()));
+            config.parse_retries = Some(parse_retries);
+            async move {
+                let inverters = resolve_inverters(&config).unwrap();
+                fetch_devices(&build_http_client(&config).unwrap(), &inverters[0], None).await
+            }
+        };
+
+        let error = fetch(1).await.unwrap_err().message;
+        assert!(error.starts_with("XML Parse Error: ") && error.ends_with(" (still failing on retry 1/1)"));
+        assert_eq!(fetch(1).await.unwrap()[0].measurements.measurement.len(), 8);
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[test]
+    fn tells_truncated_from_malformed_xml() {
+        assert_eq!(truncation(""), Some("the XML ends before its root element".to_string()));
+        assert_eq!(truncation("<?xml version='1.0'"), Some("the XML ends before its root element".to_string()));
+        assert_eq!(truncation("<?xml version='1.0'?>\n<roo"), Some("the XML ends inside <roo>".to_string()));
+        assert_eq!(truncation("<root><Device>"), Some("the XML ends before </root>".to_string()));
+        assert_eq!(truncation("<root><Device></root"), Some("the XML ends before </root>".to_string()));
+        assert_eq!(truncation("<!-- x --><root><Device Serial=1></root>\n"), None);
+        assert_eq!(truncation("<root/>"), None);
+        assert_eq!(truncation("Internal Server Error"), None);
+    }
+
+    #[tokio::test]
+    async fn closes_the_connection_without_keep_alive() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("connection", "close"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml"))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let mut config = test_config(&format!("{}/measurements.xml", server.uri()));
+        config.http_keep_alive = Some(false);
+        let inverters = resolve_inverters(&config).unwrap();
+        let http_client = build_http_client(&config).unwrap();
+
+        for _ in 0..2 {
+            fetch_devices(&http_client, &inverters[0], None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn merges_the_devices_of_all_urls_by_serial() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml")).await;
+        mount(&server, "/energy.xml", ResponseTemplate::new(200).set_body_raw(ENERGY_XML, "text/xml")).await;
+        let config: Config = toml::from_str(&format!(
+            "inverter_url = [\"{0}/measurements.xml\", \"{0}/energy.xml\"]\npoll_interval_secs = 5\nmax_errors = 3\n",
+            server.uri()
+        ))
+        .unwrap();
+        let inverters = resolve_inverters(&config).unwrap();
+
+        let devices = fetch_devices(&build_http_client(&config).unwrap(), &inverters[0], None).await.unwrap();
+
+        assert_eq!(devices.len(), 2);
+        let inverter = &devices[0];
+        assert_eq!(inverter.name, "SolBrid 10-3-4");
+        assert_eq!(inverter.date_time.as_deref(), Some("2026-10-14T12:00:00"));
+        let measurements = &inverter.measurements.measurement;
+        assert_eq!(measurements.len(), 9);
+        let ac_power = measurements.iter().find(|m| m.typ == "AC_Power").unwrap();
+        assert_eq!(ac_power.value.as_deref(), Some("390.1"));
+        assert_eq!(measurements[8].typ, "E_Total");
+        assert_eq!(devices[1].serial, "B1");
+    }
+
+    #[tokio::test]
+    async fn keeps_repeated_types_of_a_second_url_apart() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml")).await;
+        let phases = DUPLICATE_TYPES_XML.replace("238.1", "239.0").replace("38.0", "37.5");
+        mount(&server, "/phases.xml", ResponseTemplate::new(200).set_body_raw(phases, "text/xml")).await;
+        mount(&server, "/duplicates.xml", ResponseTemplate::new(200).set_body_raw(DUPLICATE_TYPES_XML, "text/xml")).await;
+        let config: Config = toml::from_str(&format!(
+            "inverter_url = [\"{0}/duplicates.xml\", \"{0}/phases.xml\"]\npoll_interval_secs = 5\nmax_errors = 3\n",
+            server.uri()
+        ))
+        .unwrap();
+        let inverters = resolve_inverters(&config).unwrap();
+
+        let devices = fetch_devices(&build_http_client(&config).unwrap(), &inverters[0], None).await.unwrap();
+
+        let values: Vec<_> = devices[0]
+            .measurements
+            .measurement
+            .iter()
+            .map(|m| (m.typ.as_str(), m.value.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("AC_Voltage", "237.3"),
+                ("AC_Voltage_2", "236.9"),
+                ("AC_Voltage_3", "239.0"),
+                ("AC_Power", "382.6"),
+                ("Temperature", "41.5"),
+                ("Temperature_2", "37.5"),
+            ]
+        );
+
+        let config: Config = toml::from_str(&format!(
+            "inverter_url = [\"{0}/measurements.xml\", \"{0}/phases.xml\"]\npoll_interval_secs = 5\nmax_errors = 3\n",
+            server.uri()
+        ))
+        .unwrap();
+        let inverters = resolve_inverters(&config).unwrap();
+        let devices = fetch_devices(&build_http_client(&config).unwrap(), &inverters[0], None).await.unwrap();
+        // The AC_Power of the second URL replaces the first one, the repeats are added.
+        assert_eq!(types(&devices[0])[8..], ["AC_Voltage", "AC_Voltage_2", "AC_Voltage_3", "Temperature", "Temperature_2"]);
+    }
+
+    #[tokio::test]
+    async fn fails_the_poll_when_one_url_fails() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml")).await;
+        let config: Config = toml::from_str(&format!(
+            "poll_interval_secs = 5\nmax_errors = 3\n[[inverter]]\nurl = [\"{0}/measurements.xml\", \"{0}/energy.xml\"]\n",
+            server.uri()
+        ))
+        .unwrap();
+        let inverters = resolve_inverters(&config).unwrap();
+
+        let error = fetch_devices(&build_http_client(&config).unwrap(), &inverters[0], None).await.unwrap_err();
+
+        assert_eq!(error.kind, FetchFailure::Other);
+        let error = error.message;
+        assert!(error.starts_with("Request Error: HTTP 404 Not Found"), "{}", error);
+        assert!(error.ends_with(&format!("[{}/energy.xml]", server.uri())), "{}", error);
+    }
+
+    #[test]
+    fn rejects_an_empty_url_list() {
+        let config: Config = toml::from_str("inverter_url = []\npoll_interval_secs = 5\nmax_errors = 3\n").unwrap();
+
+        assert_eq!(resolve_inverters(&config).unwrap_err(), "Inverter URL list must not be empty.");
+    }
+
+    #[tokio::test]
+    async fn reads_timestamps_without_offset_in_the_configured_timezone() {
+        let server = serve(ResponseTemplate::new(200).set_body_raw(MEASUREMENTS_XML, "text/xml")).await;
+        let mut config = test_config(&format!("{}/measurements.xml", server.uri()));
+        config.timezone = Some("Europe/Berlin".to_string());
+        let inverters = resolve_inverters(&config).unwrap();
+
+        let devices = fetch_devices(&build_http_client(&config).unwrap(), &inverters[0], None).await.unwrap();
+
+        // CEST is UTC+2 in October.
+        assert_eq!(devices[0].timestamp().unwrap().to_rfc3339(), "2026-10-14T10:00:00+00:00");
+        assert_eq!(
+            parse_timestamp("2026-10-14T12:00:00+00:00", Some(Tz::Europe__Berlin)).unwrap().to_rfc3339(),
+            "2026-10-14T12:00:00+00:00"
+        );
+        // 02:30 does not exist on the night clocks go forward.
+        assert_eq!(parse_timestamp("2026-03-29T02:30:00", Some(Tz::Europe__Berlin)), None);
+    }
+
+    #[test]
+    fn rejects_an_unknown_timezone() {
+        let mut config = test_config("http://192.168.1.20/measurements.xml");
+        config.timezone = Some("Europe/Atlantis".to_string());
+
+        assert_eq!(
+            validate_config(&config),
+            vec!["Unknown timezone 'Europe/Atlantis', use an IANA name like \"Europe/Berlin\".".to_string()]
+        );
+    }
+
+    #[test]
+    fn flattens_nested_devices_parents_first() {
+        let root: Root = from_str(MULTI_DEVICE_XML).unwrap();
+
+        let devices = root.into_devices();
+
+        let serials: Vec<&str> = devices.iter().map(|d| d.serial.as_str()).collect();
+        assert_eq!(serials, ["M1", "S1", "S2"]);
+        assert_eq!(devices[2].measurements.measurement.len(), 2);
+    }
+
+    #[test]
+    fn charset_of_the_content_type_wins_over_the_declaration() {
+        let xml = decode_xml(LATIN1_XML, Some("text/xml; charset=\"ISO-8859-1\""));
+        assert!(xml.contains("Unit=\"°C\""));
+        assert!(!xml.contains("<?xml"));
+
+        // Decoding Latin-1 bytes as UTF-8 replaces the degree sign.
+        let xml = decode_xml(LATIN1_XML, Some("text/xml; charset=utf-8"));
+        assert!(xml.contains('\u{FFFD}'));
+    }
+
+    // --- Example Config ---
+
+    #[test]
+    fn example_config_is_valid() {
+        let config: Config = toml::from_str(EXAMPLE_CONFIG).unwrap();
+
+        assert_eq!(validate_config(&config), Vec::<String>::new());
+    }
+
+    // Catches options that changed their type without the example following.
+    #[test]
+    fn example_config_parses_with_every_option_enabled() {
+        let enabled: String = EXAMPLE_CONFIG
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(option) if option.starts_with('[') || option.split_once(" = ").is_some_and(|(key, _)| {
+                    key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                }) => option,
+                _ => line,
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        let config: Config = toml::from_str(&enabled).unwrap();
+
+        assert!(config.mqtt.is_some_and(|m| m.birth_topic.is_some()));
+        assert!(config.influxdb.is_some() && config.influxdb_v1.is_some());
+        assert!(config.stdout.is_some() && config.prometheus.is_some() && config.health.is_some());
+        assert!(config.prometheus_measurements.is_some());
+        assert!(config.logging.is_some_and(|l| l.file.is_some()));
+        assert_eq!(config.inverters.len(), 1);
+        assert_eq!(config.normalize.len(), 1);
+        assert_eq!(config.derived.len(), 1);
+    }
+
+    // --- Sinks ---
+
+    // Records when each device arrived, optionally after a delay and failing.
+    struct TestSink {
+        delay: Duration,
+        fail: bool,
+        published: Arc<std::sync::Mutex<Vec<(String, tokio::time::Instant)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl MeasurementSink for TestSink {
+        async fn publish(&self, device: &Device, _measurements: &[Measurement]) -> Result<(), String> {
+            sleep(self.delay).await;
+            self.published.lock().unwrap().push((device.serial.clone(), tokio::time::Instant::now()));
+            if self.fail { Err("test sink failed".to_string()) } else { Ok(()) }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_slow_sink_does_not_delay_the_others() {
+        let slow = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let fast = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sinks: Vec<Box<dyn MeasurementSink>> = vec![
+            Box::new(TestSink { delay: Duration::from_millis(300), fail: true, published: slow.clone() }),
+            Box::new(TestSink { delay: Duration::ZERO, fail: false, published: fast.clone() }),
+        ];
+        let root: Root = from_str(MULTI_DEVICE_XML).unwrap();
+        let devices = root.into_devices();
+        let started = tokio::time::Instant::now();
+
+        assert!(publish_to_sinks(&sinks, &devices).await);
+
+        let fast = fast.lock().unwrap();
+        assert_eq!(fast.len(), devices.len());
+        assert!(fast.iter().all(|(_, at)| *at == started));
+        let slow = slow.lock().unwrap();
+        assert!(slow.iter().all(|(_, at)| *at >= started + Duration::from_millis(300)));
+        let slow_serials: Vec<_> = slow.iter().map(|(serial, _)| serial.clone()).collect();
+        let serials: Vec<_> = devices.iter().map(|d| d.serial.clone()).collect();
+        assert_eq!(slow_serials, serials);
+    }
+
+    // --- Environment Variables ---
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "INFLUX_TOKEN" => Some("s3cret".to_string()),
+            "HOST" => Some("192.168.1.10".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn substitutes_environment_variables_in_strings() {
+        let config = load_config(
+            "inverter_url = \"http://${HOST}/measurements.xml\"\npoll_interval_secs = 5\nmax_errors = 3\n\
+             inverter_password = \"pa$$word\"\n\
+             [influxdb]\nurl = \"http://localhost:8086\"\ntoken = \"${INFLUX_TOKEN}\"\norg = \"o\"\nbucket = \"b\"\n",
+            env,
+        )
+        .unwrap();
+
+        let inverters = resolve_inverters(&config).unwrap();
+        assert_eq!(inverters[0].url, "http://192.168.1.10/measurements.xml");
+        assert_eq!(config.influxdb.unwrap().token.as_deref(), Some("s3cret"));
+        assert_eq!(config.inverter_password.as_deref(), Some("pa$$word"));
+    }
+
+    #[test]
+    fn fails_on_unset_environment_variables() {
+        let error = load_config(
+            "inverter_url = \"http://${INVERTER}/x.xml\"\npoll_interval_secs = 5\nmax_errors = 3\n\
+             [mqtt]\nbroker = \"${BROKER}\"\nport = 1883\nclient_id = \"${BROKER}\"\n",
+            env,
+        )
+        .unwrap_err();
+
+        assert_eq!(error, "config.toml references unset environment variables: BROKER, INVERTER");
+    }
+
+    // --- Secret Files ---
+
+    #[test]
+    fn reads_the_influx_token_from_a_file() {
+        let path = std::env::temp_dir().join(format!("solbrid_token_{}", std::process::id()));
+        let influx = |extra: &str| -> InfluxDbConfig {
+            toml::from_str(&format!("url = \"http://localhost:8086\"\norg = \"o\"\nbucket = \"b\"\n{}", extra)).unwrap()
+        };
+        let with_file = influx(&format!("token = \"inline\"\ntoken_file = \"{}\"", path.display()));
+
+        let error = influx_token(&with_file).unwrap_err();
+        assert!(error.starts_with(&format!("Could not read InfluxDB token_file '{}':", path.display())), "{}", error);
+        fs::write(&path, " \n").unwrap();
+        assert_eq!(influx_token(&with_file).unwrap_err(), format!("InfluxDB token_file '{}' is empty.", path.display()));
+        fs::write(&path, "s3cret\n").unwrap();
+        assert_eq!(influx_token(&with_file).unwrap(), "s3cret");
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(influx_token(&influx("token = \"inline\"")).unwrap(), "inline");
+        assert_eq!(influx_token(&influx("")).unwrap_err(), "InfluxDB token is not set, add token or token_file.");
+    }
+
+    // --- Type Filters ---
+
+    #[test]
+    fn type_filters_accept_exact_types_globs_and_regexes() {
+        let include = vec!["AC_U_*".to_string(), "/^DC_[IU]\\d$/".to_string(), "Derating".to_string()];
+        let exclude = vec!["AC_U_L?_avg".to_string()];
+        let filter = TypeFilter::new(Some(&include), Some(&exclude)).unwrap();
+
+        for typ in ["AC_U_L1", "AC_U_L3", "DC_U1", "Derating"] {
+            assert!(filter.allows(typ), "{}", typ);
+        }
+        for typ in ["AC_U_L1_avg", "AC_I_L1", "DC_U12", "Derating2", "xAC_U_L1"] {
+            assert!(!filter.allows(typ), "{}", typ);
+        }
+
+        let error = TypeFilter::new(None, Some(&vec!["/AC_(/".to_string()])).unwrap_err();
+        assert!(error.starts_with("Invalid exclude_types pattern '/AC_(/':"), "{}", error);
+    }
+
+    // --- Limits ---
+
+    fn limited_device() -> Device {
+        let root: Root = from_str(MEASUREMENTS_XML).unwrap();
+        root.into_devices().remove(0)
+    }
+
+    fn limits(toml: &str) -> BTreeMap<String, LimitRule> {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn types(device: &Device) -> Vec<&str> {
+        device.measurements.measurement.iter().map(|m| m.typ.as_str()).collect()
+    }
+
+    #[test]
+    fn limits_drop_values_below_min() {
+        let mut device = limited_device();
+
+        apply_limits(&mut device, &limits("AC_Voltage1 = { min = 240 }"));
+
+        assert!(!types(&device).contains(&"AC_Voltage1"));
+        assert_eq!(device.measurements.measurement.len(), 7);
+    }
+
+    #[test]
+    fn limits_drop_values_above_max() {
+        let mut device = limited_device();
+
+        apply_limits(&mut device, &limits("AC_Power = { min = 0, max = 300 }"));
+
+        assert!(!types(&device).contains(&"AC_Power"));
+    }
+
+    #[test]
+    fn limits_keep_values_in_range_and_values_without_a_number() {
+        let mut device = limited_device();
+
+        // Bounds are inclusive, and DC_Power (NaN) or BDC_BAT_Voltage (no value) cannot be checked.
+        apply_limits(
+            &mut device,
+            &limits("AC_Voltage1 = { min = 237.3, max = 237.3 }\nDC_Power = { max = 0 }\nBDC_BAT_Voltage = { min = 1 }"),
+        );
+
+        assert_eq!(device.measurements.measurement.len(), 8);
+    }
+
+    #[test]
+    fn limits_apply_to_derived_measurements() {
+        let mut config = test_config("http://127.0.0.1/measurements.xml");
+        config.limits = limits("Double_Power = { max = 500 }");
+        let derived = [DerivedMeasurement::parse(&DerivedConfig {
+            name: "Double_Power".to_string(),
+            expression: "AC_Power * 2".to_string(),
+            unit: None,
+        })
+        .unwrap()];
+        let mut device = limited_device();
+
+        prepare_measurements(&mut device, &config, &Smoothing::new(&config.smoothing), &derived);
+
+        assert!(!types(&device).contains(&"Double_Power"));
+    }
+
+    #[test]
+    fn diagnostics_add_the_fetch_time_in_milliseconds() {
+        let mut device = limited_device();
+
+        add_diagnostics(&mut device, Duration::from_micros(12_345));
+
+        let fetch_ms = device.measurements.measurement.last().unwrap();
+        assert_eq!(fetch_ms.typ, "_fetch_ms");
+        assert_eq!(fetch_ms.value.as_deref(), Some("12.3"));
+        assert_eq!(fetch_ms.unit.as_deref(), Some("ms"));
+    }
+
+    // --- Topic Map ---
+
+    #[test]
+    fn topic_map_renames_mapped_types_only() {
+        let topic_map: BTreeMap<String, String> = toml::from_str("AC_U_L1 = \"grid_voltage\"").unwrap();
+        let template = TopicTemplate::parse(DEFAULT_TOPIC_TEMPLATE).unwrap();
+
+        let render = |typ| template.render("7799", topic_segment(&topic_map, typ), "", "");
+
+        assert_eq!(render("AC_U_L1"), "inverter/7799/grid_voltage");
+        assert_eq!(render("AC_Power"), "inverter/7799/AC_Power");
+    }
+
+    #[test]
+    fn rejects_deadbands_that_are_not_a_finite_threshold() {
+        let mut config = test_config("http://192.168.1.20/measurements.xml");
+        config.deadband = toml::from_str(
+            "AC_Power = { absolute = nan }\nAC_Voltage = { percent = -1 }\nE_Total = { absolute = inf }\n\
+             SOC = { absolute = 1, percent = 1 }\nTemperature = {}\nDC_Power = { percent = 0 }",
+        )
+        .unwrap();
+
+        assert_eq!(
+            validate_config(&config),
+            [
+                "deadband for AC_Power must be a finite number of at least 0.",
+                "deadband for AC_Voltage must be a finite number of at least 0.",
+                "deadband for E_Total must be a finite number of at least 0.",
+                "deadband for SOC needs either absolute or percent.",
+                "deadband for Temperature needs either absolute or percent.",
+            ]
+        );
+    }
+
+    #[test]
+    fn topic_map_rejects_duplicate_and_invalid_names() {
+        let mut config = test_config("http://192.168.1.20/measurements.xml");
+        config.topic_map = toml::from_str("AC_U_L1 = \"voltage\"\nAC_U_L2 = \"voltage\"\nAC_P = \"grid/power\"").unwrap();
+
+        assert_eq!(
+            validate_config(&config),
+            [
+                "topic_map name 'grid/power' for AC_P must be one topic level without '/', '+' or '#'.",
+                "topic_map maps both AC_U_L1 and AC_U_L2 to 'voltage'.",
+            ]
+        );
+    }
+
+    #[test]
+    fn base_topic_prefixes_topics_with_one_slash() {
+        let mqtt_conf = |base: &str| -> MqttConfig {
+            toml::from_str(&format!("broker = \"localhost\"\nport = 1883\nclient_id = \"solbrid\"\n{}", base)).unwrap()
+        };
+
+        for base in ["base_topic = \"acme/solar\"", "base_topic = \"/acme/solar/\""] {
+            assert_eq!(mqtt_conf(base).topic("inverter/7799/AC_Power"), "acme/solar/inverter/7799/AC_Power");
+            assert_eq!(mqtt_conf(base).topic("/bridge/status"), "acme/solar/bridge/status");
+        }
+        assert_eq!(mqtt_conf("base_topic = \"/\"").topic("inverter/7799"), "inverter/7799");
+        assert_eq!(mqtt_conf("").topic(DISCOVERY_TOPIC_PREFIX), "homeassistant/sensor");
+
+        let mut config = test_config("http://192.168.1.20/measurements.xml");
+        config.mqtt = Some(mqtt_conf("base_topic = \"acme/#\""));
+        assert_eq!(validate_config(&config), ["MQTT base_topic must not contain '+' or '#'."]);
+    }
+
+    // --- Polling ---
+
+    #[test]
+    fn counts_the_polls_an_overrun_skipped() {
+        assert_eq!(overrun_polls(Duration::from_millis(4900), 5), 0);
+        assert_eq!(overrun_polls(Duration::from_secs(5), 5), 1);
+        assert_eq!(overrun_polls(Duration::from_millis(12300), 5), 2);
+        assert_eq!(overrun_polls(Duration::from_secs(3), 0), 0);
+    }
+
+    // --- Values ---
+
+    #[test]
+    fn parse_value_accepts_numbers() {
+        assert_eq!(parse_value("382.6"), Some(382.6));
+        assert_eq!(parse_value("42"), Some(42.0));
+        assert_eq!(parse_value("-12.5"), Some(-12.5));
+        assert_eq!(parse_value("1.5e3"), Some(1500.0));
+        assert_eq!(parse_value(" 7 "), Some(7.0));
+    }
+
+    #[test]
+    fn parse_value_rejects_garbage_and_non_finite_values() {
+        assert_eq!(parse_value(""), None);
+        assert_eq!(parse_value("abc"), None);
+        assert_eq!(parse_value("12 W"), None);
+        assert_eq!(parse_value("1,5"), None);
+        assert_eq!(parse_value("NaN"), None);
+        assert_eq!(parse_value("inf"), None);
+    }
+
+    #[test]
+    fn clean_value_drops_values_without_a_measurement() {
+        assert_eq!(clean_value(None), None);
+        assert_eq!(clean_value(Some("")), None);
+        assert_eq!(clean_value(Some("   ")), None);
+        assert_eq!(clean_value(Some("NaN")), None);
+        assert_eq!(clean_value(Some("-inf")), None);
+        assert_eq!(clean_value(Some(" 0.0 ")), Some("0.0"));
+        assert_eq!(clean_value(Some("OK")), Some("OK"));
+    }
+
+    #[test]
+    fn sanitize_topic_level_replaces_separators_and_wildcards() {
+        assert_eq!(sanitize_topic_level(" SolBrid 10-3-4 "), "SolBrid_10-3-4");
+        assert_eq!(sanitize_topic_level("a/b+c#d\te"), "a_b_c_d_e");
+    }
+
+    #[test]
+    fn body_snippet_collapses_whitespace_and_truncates() {
+        assert_eq!(body_snippet(b"  <h1>Not\n  Found</h1> "), "<h1>Not Found</h1>");
+        assert_eq!(body_snippet(b" \n "), "(empty body)");
+
+        let snippet = body_snippet("x".repeat(300).as_bytes());
+        assert_eq!(snippet.len(), 203);
+        assert!(snippet.ends_with("..."));
+    }
+}
@@ -0,0 +1,245 @@
+// --- MQTT Client ---
+//
+// rumqttc has separate client and eventloop types for MQTT 3.1.1 and MQTT 5.
+// MqttClient and MqttEventLoop hide which one mqtt_version picked, so the
+// sinks and the connection task publish the same way on both. MQTT 5
+// publishes carry the configured user_properties.
+
+use crate::{mqtt_credentials, mqtt_endpoint, mqtt_options, MqttConfig, MQTT_KEEP_ALIVE_SECS};
+use rumqttc::v5::mqttbytes::v5::{LastWillProperties, Packet as PacketV5, PublishProperties};
+use rumqttc::v5::mqttbytes::QoS as QoSV5;
+use rumqttc::{v5, AsyncClient, ClientError, Event, EventLoop, LastWill, Outgoing, Packet, QoS};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+pub(crate) const DEFAULT_MQTT_VERSION: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MqttVersion {
+    V311,
+    V5,
+}
+
+pub(crate) fn parse_mqtt_version(value: Option<u8>) -> Result<MqttVersion, String> {
+    match value.unwrap_or(DEFAULT_MQTT_VERSION) {
+        3 => Ok(MqttVersion::V311),
+        5 => Ok(MqttVersion::V5),
+        other => Err(format!("Invalid MQTT mqtt_version {}, allowed values are 3 and 5.", other)),
+    }
+}
+
+fn qos_v5(qos: QoS) -> QoSV5 {
+    match qos {
+        QoS::AtMostOnce => QoSV5::AtMostOnce,
+        QoS::AtLeastOnce => QoSV5::AtLeastOnce,
+        QoS::ExactlyOnce => QoSV5::ExactlyOnce,
+    }
+}
+
+fn user_properties(mqtt_conf: &MqttConfig) -> Vec<(String, String)> {
+    mqtt_conf
+        .user_properties
+        .iter()
+        .flat_map(BTreeMap::iter)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+// The request error of either client, formatted like the rumqttc one.
+pub(crate) struct MqttClientError {
+    // try_publish found the request queue full (channel_full = "drop").
+    queue_full: bool,
+    message: String,
+}
+
+impl MqttClientError {
+    pub(crate) fn is_queue_full(&self) -> bool {
+        self.queue_full
+    }
+}
+
+impl From<ClientError> for MqttClientError {
+    fn from(e: ClientError) -> Self {
+        MqttClientError { queue_full: matches!(e, ClientError::TryRequest(_)), message: format!("{:?}", e) }
+    }
+}
+
+impl From<v5::ClientError> for MqttClientError {
+    fn from(e: v5::ClientError) -> Self {
+        MqttClientError { queue_full: matches!(e, v5::ClientError::TryRequest(_)), message: format!("{:?}", e) }
+    }
+}
+
+impl fmt::Debug for MqttClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum MqttClient {
+    V311(AsyncClient),
+    V5 { client: v5::AsyncClient, properties: PublishProperties },
+}
+
+impl MqttClient {
+    pub(crate) async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V311(client) => {
+                client.publish(topic, qos, retain, payload).await.map_err(MqttClientError::from)
+            }
+            MqttClient::V5 { client, properties } => client
+                .publish_with_properties(topic, qos_v5(qos), retain, payload.into(), properties.clone())
+                .await
+                .map_err(MqttClientError::from),
+        }
+    }
+
+    // Does not wait for room in the request queue, see channel_full.
+    pub(crate) fn try_publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V311(client) => client.try_publish(topic, qos, retain, payload).map_err(MqttClientError::from),
+            MqttClient::V5 { client, properties } => client
+                .try_publish_with_properties(topic, qos_v5(qos), retain, payload.into(), properties.clone())
+                .map_err(MqttClientError::from),
+        }
+    }
+
+    pub(crate) async fn disconnect(&self) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V311(client) => client.disconnect().await.map_err(MqttClientError::from),
+            MqttClient::V5 { client, .. } => client.disconnect().await.map_err(MqttClientError::from),
+        }
+    }
+}
+
+// The events the connection task reacts to, the rest is Other.
+#[derive(Debug, PartialEq)]
+pub(crate) enum MqttEvent {
+    Connected,
+    Disconnected,
+    Other,
+}
+
+pub(crate) enum MqttEventLoop {
+    // Boxed, both eventloops are large and moved into the connection task.
+    V311(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl MqttEventLoop {
+    pub(crate) async fn poll(&mut self) -> Result<MqttEvent, String> {
+        match self {
+            MqttEventLoop::V311(eventloop) => match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Ok(MqttEvent::Connected),
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => Ok(MqttEvent::Disconnected),
+                Ok(_) => Ok(MqttEvent::Other),
+                Err(e) => Err(format!("{:?}", e)),
+            },
+            MqttEventLoop::V5(eventloop) => match eventloop.poll().await {
+                Ok(v5::Event::Incoming(PacketV5::ConnAck(_))) => Ok(MqttEvent::Connected),
+                Ok(v5::Event::Outgoing(Outgoing::Disconnect)) => Ok(MqttEvent::Disconnected),
+                Ok(_) => Ok(MqttEvent::Other),
+                Err(e) => Err(format!("{:?}", e)),
+            },
+        }
+    }
+}
+
+// The bridge connection, with a retained "offline" Last Will on the
+// availability_topic. --purge-retained always uses MQTT 3.1.1.
+pub(crate) fn connect(mqtt_conf: &MqttConfig, capacity: usize) -> Result<(MqttClient, MqttEventLoop), String> {
+    let availability_topic = mqtt_conf.availability_topic.as_deref().map(|topic| mqtt_conf.topic(topic));
+    match parse_mqtt_version(mqtt_conf.mqtt_version)? {
+        MqttVersion::V311 => {
+            let mut options = mqtt_options(mqtt_conf, &mqtt_conf.client_id)?;
+            if let Some(topic) = &availability_topic {
+                options.set_last_will(LastWill::new(topic, "offline", QoS::AtLeastOnce, true));
+            }
+            let (client, eventloop) = AsyncClient::new(options, capacity);
+            Ok((MqttClient::V311(client), MqttEventLoop::V311(Box::new(eventloop))))
+        }
+        MqttVersion::V5 => {
+            // The same settings as mqtt_options, for the MQTT 5 option type.
+            let (broker, transport) = mqtt_endpoint(mqtt_conf)?;
+            let mut options = v5::MqttOptions::new(&mqtt_conf.client_id, broker, mqtt_conf.port);
+            options.set_keep_alive(Duration::from_secs(MQTT_KEEP_ALIVE_SECS));
+            options.set_clean_start(mqtt_conf.clean_session.unwrap_or(true));
+            if let Some((username, password)) = mqtt_credentials(mqtt_conf)? {
+                options.set_credentials(username, password);
+            }
+            if let Some(transport) = transport {
+                options.set_transport(transport);
+            }
+            if let Some(topic) = availability_topic {
+                let properties = LastWillProperties {
+                    delay_interval: None,
+                    payload_format_indicator: None,
+                    message_expiry_interval: None,
+                    content_type: None,
+                    response_topic: None,
+                    correlation_data: None,
+                    user_properties: user_properties(mqtt_conf),
+                };
+                options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                    topic,
+                    "offline",
+                    QoSV5::AtLeastOnce,
+                    true,
+                    Some(properties),
+                ));
+            }
+            let (client, eventloop) = v5::AsyncClient::new(options, capacity);
+            let properties = PublishProperties {
+                user_properties: user_properties(mqtt_conf),
+                ..Default::default()
+            };
+            Ok((MqttClient::V5 { client, properties }, MqttEventLoop::V5(Box::new(eventloop))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mqtt_config(toml: &str) -> MqttConfig {
+        toml::from_str(&format!("broker = \"localhost\"\nport = 1883\nclient_id = \"solbrid\"\n{}", toml)).unwrap()
+    }
+
+    #[test]
+    fn parses_mqtt_versions() {
+        assert_eq!(parse_mqtt_version(None), Ok(MqttVersion::V311));
+        assert_eq!(parse_mqtt_version(Some(3)), Ok(MqttVersion::V311));
+        assert_eq!(parse_mqtt_version(Some(5)), Ok(MqttVersion::V5));
+        assert!(parse_mqtt_version(Some(4)).unwrap_err().contains("allowed values are 3 and 5"));
+    }
+
+    #[tokio::test]
+    async fn attaches_user_properties_to_mqtt5_publishes() {
+        let mqtt_conf = mqtt_config("availability_topic = \"inverter/bridge/status\"\nmqtt_version = 5\nuser_properties = { site = \"garage\", source = \"solbrid\" }");
+
+        let (client, _eventloop) = connect(&mqtt_conf, 10).unwrap();
+
+        let MqttClient::V5 { properties, .. } = &client else { panic!("expected an MQTT 5 client") };
+        assert_eq!(
+            properties.user_properties,
+            [("site".to_string(), "garage".to_string()), ("source".to_string(), "solbrid".to_string())]
+        );
+        let (client, _eventloop) = connect(&mqtt_config(""), 10).unwrap();
+        assert!(matches!(client, MqttClient::V311(_)));
+    }
+}
@@ -0,0 +1,202 @@
+// --- Purge Retained Messages ---
+//
+// --purge-retained SERIAL removes what a decommissioned inverter left on the
+// broker. The bridge does not know every topic it ever published, so the
+// retained messages are collected by subscribing to the topics of the serial
+// and each one is then overwritten with an empty retained payload.
+
+use crate::{mqtt_options, MqttConfig, DEFAULT_BRIDGE_HEALTH_TOPIC, DEFAULT_TOPIC_TEMPLATE, DISCOVERY_TOPIC_PREFIX};
+use rumqttc::{AsyncClient, Event, Outgoing, Packet, QoS};
+use std::collections::BTreeSet;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use tracing::info;
+
+// Retained messages arrive right after the subscription, a quiet second
+// means all of them are there.
+const PURGE_IDLE_MS: u64 = 1000;
+const PURGE_TIMEOUT_SECS: u64 = 30;
+
+// Topic filters that match everything the bridge publishes for `serial`.
+// Levels with a placeholder other than {serial} become '+'. All topics,
+// including the discovery_prefix, are already below base_topic.
+fn purge_filters(
+    topic_template: &str,
+    health_topic: &str,
+    discovery_prefix: &str,
+    serial: &str,
+) -> Result<Vec<String>, String> {
+    if !topic_template.contains("{serial}") {
+        return Err(format!(
+            "topic_template '{}' has no {{serial}}, the topics of one inverter cannot be told apart.",
+            topic_template
+        ));
+    }
+    let filter = |template: &str| {
+        template
+            .split('/')
+            .map(|level| {
+                let rest = level.replace("{serial}", "");
+                if rest.contains('{') { "+".to_string() } else { level.replace("{serial}", serial) }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+
+    let measurements = filter(topic_template);
+    // The metadata topics of numeric_payloads and publish_metadata.
+    let filters: BTreeSet<String> = [
+        format!("{}/unit", measurements),
+        format!("{}/name", measurements),
+        format!("{}/type", measurements),
+        measurements,
+        filter(health_topic),
+        format!("{}/+/config", discovery_prefix),
+    ]
+    .into_iter()
+    .collect();
+    Ok(filters.into_iter().collect())
+}
+
+// Discovery configs of all inverters share one filter, only ours are removed.
+fn belongs_to(topic: &str, discovery_prefix: &str, serial: &str) -> bool {
+    match topic.strip_prefix(discovery_prefix).and_then(|rest| rest.strip_prefix('/')) {
+        Some(rest) => rest.starts_with(&format!("{}_", serial)),
+        None => true,
+    }
+}
+
+// The eventloop task only ends early on a connection error.
+async fn connection_error(handle: JoinHandle<Result<(), String>>) -> String {
+    match handle.await {
+        Ok(Err(e)) => e,
+        _ => "MQTT connection closed unexpectedly.".to_string(),
+    }
+}
+
+pub(crate) async fn purge_retained(mqtt_conf: &MqttConfig, serial: &str) -> Result<(), String> {
+    if serial.is_empty() || serial.contains(['/', '+', '#']) {
+        return Err(format!("--purge-retained needs a serial without '/', '+' or '#', got '{}'.", serial));
+    }
+    let discovery_prefix = mqtt_conf.topic(DISCOVERY_TOPIC_PREFIX);
+    let filters = purge_filters(
+        &mqtt_conf.topic(mqtt_conf.topic_template.as_deref().unwrap_or(DEFAULT_TOPIC_TEMPLATE)),
+        &mqtt_conf.topic(mqtt_conf.bridge_health_topic.as_deref().unwrap_or(DEFAULT_BRIDGE_HEALTH_TOPIC)),
+        &discovery_prefix,
+        serial,
+    )?;
+
+    // A client id of its own, a running bridge would otherwise be disconnected.
+    let mut options = mqtt_options(mqtt_conf, &format!("{}_purge", mqtt_conf.client_id))?;
+    options.set_clean_session(true);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let eventloop_handle = tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => return Ok(()),
+                Ok(event) => {
+                    if events_tx.send(event).is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Err(format!("MQTT connection error: {:?}", e)),
+            }
+        }
+    });
+    let deadline = Instant::now() + Duration::from_secs(PURGE_TIMEOUT_SECS);
+    let timed_out = || format!("Purging the retained messages of {} timed out after {}s.", serial, PURGE_TIMEOUT_SECS);
+
+    for filter in &filters {
+        client
+            .subscribe(filter, QoS::AtLeastOnce)
+            .await
+            .map_err(|e| format!("MQTT Subscribe Error: {:?}", e))?;
+    }
+
+    // 1. Collect the retained topics.
+    let mut topics = BTreeSet::new();
+    let mut subscribed = 0;
+    loop {
+        let wait = if subscribed < filters.len() {
+            deadline.saturating_duration_since(Instant::now())
+        } else {
+            Duration::from_millis(PURGE_IDLE_MS)
+        };
+        match timeout(wait, events.recv()).await {
+            Ok(Some(Event::Incoming(Packet::SubAck(_)))) => subscribed += 1,
+            Ok(Some(Event::Incoming(Packet::Publish(publish)))) => {
+                if publish.retain && !publish.payload.is_empty() && belongs_to(&publish.topic, &discovery_prefix, serial) {
+                    topics.insert(publish.topic);
+                }
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(connection_error(eventloop_handle).await),
+            Err(_) if subscribed < filters.len() => return Err(timed_out()),
+            Err(_) => break,
+        }
+    }
+
+    // 2. Overwrite them, an empty retained payload deletes the message.
+    for topic in &topics {
+        client
+            .publish(topic, QoS::AtLeastOnce, true, Vec::new())
+            .await
+            .map_err(|e| format!("MQTT Publish Error: {:?}", e))?;
+        info!("Cleared retained {}", topic);
+    }
+    let mut acked = 0;
+    while acked < topics.len() {
+        match timeout(deadline.saturating_duration_since(Instant::now()), events.recv()).await {
+            Ok(Some(Event::Incoming(Packet::PubAck(_)))) => acked += 1,
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(connection_error(eventloop_handle).await),
+            Err(_) => return Err(timed_out()),
+        }
+    }
+
+    client.disconnect().await.map_err(|e| format!("MQTT Disconnect Error: {:?}", e))?;
+    eventloop_handle.await.map_err(|e| e.to_string())??;
+    info!("Cleared {} retained messages of {}", topics.len(), serial);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_cover_the_topics_of_one_serial() {
+        let filters =
+            purge_filters(DEFAULT_TOPIC_TEMPLATE, DEFAULT_BRIDGE_HEALTH_TOPIC, DISCOVERY_TOPIC_PREFIX, "7799").unwrap();
+
+        assert_eq!(
+            filters,
+            [
+                "homeassistant/sensor/+/config",
+                "inverter/7799/+",
+                "inverter/7799/+/name",
+                "inverter/7799/+/type",
+                "inverter/7799/+/unit",
+                "inverter/7799/bridge"
+            ]
+        );
+        let filters =
+            purge_filters("solar/{name}/inv-{serial}/{type}_{unit}", "health/{serial}", "acme/homeassistant/sensor", "7799")
+                .unwrap();
+        assert!(filters.contains(&"solar/+/inv-7799/+".to_string()));
+        assert!(filters.contains(&"acme/homeassistant/sensor/+/config".to_string()));
+        assert!(purge_filters("solar/{type}", DEFAULT_BRIDGE_HEALTH_TOPIC, DISCOVERY_TOPIC_PREFIX, "7799").is_err());
+    }
+
+    #[test]
+    fn only_removes_discovery_configs_of_the_serial() {
+        assert!(belongs_to("homeassistant/sensor/7799_AC_Power/config", DISCOVERY_TOPIC_PREFIX, "7799"));
+        assert!(!belongs_to("homeassistant/sensor/77990_AC_Power/config", DISCOVERY_TOPIC_PREFIX, "7799"));
+        assert!(!belongs_to("acme/homeassistant/sensor/77990_AC_Power/config", "acme/homeassistant/sensor", "7799"));
+        assert!(belongs_to("inverter/7799/AC_Power", DISCOVERY_TOPIC_PREFIX, "7799"));
+    }
+}
@@ -0,0 +1,767 @@
+use super::MeasurementSink;
+use crate::{
+    clean_value, influx_token, parse_field_type, parse_influx_precision, parse_influx_schema, parse_value,
+    topic_segment, Device, FieldType, InfluxDbConfig, InfluxDbV1Config, InfluxPointConfig, InfluxSchema,
+    Measurement, Metrics, StringValues,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use influxdb2::api::write::TimestampPrecision;
+use influxdb2::models::data_point::DataPointBuilder;
+use influxdb2::models::{DataPoint, FieldValue, Status, WriteDataPoint};
+use influxdb2::Client as InfluxClient;
+use reqwest::header::{AUTHORIZATION, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+const DEFAULT_INFLUX_RETRIES: u32 = 3;
+const INFLUX_RETRY_DELAY_MS: u64 = 500;
+// Wait after a 429 without (a readable) Retry-After, and the longest one we follow.
+const DEFAULT_INFLUX_RETRY_AFTER_SECS: u64 = 30;
+const MAX_INFLUX_RETRY_AFTER_SECS: u64 = 300;
+// Longest one write waits for 429s in total, the other outputs and the next
+// poll wait for it. Its points are then kept for a later write.
+const MAX_INFLUX_RATE_LIMIT_WAIT_SECS: u64 = 60;
+// Most points kept between writes, while InfluxDB keeps rate limiting the
+// oldest ones are dropped so the buffer and the next request stay bounded.
+const MAX_INFLUX_PENDING_POINTS: usize = 10_000;
+const DEFAULT_INFLUX_MEASUREMENT: &str = "inverter_data";
+// Field of string_values, InfluxDB rejects a "value" that is a float in some points and a string in others.
+const STRING_VALUE_FIELD: &str = "text";
+
+// --- InfluxDB Sink ---
+
+// Where the points go, the point construction is the same for both versions.
+enum InfluxWriter {
+    // Writes are sent without the client, its errors lose the Retry-After
+    // header of a 429, it is only used for the health check.
+    V2 {
+        client: InfluxClient,
+        http_client: reqwest::Client,
+        url: String,
+        org: String,
+        bucket: String,
+        token: String,
+    },
+    // InfluxDB 1.x has no client in influxdb2, its /write endpoint takes the
+    // same line protocol.
+    V1 {
+        http_client: reqwest::Client,
+        // Without /write, the health check uses /ping.
+        url: String,
+        database: String,
+        retention_policy: Option<String>,
+        credentials: Option<(String, String)>,
+    },
+}
+
+pub(crate) struct InfluxSink {
+    writer: InfluxWriter,
+    measurement: String,
+    retries: u32,
+    precision: TimestampPrecision,
+    schema: InfluxSchema,
+    extra_tags: Vec<(String, String)>,
+    name_tag: bool,
+    // Types missing here are written as float fields.
+    field_types: HashMap<String, FieldType>,
+    string_values: StringValues,
+    // Empty unless the type tag uses the [topic_map] names.
+    topic_map: BTreeMap<String, String>,
+    // Points are collected until batch_size publishes or batch_interval
+    // have passed, whichever comes first. The default writes every publish.
+    batch_size: usize,
+    batch_interval: Option<Duration>,
+    pending: Mutex<PendingPoints>,
+    max_pending_points: usize,
+    metrics: Arc<Metrics>,
+}
+
+enum WriteError {
+    // HTTP 429 of InfluxDB Cloud, with how long it asks us to wait.
+    RateLimited(Duration),
+    Failed(String),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::RateLimited(wait) => write!(f, "HTTP 429 Too Many Requests, retry after {}s", wait.as_secs()),
+            WriteError::Failed(e) => f.write_str(e),
+        }
+    }
+}
+
+// Retry-After is either seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    Some((at - now).to_std().unwrap_or_default())
+}
+
+#[derive(Default)]
+struct PendingPoints {
+    points: Vec<DataPoint>,
+    publishes: usize,
+    since: Option<Instant>,
+    // Set by a 429 that outlasted MAX_INFLUX_RATE_LIMIT_WAIT_SECS, the next
+    // write is not tried before this Retry-After.
+    not_before: Option<tokio::time::Instant>,
+}
+
+// InfluxDB rejects writes that change the type of an existing field, so a
+// value that does not fit the configured type is dropped instead.
+fn coerce_field(value: &str, field_type: FieldType) -> Option<FieldValue> {
+    match field_type {
+        FieldType::Float => parse_value(value).map(FieldValue::F64),
+        FieldType::Integer => value
+            .parse::<i64>()
+            .ok()
+            .or_else(|| parse_value(value).filter(|v| v.fract() == 0.0 && v.abs() < i64::MAX as f64).map(|v| v as i64))
+            .map(FieldValue::I64),
+        FieldType::Boolean => match value.to_ascii_lowercase().as_str() {
+            "true" | "on" | "1" => Some(FieldValue::Bool(true)),
+            "false" | "off" | "0" => Some(FieldValue::Bool(false)),
+            _ => None,
+        },
+        FieldType::String => Some(FieldValue::String(value.to_string())),
+    }
+}
+
+impl InfluxSink {
+    pub(crate) fn new(
+        influx_conf: &InfluxDbConfig,
+        topic_map: &BTreeMap<String, String>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let token = influx_token(influx_conf)?;
+        let writer = InfluxWriter::V2 {
+            client: InfluxClient::new(&influx_conf.url, &influx_conf.org, &token),
+            http_client: reqwest::Client::new(),
+            url: influx_conf.url.trim_end_matches('/').to_string(),
+            org: influx_conf.org.clone(),
+            bucket: influx_conf.bucket.clone(),
+            token,
+        };
+        Self::with_writer(writer, &influx_conf.points, topic_map, metrics)
+    }
+
+    pub(crate) fn new_v1(
+        influx_conf: &InfluxDbV1Config,
+        topic_map: &BTreeMap<String, String>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let writer = InfluxWriter::V1 {
+            http_client: reqwest::Client::new(),
+            url: influx_conf.url.trim_end_matches('/').to_string(),
+            database: influx_conf.database.clone(),
+            retention_policy: influx_conf.retention_policy.clone(),
+            credentials: influx_conf.username.clone().zip(influx_conf.password.clone()),
+        };
+        Self::with_writer(writer, &influx_conf.points, topic_map, metrics)
+    }
+
+    fn with_writer(
+        writer: InfluxWriter,
+        points: &InfluxPointConfig,
+        topic_map: &BTreeMap<String, String>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        Ok(InfluxSink {
+            writer,
+            measurement: points
+                .measurement
+                .clone()
+                .unwrap_or_else(|| DEFAULT_INFLUX_MEASUREMENT.to_string()),
+            retries: points.retries.unwrap_or(DEFAULT_INFLUX_RETRIES),
+            precision: parse_influx_precision(points.precision.as_deref())?,
+            schema: parse_influx_schema(points.schema.as_deref())?,
+            extra_tags: points.extra_tags.clone().unwrap_or_default().into_iter().collect(),
+            name_tag: points.name_tag.unwrap_or(false),
+            field_types: points
+                .field_types
+                .iter()
+                .flatten()
+                .map(|(typ, field_type)| Ok((typ.clone(), parse_field_type(field_type)?)))
+                .collect::<Result<_, String>>()?,
+            string_values: points.string_values.clone().unwrap_or(StringValues::All(false)),
+            topic_map: if points.topic_map.unwrap_or(false) { topic_map.clone() } else { BTreeMap::new() },
+            batch_size: points.batch_size.unwrap_or(1),
+            batch_interval: points.batch_interval_secs.map(Duration::from_secs),
+            pending: Mutex::new(PendingPoints::default()),
+            max_pending_points: MAX_INFLUX_PENDING_POINTS,
+            metrics,
+        })
+    }
+
+    async fn write(&self, points: &[DataPoint]) -> Result<(), WriteError> {
+        let mut body = Vec::new();
+        for point in points {
+            point.write_data_point_to(&mut body).map_err(|e| WriteError::Failed(e.to_string()))?;
+        }
+
+        let (precision_v2, precision_v1) = match self.precision {
+            TimestampPrecision::Seconds => ("s", "s"),
+            TimestampPrecision::Milliseconds => ("ms", "ms"),
+            TimestampPrecision::Microseconds => ("us", "u"),
+            TimestampPrecision::Nanoseconds => ("ns", "n"),
+        };
+        let request = match &self.writer {
+            InfluxWriter::V2 { http_client, url, org, bucket, token, .. } => http_client
+                .post(format!("{}/api/v2/write", url))
+                .query(&[("org", org.as_str()), ("bucket", bucket.as_str()), ("precision", precision_v2)])
+                .header(AUTHORIZATION, format!("Token {}", token)),
+            InfluxWriter::V1 { http_client, url, database, retention_policy, credentials } => {
+                // InfluxDB 1.x spells the precisions u and n instead of us and ns.
+                let mut query = vec![("db", database.as_str()), ("precision", precision_v1)];
+                if let Some(retention_policy) = retention_policy {
+                    query.push(("rp", retention_policy.as_str()));
+                }
+
+                let mut request = http_client.post(format!("{}/write", url)).query(&query);
+                if let Some((username, password)) = credentials {
+                    request = request.basic_auth(username, Some(password));
+                }
+                request
+            }
+        };
+
+        let resp = request.body(body).send().await.map_err(|e| WriteError::Failed(format!("{:?}", e)))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let wait = resp
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, Utc::now()))
+                .unwrap_or(Duration::from_secs(DEFAULT_INFLUX_RETRY_AFTER_SECS));
+            return Err(WriteError::RateLimited(wait.min(Duration::from_secs(MAX_INFLUX_RETRY_AFTER_SECS))));
+        }
+        let text = resp.text().await.unwrap_or_default();
+        Err(WriteError::Failed(format!("HTTP {}: {}", status, text.trim())))
+    }
+
+    // For --validate: an empty write needs the same token or user, bucket or
+    // database and write permission as a real one, but stores nothing.
+    pub(crate) async fn check_write(&self, timeout: Duration) -> Result<(), String> {
+        match tokio::time::timeout(timeout, self.write(&[])).await {
+            Ok(result) => result.map_err(|e| format!("InfluxDB write check failed: {}", e)),
+            Err(_) => Err(format!("InfluxDB did not answer the write check within {}s.", timeout.as_secs())),
+        }
+    }
+
+    // The field of a measurement and whether it is the text of string_values,
+    // None if there is nothing to write.
+    fn field_value(&self, measurement: &Measurement) -> Option<(FieldValue, bool)> {
+        let raw_value = clean_value(measurement.value.as_deref())?;
+        match self.field_types.get(&measurement.typ) {
+            None => match parse_value(raw_value) {
+                Some(value) => Some((FieldValue::F64(value), false)),
+                None if self.string_values.applies_to(&measurement.typ) => {
+                    Some((FieldValue::String(raw_value.to_string()), true))
+                }
+                None => None,
+            },
+            Some(&field_type) => {
+                let value = coerce_field(raw_value, field_type);
+                if value.is_none() {
+                    warn!("InfluxDB: '{}' is not a valid {:?} value for {}, skipped", raw_value, field_type, measurement.typ);
+                }
+                value.map(|value| (value, false))
+            }
+        }
+    }
+
+    // The tags both schemas share.
+    fn point(&self, device: &Device, timestamp: i64) -> DataPointBuilder {
+        let mut builder = DataPoint::builder(self.measurement.as_str())
+            .tag("serial", device.serial.as_str())
+            .timestamp(timestamp);
+
+        if self.name_tag && !device.name.is_empty() {
+            builder = builder.tag("name", device.name.as_str());
+        }
+
+        for (key, value) in &self.extra_tags {
+            builder = builder.tag(key.as_str(), value.as_str());
+        }
+        builder
+    }
+
+    // The point timestamp has to be given in the unit the write request announces.
+    fn timestamp(&self, time: DateTime<Utc>) -> i64 {
+        match self.precision {
+            TimestampPrecision::Seconds => time.timestamp(),
+            TimestampPrecision::Milliseconds => time.timestamp_millis(),
+            TimestampPrecision::Microseconds => time.timestamp_micros(),
+            TimestampPrecision::Nanoseconds => time.timestamp_nanos_opt().unwrap_or_default(),
+        }
+    }
+
+    fn drop_oldest(&self, points: &mut Vec<DataPoint>) {
+        let dropped = points.len().saturating_sub(self.max_pending_points);
+        if dropped > 0 {
+            warn!("InfluxDB Write buffer full, dropping the {} oldest points", dropped);
+            points.drain(..dropped);
+            self.metrics.influx_points_dropped_total.fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+    }
+
+    // 4. InfluxDB Write Batch
+    // Only fail once all retries are used up, so a short database restart
+    // does not push us towards max_errors. A rate limited write waits as long
+    // as InfluxDB asks, up to MAX_INFLUX_RATE_LIMIT_WAIT_SECS in total, and
+    // neither uses up a retry nor counts as an error. Points still rate
+    // limited after that go back to the pending ones for a later write.
+    async fn send(&self, mut influx_points: Vec<DataPoint>, publishes: usize) -> Result<(), String> {
+        let mut attempt = 0;
+        let mut waited = Duration::ZERO;
+        loop {
+            match self.write(&influx_points).await {
+                Err(WriteError::RateLimited(wait)) => {
+                    Metrics::inc(&self.metrics.influx_rate_limited_total);
+                    if waited + wait > Duration::from_secs(MAX_INFLUX_RATE_LIMIT_WAIT_SECS) {
+                        warn!(
+                            "InfluxDB Write still rate limited (HTTP 429), keeping {} points for a write in {}s",
+                            influx_points.len(),
+                            wait.as_secs()
+                        );
+                        let mut pending = self.pending.lock().unwrap();
+                        influx_points.append(&mut pending.points);
+                        pending.points = influx_points;
+                        self.drop_oldest(&mut pending.points);
+                        pending.publishes += publishes;
+                        pending.since.get_or_insert_with(Instant::now);
+                        pending.not_before = Some(tokio::time::Instant::now() + wait);
+                        return Ok(());
+                    }
+                    warn!(
+                        "InfluxDB Write rate limited (HTTP 429), retrying {} points in {}s as asked by Retry-After",
+                        influx_points.len(),
+                        wait.as_secs()
+                    );
+                    sleep(wait).await;
+                    waited += wait;
+                }
+                Ok(_) => {
+                    Metrics::inc(&self.metrics.influx_writes_total);
+                    if attempt > 0 {
+                        warn!("InfluxDB Write succeeded after {} retries", attempt);
+                    } else {
+                        debug!("InfluxDB Write Success");
+                    }
+                    return Ok(());
+                }
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("InfluxDB Write Error (retry {}/{}): {}", attempt, self.retries, e);
+                    sleep(Duration::from_millis(INFLUX_RETRY_DELAY_MS << (attempt - 1).min(8))).await;
+                }
+                Err(e) if publishes > 1 => {
+                    return Err(format!("InfluxDB Write Error: {} ({} buffered points lost)", e, influx_points.len()));
+                }
+                Err(e) => return Err(format!("InfluxDB Write Error: {}", e)),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MeasurementSink for InfluxSink {
+    async fn publish(&self, device: &Device, measurements: &[Measurement]) -> Result<(), String> {
+        // 2. InfluxDB Point Preparation
+        let timestamp = self.timestamp(device.timestamp().unwrap_or_else(Utc::now));
+        let mut influx_points: Vec<DataPoint> = Vec::new();
+
+        match self.schema {
+            InfluxSchema::Tall => {
+                for measurement in measurements {
+                    let Some((value, text)) = self.field_value(measurement) else {
+                        continue;
+                    };
+                    let mut builder = self
+                        .point(device, timestamp)
+                        .tag("type", topic_segment(&self.topic_map, &measurement.typ))
+                        .field(if text { STRING_VALUE_FIELD } else { "value" }, value);
+
+                    if let Some(unit) = &measurement.unit {
+                        builder = builder.tag("unit", unit.as_str());
+                    }
+
+                    if let Ok(point) = builder.build() {
+                        influx_points.push(point);
+                    }
+                }
+            }
+            // The units are left out, a tag would apply to every field of the point.
+            InfluxSchema::Wide => {
+                let mut builder = self.point(device, timestamp);
+                for measurement in measurements {
+                    let Some((value, text)) = self.field_value(measurement) else {
+                        continue;
+                    };
+                    let field = topic_segment(&self.topic_map, &measurement.typ);
+                    builder = match text {
+                        true => builder.field(format!("{}_{}", field, STRING_VALUE_FIELD), value),
+                        false => builder.field(field, value),
+                    };
+                }
+                // Fails without any field, a poll without values writes nothing.
+                if let Ok(point) = builder.build() {
+                    influx_points.push(point);
+                }
+            }
+        }
+
+        // 3. Batching
+        // Every point keeps the timestamp of its own poll, however late it is written.
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.points.append(&mut influx_points);
+            self.drop_oldest(&mut pending.points);
+            pending.publishes += 1;
+            let since = *pending.since.get_or_insert_with(Instant::now);
+            let due = (pending.publishes >= self.batch_size
+                || self.batch_interval.is_some_and(|interval| since.elapsed() >= interval))
+                && pending.not_before.is_none_or(|not_before| tokio::time::Instant::now() >= not_before);
+            if !due || pending.points.is_empty() {
+                return Ok(());
+            }
+            std::mem::take(&mut *pending)
+        };
+        self.send(batch.points, batch.publishes).await
+    }
+
+    // At shutdown a rate limit is not waited out beyond one send.
+    async fn flush(&self) -> Result<(), String> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.points.is_empty() {
+            return Ok(());
+        }
+        self.send(batch.points, batch.publishes).await?;
+        match std::mem::take(&mut *self.pending.lock().unwrap()).points.len() {
+            0 => Ok(()),
+            lost => Err(format!("InfluxDB Write Error: still rate limited ({} buffered points lost)", lost)),
+        }
+    }
+
+    // /health (2.x) and /ping (1.x) need no credentials, so a wrong token
+    // still only shows on the first write.
+    async fn check(&self, timeout: Duration) -> Result<(), String> {
+        let check = async {
+            match &self.writer {
+                InfluxWriter::V2 { client, .. } => {
+                    let health = client.health().await.map_err(|e| format!("{:?}", e))?;
+                    match health.status {
+                        Status::Pass => Ok(()),
+                        Status::Fail => Err(health.message.unwrap_or_else(|| "status fail".to_string())),
+                    }
+                }
+                InfluxWriter::V1 { http_client, url, .. } => {
+                    let resp = http_client.get(format!("{}/ping", url)).send().await.map_err(|e| format!("{:?}", e))?;
+                    match resp.status() {
+                        status if status.is_success() => Ok(()),
+                        status => Err(format!("HTTP {}", status)),
+                    }
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, check).await {
+            Ok(result) => result.map_err(|e| format!("InfluxDB health check failed: {}", e)),
+            Err(_) => Err(format!("InfluxDB did not answer the health check within {}s.", timeout.as_secs())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Root;
+    use serde_xml_rs::from_str;
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn v1_sink(server: &MockServer, toml: &str) -> InfluxSink {
+        let influx_conf: InfluxDbV1Config =
+            toml::from_str(&format!("url = \"{}\"\ndatabase = \"solar\"\n{}", server.uri(), toml)).unwrap();
+        InfluxSink::new_v1(&influx_conf, &BTreeMap::new(), Arc::new(Metrics::default())).unwrap()
+    }
+
+    fn device(date_time: &str) -> Device {
+        let xml = include_str!("../../tests/fixtures/measurements.xml").replace("2026-10-14T12:00:00", date_time);
+        let root: Root = from_str(&xml).unwrap();
+        root.into_devices().remove(0)
+    }
+
+    fn timestamps(body: &[u8]) -> Vec<String> {
+        let lines = String::from_utf8_lossy(body).lines().map(str::to_string).collect::<Vec<_>>();
+        let mut timestamps: Vec<String> = lines.iter().map(|l| l.rsplit(' ').next().unwrap().to_string()).collect();
+        timestamps.dedup();
+        timestamps
+    }
+
+    #[tokio::test]
+    async fn batches_points_of_several_publishes_with_their_own_timestamps() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST")).and(path("/write")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+        let sink = v1_sink(&server, "precision = \"s\"\nbatch_size = 2");
+
+        let first = device("2026-10-14T12:00:00");
+        sink.publish(&first, &first.measurements.measurement).await.unwrap();
+        assert!(server.received_requests().await.unwrap().is_empty());
+        let second = device("2026-10-14T12:00:05");
+        sink.publish(&second, &second.measurements.measurement).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(timestamps(&requests[0].body), ["1791979200", "1791979205"]);
+    }
+
+    #[tokio::test]
+    async fn flushes_a_partial_batch_on_shutdown() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(204)).expect(1).mount(&server).await;
+        let sink = v1_sink(&server, "batch_size = 10\nbatch_interval_secs = 60");
+        let device = device("2026-10-14T12:00:00");
+
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+        sink.flush().await.unwrap();
+        sink.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_text_values_of_the_string_values_types() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+        let root: Root = from_str(include_str!("../../tests/fixtures/status.xml")).unwrap();
+        let device = root.into_devices().remove(0);
+
+        for toml in ["string_values = [\"Inverter_State\"]", "string_values = true", ""] {
+            let sink = v1_sink(&server, &format!("precision = \"s\"\n{}", toml));
+            sink.publish(&device, &device.measurements.measurement).await.unwrap();
+        }
+
+        let requests = server.received_requests().await.unwrap();
+        let lines = |index: usize| String::from_utf8_lossy(&requests[index].body).lines().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            lines(0),
+            [
+                "inverter_data,serial=7799ABCDEXXXXXX000,type=AC_Power,unit=W value=382.6 1791979200",
+                "inverter_data,serial=7799ABCDEXXXXXX000,type=Inverter_State text=\"Running\" 1791979200",
+            ]
+        );
+        assert_eq!(
+            lines(1)[2],
+            "inverter_data,serial=7799ABCDEXXXXXX000,type=Operating_Mode text=\"MPP \\\"tracking\\\"\" 1791979200"
+        );
+        // Off by default, the NaN DC_Power is never written.
+        assert_eq!(lines(2).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn writes_one_point_per_measurement_or_one_per_poll_with_the_wide_schema() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+        let root: Root = from_str(include_str!("../../tests/fixtures/status.xml")).unwrap();
+        let device = root.into_devices().remove(0);
+        let topic_map = BTreeMap::from([("AC_Power".to_string(), "ac_power".to_string())]);
+        let influx_conf: InfluxDbV1Config = toml::from_str(&format!(
+            "url = \"{}\"\ndatabase = \"solar\"\nprecision = \"s\"\nstring_values = true\ntopic_map = true\nschema = \"wide\"",
+            server.uri()
+        ))
+        .unwrap();
+
+        let tall = v1_sink(&server, "precision = \"s\"\nschema = \"tall\"");
+        tall.publish(&device, &device.measurements.measurement).await.unwrap();
+        let wide = InfluxSink::new_v1(&influx_conf, &topic_map, Arc::new(Metrics::default())).unwrap();
+        wide.publish(&device, &device.measurements.measurement).await.unwrap();
+        // Nothing to write, not even an empty point.
+        wide.publish(&device, &[]).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            String::from_utf8_lossy(&requests[0].body),
+            "inverter_data,serial=7799ABCDEXXXXXX000,type=AC_Power,unit=W value=382.6 1791979200\n"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&requests[1].body),
+            "inverter_data,serial=7799ABCDEXXXXXX000 \
+             Inverter_State_text=\"Running\",Operating_Mode_text=\"MPP \\\"tracking\\\"\",ac_power=382.6 1791979200\n"
+        );
+        assert!(parse_influx_schema(Some("long")).is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_the_v1_ping_endpoint() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET")).and(path("/ping")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+        let sink = v1_sink(&server, "");
+
+        assert!(sink.check(Duration::from_secs(5)).await.is_ok());
+        server.reset().await;
+        Mock::given(method("GET")).respond_with(ResponseTemplate::new(503)).mount(&server).await;
+        assert_eq!(
+            sink.check(Duration::from_secs(5)).await.unwrap_err(),
+            "InfluxDB health check failed: HTTP 503 Service Unavailable"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_retry_after_when_rate_limited_without_using_up_retries() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2/write"))
+            .and(query_param("org", "home"))
+            .and(query_param("bucket", "solar"))
+            .and(query_param("precision", "ns"))
+            .and(header("Authorization", "Token secret"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let influx_conf: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"{}/\"\ntoken = \"secret\"\norg = \"home\"\nbucket = \"solar\"\nretries = 0",
+            server.uri()
+        ))
+        .unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let sink = InfluxSink::new(&influx_conf, &BTreeMap::new(), metrics.clone()).unwrap();
+        let device = device("2026-10-14T12:00:00");
+
+        let start = tokio::time::Instant::now();
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+        assert_eq!(metrics.influx_rate_limited_total.load(std::sync::atomic::Ordering::Relaxed), 2);
+        assert_eq!(metrics.influx_writes_total.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_the_points_when_rate_limited_for_too_long() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "40"))
+            .mount(&server)
+            .await;
+        let influx_conf: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"{}\"\ntoken = \"secret\"\norg = \"home\"\nbucket = \"solar\"",
+            server.uri()
+        ))
+        .unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let sink = InfluxSink::new(&influx_conf, &BTreeMap::new(), metrics.clone()).unwrap();
+        let device = device("2026-10-14T12:00:00");
+
+        // Waits 40s once, the second 429 would go beyond the limit.
+        let start = tokio::time::Instant::now();
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(40));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+        let kept = sink.pending.lock().unwrap().points.len();
+        assert!(kept > 0);
+
+        // Within the last Retry-After the next poll only adds its points.
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+        assert_eq!(sink.pending.lock().unwrap().points.len(), 2 * kept);
+
+        let error = sink.flush().await.unwrap_err();
+        assert!(error.contains(&format!("({} buffered points lost)", 2 * kept)), "{}", error);
+        assert_eq!(metrics.influx_writes_total.load(std::sync::atomic::Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_the_oldest_points_when_the_buffer_is_full() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "40"))
+            .mount(&server)
+            .await;
+        let influx_conf: InfluxDbConfig = toml::from_str(&format!(
+            "url = \"{}\"\ntoken = \"secret\"\norg = \"home\"\nbucket = \"solar\"\nprecision = \"s\"",
+            server.uri()
+        ))
+        .unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let mut sink = InfluxSink::new(&influx_conf, &BTreeMap::new(), metrics.clone()).unwrap();
+        let first = device("2026-10-14T12:00:00");
+        let second = device("2026-10-14T12:00:05");
+
+        sink.publish(&first, &first.measurements.measurement).await.unwrap();
+        let kept = sink.pending.lock().unwrap().points.len();
+        sink.max_pending_points = kept;
+        sink.publish(&second, &second.measurements.measurement).await.unwrap();
+
+        assert_eq!(sink.pending.lock().unwrap().points.len(), kept);
+        assert_eq!(metrics.influx_points_dropped_total.load(std::sync::atomic::Ordering::Relaxed), kept as u64);
+
+        // Only the points of the second poll are left to write.
+        server.reset().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+        sink.flush().await.unwrap();
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(timestamps(&requests[0].body), ["1791979205"]);
+    }
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        let now = DateTime::parse_from_rfc3339("2026-10-14T12:00:00Z").unwrap().with_timezone(&Utc);
+
+        assert_eq!(parse_retry_after(" 42 ", now), Some(Duration::from_secs(42)));
+        assert_eq!(parse_retry_after("Wed, 14 Oct 2026 12:01:30 GMT", now), Some(Duration::from_secs(90)));
+        assert_eq!(parse_retry_after("Wed, 14 Oct 2026 11:59:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn coerces_floats() {
+        assert_eq!(coerce_field("382.6", FieldType::Float), Some(FieldValue::F64(382.6)));
+        assert_eq!(coerce_field("on", FieldType::Float), None);
+    }
+
+    #[test]
+    fn coerces_integers_including_whole_floats() {
+        assert_eq!(coerce_field("12", FieldType::Integer), Some(FieldValue::I64(12)));
+        assert_eq!(coerce_field("-3", FieldType::Integer), Some(FieldValue::I64(-3)));
+        assert_eq!(coerce_field("12.0", FieldType::Integer), Some(FieldValue::I64(12)));
+        assert_eq!(coerce_field("12.5", FieldType::Integer), None);
+        assert_eq!(coerce_field("1e30", FieldType::Integer), None);
+        assert_eq!(coerce_field("abc", FieldType::Integer), None);
+    }
+
+    #[test]
+    fn coerces_booleans() {
+        for value in ["true", "TRUE", "on", "1"] {
+            assert_eq!(coerce_field(value, FieldType::Boolean), Some(FieldValue::Bool(true)), "{}", value);
+        }
+        for value in ["false", "Off", "0"] {
+            assert_eq!(coerce_field(value, FieldType::Boolean), Some(FieldValue::Bool(false)), "{}", value);
+        }
+        assert_eq!(coerce_field("2", FieldType::Boolean), None);
+    }
+
+    #[test]
+    fn keeps_strings_as_they_are() {
+        assert_eq!(coerce_field("Feed-in", FieldType::String), Some(FieldValue::String("Feed-in".to_string())));
+        assert_eq!(coerce_field("12", FieldType::String), Some(FieldValue::String("12".to_string())));
+    }
+}
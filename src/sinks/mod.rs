@@ -0,0 +1,91 @@
+// --- Measurement Sinks ---
+//
+// Every successful poll hands the (filtered) measurements of the device to all
+// configured sinks, which are awaited concurrently via join_all. Each sink still
+// gets the devices in order. New outputs only need to implement MeasurementSink
+// and be added to the list built in main().
+
+mod influx;
+mod mqtt;
+mod prometheus;
+mod sqlite;
+mod stdout;
+mod webhook;
+
+pub(crate) use influx::InfluxSink;
+pub(crate) use mqtt::MqttSink;
+pub(crate) use prometheus::{valid_metric_name, PrometheusSink, DEFAULT_PROMETHEUS_PREFIX};
+pub(crate) use sqlite::SqliteSink;
+pub(crate) use stdout::StdoutSink;
+pub(crate) use webhook::WebhookSink;
+
+use crate::{clean_value, json_value, Device, Measurement};
+use async_trait::async_trait;
+use chrono::SecondsFormat;
+use serde::Serialize;
+use std::time::Duration;
+
+#[async_trait]
+pub(crate) trait MeasurementSink: Send + Sync {
+    // An Err counts as a failed poll towards max_errors, so only return one
+    // when the data of this poll is definitely lost.
+    async fn publish(&self, device: &Device, measurements: &[Measurement]) -> Result<(), String>;
+
+    // Called once on shutdown by sinks that buffer data between polls.
+    async fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Called once at startup, Err if the backend cannot be reached within
+    // `timeout`. Outputs without a way to tell keep the default.
+    async fn check(&self, _timeout: Duration) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// --- JSON Record ---
+
+// One device and its measurements with a value, shared by the stdout and
+// webhook sinks so both emit the same document.
+#[derive(Debug, Serialize)]
+struct JsonRecord<'a> {
+    serial: &'a str,
+    name: &'a str,
+    timestamp: Option<String>,
+    measurements: Vec<JsonMeasurement<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonMeasurement<'a> {
+    #[serde(rename = "type")]
+    typ: &'a str,
+    value: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+}
+
+impl<'a> JsonRecord<'a> {
+    fn new(device: &'a Device, measurements: &'a [Measurement]) -> Self {
+        JsonRecord {
+            serial: &device.serial,
+            name: &device.name,
+            timestamp: device.timestamp().map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, false)),
+            measurements: measurements
+                .iter()
+                .filter_map(|m| {
+                    clean_value(m.value.as_deref()).map(|value| JsonMeasurement {
+                        typ: &m.typ,
+                        value: json_value(value),
+                        unit: m.unit.as_deref().filter(|u| !u.is_empty()),
+                        name: m.name.as_deref().filter(|n| !n.trim().is_empty()),
+                        description: m.description.as_deref().filter(|d| !d.trim().is_empty()),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
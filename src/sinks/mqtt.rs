@@ -0,0 +1,862 @@
+use super::MeasurementSink;
+use crate::{
+    clean_value, json_value, parse_channel_full, parse_payload_encoding, parse_payload_format, parse_qos, parse_value,
+    ChannelFull, Config, DeadbandRule, Device, Measurement, Metrics, MqttConfig, PayloadEncoding, PayloadFormat, TopicTemplate,
+    topic_segment, DEFAULT_TOPIC_TEMPLATE, DISCOVERY_TOPIC_PREFIX,
+};
+use crate::mqtt_client::MqttClient;
+use crate::units::Unit;
+use async_trait::async_trait;
+use rumqttc::QoS;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tracing::{debug, error, warn};
+
+const DEFAULT_FORCE_PUBLISH_INTERVAL_SECS: u64 = 600;
+
+// --- MQTT JSON State Payload ---
+
+#[derive(Debug, Serialize)]
+struct StatePayload<'a> {
+    serial: &'a str,
+    name: &'a str,
+    measurements: BTreeMap<&'a str, serde_json::Value>,
+    units: BTreeMap<&'a str, &'a str>,
+    // @Name or @Description of the measurement, @Type when it has neither.
+    names: BTreeMap<&'a str, &'a str>,
+}
+
+// --- Home Assistant Discovery ---
+
+#[derive(Debug, Serialize)]
+struct DiscoveryDevice<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    manufacturer: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct DiscoveryConfig<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    state_topic: String,
+    value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability_topic: Option<&'a str>,
+    device: DiscoveryDevice<'a>,
+}
+
+fn device_class_for_unit(unit: &str) -> Option<&'static str> {
+    match Unit::parse(unit)? {
+        Unit::Watt | Unit::Kilowatt => Some("power"),
+        Unit::Volt => Some("voltage"),
+        Unit::Ampere => Some("current"),
+        Unit::WattHour | Unit::KilowattHour | Unit::MegawattHour => Some("energy"),
+        Unit::VoltAmpere | Unit::KilovoltAmpere => Some("apparent_power"),
+        Unit::Var | Unit::Kilovar => Some("reactive_power"),
+        Unit::Hertz => Some("frequency"),
+        Unit::Celsius => Some("temperature"),
+        Unit::Percent | Unit::Ohm | Unit::Hour => None,
+    }
+}
+
+fn state_class_for_unit(unit: &str) -> Option<&'static str> {
+    match device_class_for_unit(unit) {
+        Some("energy") => Some("total_increasing"),
+        Some(_) => Some("measurement"),
+        None => None,
+    }
+}
+
+// --- Value Precision ---
+
+// Rounds numeric values with more than `precision` decimal places, e.g.
+// "229.9998" becomes "230.00" with 2. Values that already fit, like "230.5"
+// or "12", and anything that is not a number are published as they are.
+fn round_value(value: &str, precision: Option<usize>) -> Cow<'_, str> {
+    let (Some(precision), Ok(number)) = (precision, value.parse::<f64>()) else {
+        return Cow::Borrowed(value);
+    };
+    let rounded = format!("{:.precision$}", number);
+    if rounded.parse::<f64>() == Ok(number) { Cow::Borrowed(value) } else { Cow::Owned(rounded) }
+}
+
+// --- State Labels ---
+
+// The [state_map] label of a status code, looked up by the code as sent and
+// then by its number, so "3", "3.0" and "03" all find 3 = "Running".
+fn state_label<'a>(state_map: &'a BTreeMap<String, BTreeMap<String, String>>, typ: &str, value: &str) -> Option<&'a str> {
+    let labels = state_map.get(typ)?;
+    labels
+        .get(value)
+        .or_else(|| {
+            let number = parse_value(value)?;
+            labels.iter().find(|(code, _)| parse_value(code) == Some(number)).map(|(_, label)| label)
+        })
+        .map(String::as_str)
+}
+
+// --- Change Detection ---
+
+// Remembers the last payload per topic for publish_on_change_only.
+struct ChangeFilter {
+    force_interval: Duration,
+    last: Mutex<HashMap<String, (Vec<u8>, Instant)>>,
+}
+
+impl ChangeFilter {
+    fn new(force_interval: Duration) -> Self {
+        ChangeFilter {
+            force_interval,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns false for an unchanged payload that was published less than
+    // force_interval ago, otherwise records it as published.
+    fn should_publish(&self, topic: &str, payload: &[u8], now: Instant) -> bool {
+        let mut last = self.last.lock().unwrap();
+        if let Some((previous, published_at)) = last.get(topic)
+            && previous.as_slice() == payload
+            && now.duration_since(*published_at) < self.force_interval {
+            return false;
+        }
+        last.insert(topic.to_string(), (payload.to_vec(), now));
+        true
+    }
+
+    // A failed publish must not suppress the next attempt with the same value.
+    fn forget(&self, topic: &str) {
+        self.last.lock().unwrap().remove(topic);
+    }
+}
+
+// --- Throttling ---
+
+// Minimum time between two publishes of one topic, from [throttle]. Types
+// that are not listed are never held back.
+struct Throttle {
+    intervals: BTreeMap<String, Duration>,
+    last: Mutex<HashMap<String, Instant>>,
+}
+
+impl Throttle {
+    fn new(throttle: &BTreeMap<String, f64>) -> Self {
+        Throttle {
+            intervals: throttle.iter().map(|(typ, secs)| (typ.clone(), Duration::from_secs_f64(*secs))).collect(),
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn allows(&self, typ: &str, topic: &str, now: Instant) -> bool {
+        let Some(interval) = self.intervals.get(typ) else {
+            return true;
+        };
+        self.last.lock().unwrap().get(topic).is_none_or(|published_at| now.duration_since(*published_at) >= *interval)
+    }
+
+    // Only actual publishes count, a skipped or failed one does not delay the next.
+    fn record(&self, typ: &str, topic: &str, now: Instant) {
+        if self.intervals.contains_key(typ) {
+            self.last.lock().unwrap().insert(topic.to_string(), now);
+        }
+    }
+}
+
+// --- Deadband ---
+
+#[derive(Debug, Clone, Copy)]
+enum Threshold {
+    Absolute(f64),
+    Percent(f64),
+}
+
+// Holds back values of the [deadband] types that moved no further than their
+// threshold from the last published one. Like publish_on_change_only, a held
+// back topic is still published every force_interval so it never goes stale.
+struct Deadband {
+    thresholds: BTreeMap<String, Threshold>,
+    force_interval: Duration,
+    // topic -> last published value and when.
+    last: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl Deadband {
+    fn new(rules: &BTreeMap<String, DeadbandRule>, force_interval: Duration) -> Self {
+        Deadband {
+            thresholds: rules
+                .iter()
+                .filter_map(|(typ, rule)| {
+                    let threshold = match (rule.absolute, rule.percent) {
+                        (Some(absolute), _) => Threshold::Absolute(absolute),
+                        (None, Some(percent)) => Threshold::Percent(percent),
+                        (None, None) => return None,
+                    };
+                    Some((typ.clone(), threshold))
+                })
+                .collect(),
+            force_interval,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Values that are not numbers always pass.
+    fn allows(&self, typ: &str, topic: &str, value: Option<f64>, now: Instant) -> bool {
+        let (Some(threshold), Some(value)) = (self.thresholds.get(typ), value) else {
+            return true;
+        };
+        let last = self.last.lock().unwrap();
+        let Some(&(previous, published_at)) = last.get(topic) else {
+            return true;
+        };
+        let threshold = match *threshold {
+            Threshold::Absolute(absolute) => absolute,
+            Threshold::Percent(percent) => previous.abs() * percent / 100.0,
+        };
+        (value - previous).abs() > threshold || now.duration_since(published_at) >= self.force_interval
+    }
+
+    // Only actual publishes count, the band stays around the value subscribers have.
+    fn record(&self, typ: &str, topic: &str, value: Option<f64>, now: Instant) {
+        if let (true, Some(value)) = (self.thresholds.contains_key(typ), value) {
+            self.last.lock().unwrap().insert(topic.to_string(), (value, now));
+        }
+    }
+}
+
+// --- Payload Encoding ---
+
+#[derive(Debug, Serialize)]
+struct ValuePayload<'a> {
+    value: serde_json::Value,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    unit: &'a str,
+}
+
+// The payload of one measurement in individual mode, None for a value that
+// float32_le cannot carry. json and msgpack send text values as strings.
+fn encode_payload(encoding: PayloadEncoding, value: Option<&str>, unit: &str, numeric_payloads: bool) -> Option<Vec<u8>> {
+    match encoding {
+        PayloadEncoding::String => {
+            let payload = match value {
+                Some(value) if numeric_payloads => value.to_string(),
+                Some(value) => format!("{} {}", value, unit).trim().to_string(),
+                None => "null".to_string(),
+            };
+            Some(payload.into_bytes())
+        }
+        PayloadEncoding::Json => {
+            let value = value.map(json_value).unwrap_or(serde_json::Value::Null);
+            serde_json::to_vec(&ValuePayload { value, unit }).ok()
+        }
+        PayloadEncoding::MessagePack => Some(msgpack_payload(value, unit)),
+        PayloadEncoding::Float32Le => value.and_then(parse_value).map(|number| (number as f32).to_le_bytes().to_vec()),
+    }
+}
+
+// The same map as json, written by hand for the few MessagePack types it
+// needs: numbers as float 64, text as str and a missing value as nil.
+fn msgpack_payload(value: Option<&str>, unit: &str) -> Vec<u8> {
+    let mut out = vec![if unit.is_empty() { 0x81 } else { 0x82 }];
+    msgpack_str(&mut out, "value");
+    match (value, value.and_then(parse_value)) {
+        (_, Some(number)) => {
+            out.push(0xcb);
+            out.extend(number.to_be_bytes());
+        }
+        (Some(value), None) => msgpack_str(&mut out, value),
+        (None, None) => out.push(0xc0),
+    }
+    if !unit.is_empty() {
+        msgpack_str(&mut out, "unit");
+        msgpack_str(&mut out, unit);
+    }
+    out
+}
+
+fn msgpack_str(out: &mut Vec<u8>, value: &str) {
+    let len = value.len();
+    match len {
+        0..32 => out.push(0xa0 | len as u8),
+        32..256 => out.extend([0xd9, len as u8]),
+        256..65536 => {
+            out.push(0xda);
+            out.extend((len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0xdb);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+    out.extend(value.as_bytes());
+}
+
+// --- MQTT Sink ---
+
+pub(crate) struct MqttSink {
+    client: MqttClient,
+    payload_format: PayloadFormat,
+    payload_encoding: PayloadEncoding,
+    qos: QoS,
+    retain: bool,
+    // Per type overrides of retain, from [retain].
+    retain_types: BTreeMap<String, bool>,
+    // Labels of status codes per type, from [state_map].
+    state_map: BTreeMap<String, BTreeMap<String, String>>,
+    channel_full: ChannelFull,
+    topic_template: TopicTemplate,
+    topic_map: BTreeMap<String, String>,
+    ha_discovery: bool,
+    // homeassistant/sensor below base_topic.
+    discovery_prefix: String,
+    skip_empty: bool,
+    numeric_payloads: bool,
+    publish_metadata: bool,
+    value_precision: Option<usize>,
+    availability_topic: Option<String>,
+    // Discovery configs are only sent once per sensor and run.
+    announced_sensors: Mutex<HashSet<String>>,
+    // Last payload published per /unit, /name and /type topic.
+    published_metadata: Mutex<HashMap<String, String>>,
+    // Set when publish_on_change_only is enabled.
+    change_filter: Option<ChangeFilter>,
+    throttle: Throttle,
+    deadband: Deadband,
+    metrics: Arc<Metrics>,
+}
+
+impl MqttSink {
+    pub(crate) fn new(
+        client: MqttClient,
+        mqtt_conf: &MqttConfig,
+        // [topic_map], [throttle], [retain], [deadband] and [state_map].
+        config: &Config,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let force_interval = Duration::from_secs(
+            mqtt_conf.force_publish_interval_secs.unwrap_or(DEFAULT_FORCE_PUBLISH_INTERVAL_SECS),
+        );
+        Ok(MqttSink {
+            client,
+            payload_format: parse_payload_format(mqtt_conf.payload_format.as_deref())?,
+            payload_encoding: parse_payload_encoding(mqtt_conf.payload_encoding.as_deref())?,
+            qos: parse_qos(mqtt_conf.qos)?,
+            retain: mqtt_conf.retain.unwrap_or(false),
+            retain_types: config.retain.clone(),
+            state_map: config.state_map.clone(),
+            channel_full: parse_channel_full(mqtt_conf.channel_full.as_deref())?,
+            topic_template: TopicTemplate::parse(
+                &mqtt_conf.topic(mqtt_conf.topic_template.as_deref().unwrap_or(DEFAULT_TOPIC_TEMPLATE)),
+            )?,
+            topic_map: config.topic_map.clone(),
+            ha_discovery: mqtt_conf.homeassistant_discovery.unwrap_or(false),
+            discovery_prefix: mqtt_conf.topic(DISCOVERY_TOPIC_PREFIX),
+            skip_empty: mqtt_conf.skip_empty.unwrap_or(true),
+            numeric_payloads: mqtt_conf.numeric_payloads.unwrap_or(false),
+            publish_metadata: mqtt_conf.publish_metadata.unwrap_or(false),
+            value_precision: mqtt_conf.value_precision,
+            availability_topic: mqtt_conf.availability_topic.as_deref().map(|topic| mqtt_conf.topic(topic)),
+            announced_sensors: Mutex::new(HashSet::new()),
+            published_metadata: Mutex::new(HashMap::new()),
+            change_filter: mqtt_conf.publish_on_change_only.unwrap_or(false).then(|| ChangeFilter::new(force_interval)),
+            throttle: Throttle::new(&config.throttle),
+            deadband: Deadband::new(&config.deadband, force_interval),
+            metrics,
+        })
+    }
+
+    // float32_le has no room for a label and keeps the code.
+    fn label<'a>(&'a self, measurement: &Measurement) -> Option<&'a str> {
+        let value = clean_value(measurement.value.as_deref())?;
+        if self.payload_encoding == PayloadEncoding::Float32Le {
+            return None;
+        }
+        state_label(&self.state_map, &measurement.typ, value)
+    }
+
+    // The value as published: its [state_map] label, else rounded to value_precision.
+    fn value<'a>(&'a self, measurement: &'a Measurement) -> Option<Cow<'a, str>> {
+        match self.label(measurement) {
+            Some(label) => Some(Cow::Borrowed(label)),
+            None => clean_value(measurement.value.as_deref()).map(|value| round_value(value, self.value_precision)),
+        }
+    }
+
+    // [retain] wins over the retain setting of [mqtt].
+    fn retain_for(&self, typ: &str) -> bool {
+        self.retain_types.get(typ).copied().unwrap_or(self.retain)
+    }
+
+    // Publishes a measurement payload, honouring publish_on_change_only.
+    async fn publish_payload(&self, topic: &str, retain: bool, payload: Vec<u8>) -> bool {
+        if let Some(filter) = &self.change_filter
+            && !filter.should_publish(topic, &payload, Instant::now()) {
+            debug!("MQTT Unchanged, skipped: {}", topic);
+            return false;
+        }
+
+        let result = match self.channel_full {
+            ChannelFull::Wait => self.client.publish(topic, self.qos, retain, payload).await,
+            ChannelFull::Drop => self.client.try_publish(topic, self.qos, retain, payload),
+        };
+        if let Err(e) = result {
+            if let Some(filter) = &self.change_filter {
+                filter.forget(topic);
+            }
+            if e.is_queue_full() {
+                Metrics::inc(&self.metrics.mqtt_publishes_dropped_total);
+                warn!("MQTT Publish dropped, request queue full: {}", topic);
+            } else {
+                error!("MQTT Publish Error: {:?}", e);
+            }
+            return false;
+        }
+        Metrics::inc(&self.metrics.mqtt_publishes_total);
+        true
+    }
+
+    // The discovery config of one measurement.
+    fn discovery<'a>(&'a self, device: &'a Device, measurement: &'a Measurement, unique_id: &'a str) -> DiscoveryConfig<'a> {
+        let unit = measurement.unit.as_deref().filter(|u| !u.is_empty());
+        // Labels of [state_map] are text, Home Assistant marks a sensor with
+        // a numeric device_class or state_class unavailable on those.
+        let numeric_unit = unit.filter(|_| !self.state_map.contains_key(&measurement.typ));
+        let (state_topic, value_template) = match self.payload_format {
+            PayloadFormat::Individual => (
+                self.topic_template.render(
+                    &device.serial,
+                    topic_segment(&self.topic_map, &measurement.typ),
+                    &device.name,
+                    unit.unwrap_or(""),
+                ),
+                match self.payload_encoding {
+                    PayloadEncoding::Json => "{{ value_json.value }}",
+                    // A label like "Grid fault" would lose its second word.
+                    _ if self.state_map.contains_key(&measurement.typ) => "{{ value }}",
+                    _ => "{{ value.split(' ')[0] }}",
+                }
+                .to_string(),
+            ),
+            PayloadFormat::Json => (
+                self.topic_template.render(&device.serial, "state", &device.name, ""),
+                format!("{{{{ value_json.measurements['{}'] }}}}", measurement.typ),
+            ),
+        };
+
+        DiscoveryConfig {
+            name: measurement.display_name(),
+            unique_id,
+            state_topic,
+            value_template,
+            unit_of_measurement: numeric_unit,
+            device_class: numeric_unit.and_then(device_class_for_unit),
+            state_class: numeric_unit.and_then(state_class_for_unit),
+            availability_topic: self.availability_topic.as_deref(),
+            device: DiscoveryDevice {
+                identifiers: [&device.serial],
+                name: &device.name,
+                manufacturer: "Kontron",
+            },
+        }
+    }
+
+    // 0. Home Assistant Discovery
+    async fn publish_discovery(&self, device: &Device, measurements: &[Measurement]) {
+        for measurement in measurements {
+            let unique_id = format!("{}_{}", device.serial, measurement.typ);
+            if (self.skip_empty && clean_value(measurement.value.as_deref()).is_none())
+                || self.announced_sensors.lock().unwrap().contains(&unique_id) {
+                continue;
+            }
+
+            let discovery = self.discovery(device, measurement, &unique_id);
+            let topic = format!("{}/{}/config", self.discovery_prefix, unique_id);
+            match serde_json::to_vec(&discovery) {
+                Ok(payload) => {
+                    if let Err(e) = self
+                        .client
+                        .publish(&topic, QoS::AtLeastOnce, true, payload)
+                        .await {
+                        error!("MQTT Discovery Publish Error: {:?}", e);
+                    } else {
+                        debug!("MQTT Discovery Published: {}", topic);
+                        self.announced_sensors.lock().unwrap().insert(unique_id);
+                    }
+                }
+                Err(e) => error!("MQTT JSON Serialize Error: {:?}", e),
+            }
+        }
+    }
+
+    // 1. MQTT Publish
+    async fn publish_individual(&self, device: &Device, measurements: &[Measurement]) {
+        for measurement in measurements {
+            let value = self.value(measurement);
+            if value.is_none() && self.skip_empty {
+                continue;
+            }
+
+            let unit_str = measurement.unit.as_deref().unwrap_or("");
+            let typ = topic_segment(&self.topic_map, &measurement.typ);
+            let topic = self.topic_template.render(&device.serial, typ, &device.name, unit_str);
+            let now = Instant::now();
+            if !self.throttle.allows(&measurement.typ, &topic, now) {
+                debug!("MQTT Throttled, skipped: {}", topic);
+                continue;
+            }
+            let number = value.as_deref().and_then(parse_value);
+            if !self.deadband.allows(&measurement.typ, &topic, number, now) {
+                debug!("MQTT Within deadband, skipped: {}", topic);
+                continue;
+            }
+            // "Running", not "Running %".
+            let payload_unit = if self.label(measurement).is_some() { "" } else { unit_str };
+            let encoded = encode_payload(self.payload_encoding, value.as_deref(), payload_unit, self.numeric_payloads);
+            let Some(payload) = encoded else {
+                debug!("MQTT Not a number for float32_le, skipped: {}", topic);
+                continue;
+            };
+            // Binary payloads are logged by their value.
+            let logged = match self.payload_encoding {
+                PayloadEncoding::String | PayloadEncoding::Json => String::from_utf8_lossy(&payload).into_owned(),
+                PayloadEncoding::MessagePack | PayloadEncoding::Float32Le => value.as_deref().unwrap_or("null").to_string(),
+            };
+
+            if self.publish_payload(&topic, self.retain_for(&measurement.typ), payload).await {
+                self.throttle.record(&measurement.typ, &topic, now);
+                self.deadband.record(&measurement.typ, &topic, number, now);
+                debug!("MQTT Published: {} = {}", topic, logged);
+            }
+            for (metadata_topic, payload) in self.metadata(&topic, measurement) {
+                self.publish_metadata(&metadata_topic, payload).await;
+            }
+        }
+    }
+
+    // The /unit topic of numeric_payloads, or all of them with publish_metadata.
+    // They follow the (topic_map) topic, /type keeps the raw Type.
+    fn metadata<'a>(&self, topic: &str, measurement: &'a Measurement) -> Vec<(String, &'a str)> {
+        let unit = measurement.unit.as_deref().unwrap_or("");
+        let mut metadata = Vec::new();
+        if (self.numeric_payloads || self.publish_metadata) && !unit.is_empty() {
+            metadata.push((format!("{}/unit", topic), unit));
+        }
+        if self.publish_metadata {
+            metadata.push((format!("{}/name", topic), measurement.display_name()));
+            metadata.push((format!("{}/type", topic), measurement.typ.as_str()));
+        }
+        metadata
+    }
+
+    // Metadata hardly ever changes, so it is only sent again when it does.
+    async fn publish_metadata(&self, topic: &str, payload: &str) {
+        if self.published_metadata.lock().unwrap().get(topic).is_some_and(|p| p == payload) {
+            return;
+        }
+        if let Err(e) = self.client.publish(topic, QoS::AtLeastOnce, true, payload).await {
+            error!("MQTT Metadata Publish Error: {:?}", e);
+            return;
+        }
+        debug!("MQTT Published: {} = {}", topic, payload);
+        self.published_metadata.lock().unwrap().insert(topic.to_string(), payload.to_string());
+    }
+
+    // 1b. MQTT JSON State
+    async fn publish_state(&self, device: &Device, measurements: &[Measurement]) {
+        let mut state = StatePayload {
+            serial: &device.serial,
+            name: &device.name,
+            measurements: BTreeMap::new(),
+            units: BTreeMap::new(),
+            names: BTreeMap::new(),
+        };
+
+        for measurement in measurements {
+            let value = self.value(measurement);
+            if value.is_none() && self.skip_empty {
+                continue;
+            }
+
+            state.measurements.insert(&measurement.typ, value.as_deref().map(json_value).unwrap_or(serde_json::Value::Null));
+            if let Some(unit) = &measurement.unit {
+                state.units.insert(&measurement.typ, unit);
+            }
+            state.names.insert(&measurement.typ, measurement.display_name());
+        }
+
+        let topic = self.topic_template.render(&device.serial, "state", &device.name, "");
+        match serde_json::to_vec(&state) {
+            Ok(payload) => {
+                if self.publish_payload(&topic, true, payload).await {
+                    debug!("MQTT Published: {} ({} measurements)", topic, state.measurements.len());
+                }
+            }
+            Err(e) => error!("MQTT JSON Serialize Error: {:?}", e),
+        }
+    }
+}
+
+#[async_trait]
+impl MeasurementSink for MqttSink {
+    async fn publish(&self, device: &Device, measurements: &[Measurement]) -> Result<(), String> {
+        if self.ha_discovery {
+            self.publish_discovery(device, measurements).await;
+        }
+
+        match self.payload_format {
+            PayloadFormat::Individual => self.publish_individual(device, measurements).await,
+            PayloadFormat::Json => self.publish_state(device, measurements).await,
+        }
+
+        // Publish errors are only logged, reconnecting is up to the eventloop.
+        Ok(())
+    }
+
+    // The eventloop connects in the background, this only waits for its ConnAck.
+    async fn check(&self, timeout: Duration) -> Result<(), String> {
+        let connected = async {
+            while self.metrics.mqtt_connected.load(Ordering::Relaxed) == 0 {
+                sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::time::timeout(timeout, connected)
+            .await
+            .map_err(|_| format!("MQTT broker not connected within {}s.", timeout.as_secs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_xml_rs::from_str;
+
+    // [mqtt] options and the sections after it, the broker is never connected to.
+    fn sink(options: &str, sections: &str) -> MqttSink {
+        let config: Config = toml::from_str(&format!(
+            "[mqtt]\nbroker = \"localhost\"\nport = 1883\nclient_id = \"solbrid\"\n{}\n{}",
+            options, sections
+        ))
+        .unwrap();
+        let mqtt_conf = config.mqtt.as_ref().unwrap();
+        let (client, _eventloop) = crate::mqtt_client::connect(mqtt_conf, 10).unwrap();
+        MqttSink::new(client, mqtt_conf, &config, Default::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn retain_per_type_overrides_the_mqtt_setting() {
+        let sink = sink("retain = false", "[retain]\nE_Total = true\nE_Day = true\nAC_Power = false");
+
+        assert!(sink.retain_for("E_Total") && sink.retain_for("E_Day"));
+        assert!(!sink.retain_for("AC_Power") && !sink.retain_for("AC_Voltage1"));
+    }
+
+    #[test]
+    fn publishes_metadata_next_to_the_mapped_topic() {
+        let measurement = |xml: &str| from_str::<Measurement>(xml).unwrap();
+        let sink = |options: &str| sink(options, "[topic_map]\nAC_U_L1 = \"grid_voltage\"");
+        let voltage = measurement("<Measurement Value='230.1' Unit='V' Type='AC_U_L1' Name='Grid voltage L1'/>");
+        let state = measurement("<Measurement Value='Running' Type='Inverter_State'/>");
+
+        let metadata = sink("publish_metadata = true");
+        assert_eq!(
+            metadata.metadata("inverter/1/grid_voltage", &voltage),
+            [
+                ("inverter/1/grid_voltage/unit".to_string(), "V"),
+                ("inverter/1/grid_voltage/name".to_string(), "Grid voltage L1"),
+                ("inverter/1/grid_voltage/type".to_string(), "AC_U_L1"),
+            ]
+        );
+        assert_eq!(
+            metadata.metadata("inverter/1/Inverter_State", &state),
+            [
+                ("inverter/1/Inverter_State/name".to_string(), "Inverter_State"),
+                ("inverter/1/Inverter_State/type".to_string(), "Inverter_State"),
+            ]
+        );
+        assert_eq!(
+            sink("numeric_payloads = true").metadata("inverter/1/grid_voltage", &voltage),
+            [("inverter/1/grid_voltage/unit".to_string(), "V")]
+        );
+        assert!(sink("").metadata("inverter/1/grid_voltage", &voltage).is_empty());
+    }
+
+    #[test]
+    fn publishes_state_labels_instead_of_mapped_codes() {
+        let state = |value: &str| from_str::<Measurement>(&format!("<Measurement Value='{}' Type='Inverter_State'/>", value)).unwrap();
+        let labels = "[state_map]\nInverter_State = { 0 = \"Off\", 3 = \"Running\", E12 = \"Grid fault\" }";
+        let labelled = sink("value_precision = 1", labels);
+        let value = |measurement: &Measurement| labelled.value(measurement).map(Cow::into_owned);
+
+        assert_eq!(value(&state("3")).as_deref(), Some("Running"));
+        assert_eq!(value(&state(" 3.0 ")).as_deref(), Some("Running"));
+        assert_eq!(value(&state("0")).as_deref(), Some("Off"));
+        assert_eq!(value(&state("E12")).as_deref(), Some("Grid fault"));
+        // Unmapped codes and other types pass through as usual.
+        assert_eq!(value(&state("7")).as_deref(), Some("7"));
+        assert_eq!(value(&state("")), None);
+        let power = from_str::<Measurement>("<Measurement Value='3.04' Unit='W' Type='AC_Power'/>").unwrap();
+        assert_eq!(value(&power).as_deref(), Some("3.0"));
+
+        let binary = sink("payload_encoding = \"float32_le\"", labels);
+        assert_eq!(binary.value(&state("3")).as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn announces_labelled_types_as_text_sensors() {
+        let root: crate::Root = from_str(
+            "<root><Device Name='SolBrid' Serial='7799'><Measurements>\
+             <Measurement Value='3' Unit='%' Type='Derating'/><Measurement Value='230.1' Unit='V' Type='AC_Voltage1'/>\
+             </Measurements></Device></root>",
+        )
+        .unwrap();
+        let device = root.into_devices().remove(0);
+        let [derating, voltage] = &device.measurements.measurement[..] else { panic!() };
+        let sink = sink("homeassistant_discovery = true", "[state_map]\nDerating = { 3 = \"Limited\" }");
+        let discovery = |measurement| serde_json::to_value(sink.discovery(&device, measurement, "id")).unwrap();
+
+        let labelled = discovery(derating);
+        assert_eq!(labelled["value_template"], "{{ value }}");
+        for key in ["unit_of_measurement", "device_class", "state_class"] {
+            assert!(labelled.get(key).is_none(), "{}", key);
+        }
+        let numeric = discovery(voltage);
+        assert_eq!(numeric["unit_of_measurement"], "V");
+        assert_eq!(numeric["device_class"], "voltage");
+        assert_eq!(numeric["state_class"], "measurement");
+    }
+
+    #[test]
+    fn encodes_payloads_for_binary_consumers() {
+        let encode = |encoding, value| encode_payload(encoding, value, "V", false).unwrap();
+
+        assert_eq!(encode(PayloadEncoding::String, Some("230.5")), b"230.5 V");
+        assert_eq!(encode(PayloadEncoding::Json, Some("230.5")), br#"{"value":230.5,"unit":"V"}"#);
+        assert_eq!(encode_payload(PayloadEncoding::Json, Some("Running"), "", false).unwrap(), br#"{"value":"Running"}"#);
+        // IEEE-754 single precision, least significant byte first.
+        assert_eq!(encode(PayloadEncoding::Float32Le, Some("230.5")), [0x00, 0x80, 0x66, 0x43]);
+        assert_eq!(encode(PayloadEncoding::Float32Le, Some("-1")), [0x00, 0x00, 0x80, 0xbf]);
+        assert_eq!(encode(PayloadEncoding::Float32Le, Some("0")), [0x00; 4]);
+        assert_eq!(encode_payload(PayloadEncoding::Float32Le, Some("Running"), "", false), None);
+        assert_eq!(encode_payload(PayloadEncoding::Float32Le, None, "V", false), None);
+
+        let mut msgpack = vec![0x82, 0xa5];
+        msgpack.extend(b"value");
+        msgpack.push(0xcb);
+        msgpack.extend(230.5f64.to_be_bytes());
+        msgpack.push(0xa4);
+        msgpack.extend(b"unit");
+        msgpack.extend([0xa1, b'V']);
+        assert_eq!(encode(PayloadEncoding::MessagePack, Some("230.5")), msgpack);
+        let mut text = vec![0x81, 0xa5];
+        text.extend(b"value");
+        text.extend([0xa3, b'R', b'u', b'n']);
+        assert_eq!(encode_payload(PayloadEncoding::MessagePack, Some("Run"), "", false).unwrap(), text);
+        assert!(encode(PayloadEncoding::MessagePack, None).ends_with(&[0xc0, 0xa4, b'u', b'n', b'i', b't', 0xa1, b'V']));
+        let mut long = Vec::new();
+        msgpack_str(&mut long, &"x".repeat(40));
+        assert_eq!(long[..2], [0xd9, 40]);
+    }
+
+    #[test]
+    fn change_filter_skips_unchanged_payloads_until_forced() {
+        let filter = ChangeFilter::new(Duration::from_secs(600));
+        let start = Instant::now();
+
+        assert!(filter.should_publish("inverter/1/AC_Power", b"382.6 W", start));
+        assert!(!filter.should_publish("inverter/1/AC_Power", b"382.6 W", start + Duration::from_secs(10)));
+        assert!(filter.should_publish("inverter/1/AC_Power", b"390.0 W", start + Duration::from_secs(20)));
+        assert!(filter.should_publish("inverter/1/AC_Voltage1", b"390.0 W", start + Duration::from_secs(20)));
+        assert!(filter.should_publish("inverter/1/AC_Power", b"390.0 W", start + Duration::from_secs(620)));
+    }
+
+    #[test]
+    fn throttle_holds_back_listed_types_only() {
+        let throttle = Throttle::new(&BTreeMap::from([("AC_Power".to_string(), 2.0)]));
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert!(throttle.allows("AC_Power", "inverter/1/AC_Power", at(0)));
+        throttle.record("AC_Power", "inverter/1/AC_Power", at(0));
+        assert!(!throttle.allows("AC_Power", "inverter/1/AC_Power", at(1000)));
+        assert!(throttle.allows("AC_Power", "inverter/2/AC_Power", at(1000)));
+        assert!(throttle.allows("AC_Power", "inverter/1/AC_Power", at(2000)));
+        throttle.record("AC_Voltage1", "inverter/1/AC_Voltage1", at(0));
+        assert!(throttle.allows("AC_Voltage1", "inverter/1/AC_Voltage1", at(1)));
+    }
+
+    fn deadband(rules: &str) -> Deadband {
+        Deadband::new(&toml::from_str(rules).unwrap(), Duration::from_secs(600))
+    }
+
+    #[test]
+    fn deadband_publishes_changes_beyond_the_threshold_only() {
+        let deadband = deadband("AC_Power = { absolute = 5 }");
+        let topic = "inverter/1/AC_Power";
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(deadband.allows("AC_Power", topic, Some(100.0), at(0)));
+        deadband.record("AC_Power", topic, Some(100.0), at(0));
+        // A change of exactly the threshold is still within the band.
+        assert!(!deadband.allows("AC_Power", topic, Some(105.0), at(1)));
+        assert!(!deadband.allows("AC_Power", topic, Some(95.0), at(1)));
+        assert!(deadband.allows("AC_Power", topic, Some(105.1), at(1)));
+        assert!(deadband.allows("AC_Power", topic, Some(94.9), at(1)));
+        // Unpublished values do not move the band.
+        assert!(!deadband.allows("AC_Power", topic, Some(104.0), at(2)));
+        assert!(deadband.allows("AC_Power", topic, None, at(2)));
+        assert!(deadband.allows("AC_Power", "inverter/2/AC_Power", Some(100.0), at(2)));
+        assert!(deadband.allows("AC_Voltage1", "inverter/1/AC_Voltage1", Some(100.0), at(2)));
+    }
+
+    #[test]
+    fn deadband_percent_is_relative_to_the_last_value_and_forced_after_the_interval() {
+        let deadband = deadband("AC_Power = { percent = 10 }");
+        let topic = "inverter/1/AC_Power";
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        deadband.record("AC_Power", topic, Some(-200.0), at(0));
+        assert!(!deadband.allows("AC_Power", topic, Some(-220.0), at(1)));
+        assert!(deadband.allows("AC_Power", topic, Some(-220.5), at(1)));
+        assert!(!deadband.allows("AC_Power", topic, Some(-200.0), at(599)));
+        assert!(deadband.allows("AC_Power", topic, Some(-200.0), at(600)));
+    }
+
+    #[test]
+    fn rounds_values_with_more_decimals_than_the_precision() {
+        assert_eq!(round_value("229.9998", Some(2)), "230.00");
+        assert_eq!(round_value("0.123456", Some(3)), "0.123");
+        assert_eq!(round_value("-1.25", Some(0)), "-1");
+        assert_eq!(round_value("229.9998", None), "229.9998");
+    }
+
+    #[test]
+    fn keeps_values_that_fit_and_non_numeric_ones() {
+        assert_eq!(round_value("230.5", Some(2)), "230.5");
+        assert_eq!(round_value("12", Some(2)), "12");
+        assert_eq!(round_value("Feed-in", Some(2)), "Feed-in");
+        assert!(matches!(round_value("0x1F", Some(1)), Cow::Borrowed("0x1F")));
+    }
+
+    #[test]
+    fn change_filter_retries_after_a_failed_publish() {
+        let filter = ChangeFilter::new(Duration::from_secs(600));
+        let now = Instant::now();
+
+        assert!(filter.should_publish("inverter/1/AC_Power", b"382.6 W", now));
+        filter.forget("inverter/1/AC_Power");
+        assert!(filter.should_publish("inverter/1/AC_Power", b"382.6 W", now));
+    }
+}
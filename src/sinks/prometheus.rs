@@ -0,0 +1,162 @@
+use super::MeasurementSink;
+use crate::{clean_value, parse_value, Device, Measurement, PrometheusMeasurementsConfig};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub(crate) const DEFAULT_PROMETHEUS_PREFIX: &str = "solbrid_";
+const DEFAULT_PROMETHEUS_MAX_AGE_SECS: u64 = 300;
+
+// --- Prometheus Measurements Sink ---
+
+// Keeps the last values of every device for the /metrics endpoint of
+// [prometheus_measurements], the bridge metrics of [prometheus] are separate.
+pub(crate) struct PrometheusSink {
+    gauges: Arc<Gauges>,
+}
+
+// (metric name, unit, value) of one measurement.
+type Gauge = (String, String, f64);
+
+pub(crate) struct Gauges {
+    prefix: String,
+    max_age: Duration,
+    // serial -> when it was last published, and its gauges.
+    devices: Mutex<BTreeMap<String, (Instant, Vec<Gauge>)>>,
+}
+
+// Metric names allow [a-zA-Z0-9_:] and must not start with a digit.
+pub(crate) fn valid_metric_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+// "AC_Power" becomes solbrid_ac_power, anything else than letters and digits an underscore.
+fn metric_name(prefix: &str, typ: &str) -> String {
+    let typ: String = typ.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+    format!("{}{}", prefix, typ)
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl PrometheusSink {
+    pub(crate) fn new(conf: &PrometheusMeasurementsConfig) -> Self {
+        PrometheusSink {
+            gauges: Arc::new(Gauges {
+                prefix: conf.prefix.clone().unwrap_or_else(|| DEFAULT_PROMETHEUS_PREFIX.to_string()),
+                max_age: Duration::from_secs(conf.max_age_secs.unwrap_or(DEFAULT_PROMETHEUS_MAX_AGE_SECS)),
+                devices: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    // Shared with the HTTP handler that renders them.
+    pub(crate) fn gauges(&self) -> Arc<Gauges> {
+        self.gauges.clone()
+    }
+}
+
+impl Gauges {
+    // Devices not published for max_age are left out, so a dead inverter
+    // shows up as a gap instead of its last values forever.
+    pub(crate) fn render(&self) -> String {
+        let devices = self.devices.lock().unwrap();
+        let mut metrics: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (serial, (published_at, gauges)) in devices.iter() {
+            if published_at.elapsed() > self.max_age {
+                continue;
+            }
+            for (name, unit, value) in gauges {
+                let labels = if unit.is_empty() {
+                    format!("serial=\"{}\"", escape_label(serial))
+                } else {
+                    format!("serial=\"{}\",unit=\"{}\"", escape_label(serial), escape_label(unit))
+                };
+                metrics.entry(name).or_default().push(format!("{}{{{}}} {}", name, labels, value));
+            }
+        }
+
+        let mut out = String::new();
+        for (name, samples) in metrics {
+            out.push_str(&format!("# TYPE {} gauge\n", name));
+            for sample in samples {
+                out.push_str(&sample);
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+#[async_trait]
+impl MeasurementSink for PrometheusSink {
+    // Replaces all values of the device, types it no longer sends disappear.
+    // Of types that end up with the same metric name the last one wins.
+    async fn publish(&self, device: &Device, measurements: &[Measurement]) -> Result<(), String> {
+        let mut gauges: BTreeMap<String, (String, f64)> = BTreeMap::new();
+        for measurement in measurements {
+            let Some(value) = clean_value(measurement.value.as_deref()).and_then(parse_value) else {
+                continue;
+            };
+            gauges.insert(
+                metric_name(&self.gauges.prefix, &measurement.typ),
+                (measurement.unit.clone().unwrap_or_default(), value),
+            );
+        }
+        let gauges = gauges.into_iter().map(|(name, (unit, value))| (name, unit, value)).collect();
+        self.gauges.devices.lock().unwrap().insert(device.serial.clone(), (Instant::now(), gauges));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Root;
+    use serde_xml_rs::from_str;
+
+    fn sink(toml: &str) -> PrometheusSink {
+        PrometheusSink::new(&toml::from_str(&format!("listen = \"127.0.0.1:9101\"\n{}", toml)).unwrap())
+    }
+
+    fn device() -> Device {
+        let root: Root = from_str(include_str!("../../tests/fixtures/measurements.xml")).unwrap();
+        root.into_devices().remove(0)
+    }
+
+    #[tokio::test]
+    async fn exposes_numeric_measurements_as_gauges() {
+        let sink = sink("");
+        let device = device();
+
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+
+        let text = sink.gauges().render();
+        assert!(text.contains(
+            "# TYPE solbrid_ac_power gauge\nsolbrid_ac_power{serial=\"7799ABCDEXXXXXX000\",unit=\"W\"} 382.6\n"
+        ));
+        assert!(text.contains("solbrid_derating{serial=\"7799ABCDEXXXXXX000\",unit=\"%\"} 0\n"));
+        // NaN, empty and missing values are left out.
+        assert!(!text.contains("solbrid_dc_power") && !text.contains("solbrid_bdc_bat_voltage"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_devices_after_max_age() {
+        let sink = sink("max_age_secs = 60\nprefix = \"pv_\"");
+        let device = device();
+
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(sink.gauges().render().contains("pv_ac_power"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert_eq!(sink.gauges().render(), "");
+        assert_eq!(metric_name("pv_", "AC-Power L1"), "pv_ac_power_l1");
+        assert!(valid_metric_name("pv_") && !valid_metric_name("1pv") && !valid_metric_name("pv-"));
+    }
+}
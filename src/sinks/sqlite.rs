@@ -0,0 +1,138 @@
+use super::MeasurementSink;
+use crate::{clean_value, parse_value, Device, Measurement, SqliteConfig};
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS measurements (
+    serial    TEXT NOT NULL,
+    type      TEXT NOT NULL,
+    value,
+    unit      TEXT,
+    timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS measurements_serial_type_timestamp ON measurements (serial, type, timestamp);
+";
+
+// --- SQLite Sink ---
+
+// rusqlite is blocking, every poll is written on the blocking thread pool.
+pub(crate) struct SqliteSink {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteSink {
+    pub(crate) fn new(sqlite_conf: &SqliteConfig) -> Result<Self, String> {
+        let connection = Connection::open(&sqlite_conf.path)
+            .map_err(|e| format!("Failed to open SQLite database '{}': {}", sqlite_conf.path, e))?;
+        Self::with_connection(connection).map_err(|e| format!("SQLite database '{}': {}", sqlite_conf.path, e))
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, String> {
+        // WAL survives a power cut mid-write and lets a sync job read while we write.
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(|e| format!("Failed to enable WAL: {}", e))?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|e| format!("Failed to create the measurements table: {}", e))?;
+        Ok(SqliteSink {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+}
+
+// Numbers are stored as REAL, anything else as TEXT in the same column.
+fn sqlite_value(value: &str) -> Value {
+    match parse_value(value) {
+        Some(number) => Value::Real(number),
+        None => Value::Text(value.to_string()),
+    }
+}
+
+fn insert_rows(connection: &mut Connection, rows: &[(String, String, Value, Option<String>, i64)]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction
+            .prepare_cached("INSERT INTO measurements (serial, type, value, unit, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+        for (serial, typ, value, unit, timestamp) in rows {
+            statement.execute(params![serial, typ, value, unit, timestamp])?;
+        }
+    }
+    transaction.commit()
+}
+
+#[async_trait]
+impl MeasurementSink for SqliteSink {
+    async fn publish(&self, device: &Device, measurements: &[Measurement]) -> Result<(), String> {
+        let timestamp = device.timestamp().unwrap_or_else(Utc::now).timestamp();
+        let rows: Vec<_> = measurements
+            .iter()
+            .filter_map(|measurement| {
+                let value = clean_value(measurement.value.as_deref())?;
+                Some((
+                    device.serial.clone(),
+                    measurement.typ.clone(),
+                    sqlite_value(value),
+                    measurement.unit.clone(),
+                    timestamp,
+                ))
+            })
+            .collect();
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        // One transaction per poll, a single fsync instead of one per row.
+        let connection = self.connection.clone();
+        let count = rows.len();
+        tokio::task::spawn_blocking(move || insert_rows(&mut connection.lock().unwrap(), &rows))
+            .await
+            .map_err(|e| format!("SQLite Write Error: {}", e))?
+            .map_err(|e| format!("SQLite Write Error: {}", e))?;
+
+        debug!("SQLite Write Success: {} rows", count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Root;
+    use serde_xml_rs::from_str;
+
+    #[tokio::test]
+    async fn writes_every_measurement_with_a_value() {
+        let root: Root = from_str(include_str!("../../tests/fixtures/measurements.xml")).unwrap();
+        let device = root.into_devices().remove(0);
+        let sink = SqliteSink::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+
+        let connection = sink.connection.lock().unwrap();
+        let rows: Vec<(String, String, f64, Option<String>, i64)> = connection
+            .prepare("SELECT serial, type, value, unit, timestamp FROM measurements ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        // BDC_BAT_Voltage, DC_Power (NaN) and DC_Current (blank) carry no value.
+        assert_eq!(rows.len(), 5);
+        assert_eq!(
+            rows[0],
+            ("7799ABCDEXXXXXX000".to_string(), "AC_Voltage1".to_string(), 237.3, Some("V".to_string()), 1791979200)
+        );
+    }
+
+    #[test]
+    fn keeps_text_values_as_text() {
+        assert_eq!(sqlite_value("382.6"), Value::Real(382.6));
+        assert_eq!(sqlite_value("Feed-in"), Value::Text("Feed-in".to_string()));
+    }
+}
@@ -0,0 +1,93 @@
+use super::{JsonRecord, MeasurementSink};
+use crate::{clean_value, parse_value, Device, Measurement, StdoutFormat};
+use async_trait::async_trait;
+use chrono::Utc;
+use prost::Message;
+use std::io::Write;
+
+// --- Stdout Protobuf Record ---
+
+// Mirrors proto/measurement.proto, keep both in sync.
+#[derive(Clone, PartialEq, Message)]
+struct MeasurementRecord {
+    #[prost(string, tag = "1")]
+    serial: String,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "3")]
+    typ: String,
+    #[prost(double, optional, tag = "4")]
+    value: Option<f64>,
+    #[prost(string, tag = "5")]
+    unit: String,
+    #[prost(int64, tag = "6")]
+    timestamp_ms: i64,
+    #[prost(string, optional, tag = "7")]
+    text_value: Option<String>,
+}
+
+// One length-delimited record per measurement with a value.
+fn encode_protobuf(device: &Device, measurements: &[Measurement]) -> Vec<u8> {
+    let timestamp_ms = device.timestamp().unwrap_or_else(Utc::now).timestamp_millis();
+    let mut buffer = Vec::new();
+
+    for measurement in measurements {
+        let Some(value) = clean_value(measurement.value.as_deref()) else {
+            continue;
+        };
+        let number = parse_value(value);
+        let record = MeasurementRecord {
+            serial: device.serial.clone(),
+            name: device.name.clone(),
+            typ: measurement.typ.clone(),
+            value: number,
+            unit: measurement.unit.clone().unwrap_or_default(),
+            timestamp_ms,
+            text_value: number.is_none().then(|| value.to_string()),
+        };
+        // Writing into a Vec cannot run out of space.
+        let _ = record.encode_length_delimited(&mut buffer);
+    }
+    buffer
+}
+
+// --- Stdout Sink ---
+
+pub(crate) struct StdoutSink {
+    format: StdoutFormat,
+}
+
+impl StdoutSink {
+    pub(crate) fn new(format: StdoutFormat) -> Self {
+        StdoutSink { format }
+    }
+
+    // One JSON document per device, followed by a newline.
+    fn encode_json(&self, device: &Device, measurements: &[Measurement]) -> Result<Vec<u8>, String> {
+        let record = JsonRecord::new(device, measurements);
+        let mut line = match self.format {
+            StdoutFormat::Json => serde_json::to_vec_pretty(&record),
+            _ => serde_json::to_vec(&record),
+        }
+        .map_err(|e| format!("Stdout JSON Serialize Error: {:?}", e))?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+#[async_trait]
+impl MeasurementSink for StdoutSink {
+    async fn publish(&self, device: &Device, measurements: &[Measurement]) -> Result<(), String> {
+        let output = match self.format {
+            StdoutFormat::Protobuf => encode_protobuf(device, measurements),
+            StdoutFormat::Json | StdoutFormat::Jsonl => self.encode_json(device, measurements)?,
+        };
+
+        // Flush every record so a consumer on the other end of a pipe sees it right away.
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(&output)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| format!("Stdout Write Error: {}", e))
+    }
+}
@@ -0,0 +1,190 @@
+use super::{JsonRecord, MeasurementSink};
+use crate::{body_snippet, Device, Measurement, Metrics, WebhookConfig};
+use async_trait::async_trait;
+use reqwest::header::AUTHORIZATION;
+use reqwest::Client;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+const DEFAULT_WEBHOOK_RETRIES: u32 = 3;
+const WEBHOOK_RETRY_DELAY_MS: u64 = 500;
+
+// --- Webhook Sink ---
+
+// POSTs a JSON array of device records, the same documents the stdout sink
+// writes, once batch_size of them have been collected.
+pub(crate) struct WebhookSink {
+    // The inverter client, so the http_* settings apply here as well.
+    http_client: Client,
+    url: String,
+    authorization: Option<String>,
+    retries: u32,
+    batch_size: usize,
+    pending: Mutex<Vec<serde_json::Value>>,
+    metrics: Arc<Metrics>,
+}
+
+impl WebhookSink {
+    pub(crate) fn new(http_client: Client, webhook_conf: &WebhookConfig, metrics: Arc<Metrics>) -> Result<Self, String> {
+        let authorization = match (&webhook_conf.authorization, &webhook_conf.authorization_env) {
+            (Some(_), Some(_)) => {
+                return Err("Webhook config sets both authorization and authorization_env, use only one.".to_string());
+            }
+            (Some(value), None) => Some(value.clone()),
+            (None, Some(var)) => Some(std::env::var(var).map_err(|_| {
+                format!("Webhook authorization_env refers to '{}', but that environment variable is not set.", var)
+            })?),
+            (None, None) => None,
+        };
+
+        Ok(WebhookSink {
+            http_client,
+            url: webhook_conf.url.clone(),
+            authorization,
+            retries: webhook_conf.retries.unwrap_or(DEFAULT_WEBHOOK_RETRIES),
+            batch_size: webhook_conf.batch_size.unwrap_or(1),
+            pending: Mutex::new(Vec::new()),
+            metrics,
+        })
+    }
+
+    async fn post(&self, body: &[serde_json::Value]) -> Result<(), String> {
+        let mut request = self.http_client.post(&self.url).json(body);
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+
+        let resp = request.send().await.map_err(|e| format!("{:?}", e))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let bytes = resp.bytes().await.unwrap_or_default();
+        Err(format!("HTTP {}: {}", status, body_snippet(&bytes)))
+    }
+
+    async fn send(&self, batch: Vec<serde_json::Value>) -> Result<(), String> {
+        // Same retry schedule as the InfluxDB sink.
+        let mut attempt = 0;
+        loop {
+            match self.post(&batch).await {
+                Ok(_) => {
+                    Metrics::inc(&self.metrics.webhook_posts_total);
+                    if attempt > 0 {
+                        warn!("Webhook POST succeeded after {} retries", attempt);
+                    } else {
+                        debug!("Webhook POST Success: {} records", batch.len());
+                    }
+                    return Ok(());
+                }
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("Webhook POST Error (retry {}/{}): {}", attempt, self.retries, e);
+                    sleep(Duration::from_millis(WEBHOOK_RETRY_DELAY_MS << (attempt - 1).min(8))).await;
+                }
+                Err(e) if batch.len() > 1 => {
+                    return Err(format!("Webhook POST Error: {} ({} buffered records lost)", e, batch.len()));
+                }
+                Err(e) => return Err(format!("Webhook POST Error: {}", e)),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MeasurementSink for WebhookSink {
+    async fn publish(&self, device: &Device, measurements: &[Measurement]) -> Result<(), String> {
+        let record = serde_json::to_value(JsonRecord::new(device, measurements))
+            .map_err(|e| format!("Webhook JSON Serialize Error: {:?}", e))?;
+
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.push(record);
+            if pending.len() < self.batch_size {
+                return Ok(());
+            }
+            std::mem::take(&mut *pending)
+        };
+        self.send(batch).await
+    }
+
+    async fn flush(&self) -> Result<(), String> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.send(batch).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Root;
+    use serde_xml_rs::from_str;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn sink(server: &MockServer, toml: &str) -> WebhookSink {
+        let webhook_conf: WebhookConfig = toml::from_str(&format!("url = \"{}/ingest\"\n{}", server.uri(), toml)).unwrap();
+        WebhookSink::new(Client::new(), &webhook_conf, Arc::new(Metrics::default())).unwrap()
+    }
+
+    fn device() -> Device {
+        let root: Root = from_str(include_str!("../../tests/fixtures/measurements.xml")).unwrap();
+        root.into_devices().remove(0)
+    }
+
+    #[tokio::test]
+    async fn posts_batches_of_device_records() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("authorization", "Bearer secret"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let sink = sink(&server, "authorization = \"Bearer secret\"\nbatch_size = 2");
+        let device = device();
+
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+        assert!(server.received_requests().await.unwrap().is_empty());
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["serial"], "7799ABCDEXXXXXX000");
+        assert_eq!(body[0]["measurements"][0]["value"], 237.3);
+    }
+
+    #[tokio::test]
+    async fn flushes_a_partial_batch() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).expect(1).mount(&server).await;
+        let sink = sink(&server, "batch_size = 10");
+        let device = device();
+
+        sink.publish(&device, &device.measurements.measurement).await.unwrap();
+        sink.flush().await.unwrap();
+        sink.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_once_all_retries_are_used_up() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500).set_body_string("database down"))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let sink = sink(&server, "retries = 1");
+        let device = device();
+
+        let error = sink.publish(&device, &device.measurements.measurement).await.unwrap_err();
+
+        assert_eq!(error, "Webhook POST Error: HTTP 500 Internal Server Error: database down");
+    }
+}
@@ -0,0 +1,122 @@
+// --- Smoothing ---
+//
+// [smoothing] replaces noisy instantaneous values with the moving average of
+// the last `window` polls, kept per device and type. Until the window has
+// filled up after startup, the average covers the values seen so far.
+
+use crate::{clean_value, parse_value, Measurement, SmoothingRule};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+
+pub(crate) struct Smoothing {
+    rules: BTreeMap<String, (usize, bool)>,
+    // (serial, type) -> the last values, oldest first.
+    windows: Mutex<HashMap<(String, String), VecDeque<f64>>>,
+}
+
+impl Smoothing {
+    pub(crate) fn new(rules: &BTreeMap<String, SmoothingRule>) -> Self {
+        Smoothing {
+            rules: rules
+                .iter()
+                .map(|(typ, rule)| (typ.clone(), (rule.window, rule.publish_raw.unwrap_or(false))))
+                .collect(),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Values that are missing or not numbers are left alone and do not enter
+    // the window. With publish_raw the unsmoothed value follows as <Type>_raw.
+    pub(crate) fn apply(&self, serial: &str, measurements: &mut Vec<Measurement>) {
+        if self.rules.is_empty() {
+            return;
+        }
+        let mut windows = self.windows.lock().unwrap();
+        let mut smoothed = Vec::with_capacity(measurements.len());
+        for mut measurement in measurements.drain(..) {
+            let Some(&(window, publish_raw)) = self.rules.get(&measurement.typ) else {
+                smoothed.push(measurement);
+                continue;
+            };
+            let Some(value) = clean_value(measurement.value.as_deref()).and_then(parse_value) else {
+                smoothed.push(measurement);
+                continue;
+            };
+
+            let values = windows.entry((serial.to_string(), measurement.typ.clone())).or_default();
+            values.push_back(value);
+            if values.len() > window {
+                values.pop_front();
+            }
+            let average = values.iter().sum::<f64>() / values.len() as f64;
+
+            let raw = publish_raw.then(|| Measurement {
+                value: measurement.value.clone(),
+                typ: format!("{}_raw", measurement.typ),
+                unit: measurement.unit.clone(),
+                name: None,
+                description: None,
+            });
+            // Same rounding as [normalize], hides float noise of the sum.
+            measurement.value = Some(((average * 1e9).round() / 1e9).to_string());
+            smoothed.push(measurement);
+            smoothed.extend(raw);
+        }
+        *measurements = smoothed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smoothing(toml: &str) -> Smoothing {
+        Smoothing::new(&toml::from_str(toml).unwrap())
+    }
+
+    fn measurement(typ: &str, value: &str) -> Measurement {
+        Measurement {
+            value: Some(value.to_string()),
+            typ: typ.to_string(),
+            unit: Some("W".to_string()),
+            name: None,
+            description: None,
+        }
+    }
+
+    fn values(measurements: &[Measurement]) -> Vec<(&str, &str)> {
+        measurements.iter().map(|m| (m.typ.as_str(), m.value.as_deref().unwrap_or("-"))).collect()
+    }
+
+    #[test]
+    fn averages_over_the_window_and_warms_up_with_fewer_values() {
+        let smoothing = smoothing("AC_Power = { window = 3 }");
+        let mut averages = Vec::new();
+
+        for value in ["100", "200", "600", "400", "NaN", "800"] {
+            let mut measurements = vec![measurement("AC_Power", value), measurement("AC_Voltage1", value)];
+            smoothing.apply("7799", &mut measurements);
+            assert_eq!(measurements[1].value.as_deref(), Some(value));
+            averages.push(measurements[0].value.clone().unwrap());
+        }
+
+        // 100, (100+200)/2, (100+200+600)/3, (200+600+400)/3, NaN, (600+400+800)/3
+        assert_eq!(averages, ["100", "150", "300", "400", "NaN", "600"]);
+    }
+
+    #[test]
+    fn keeps_one_window_per_serial_and_publishes_raw_values() {
+        let smoothing = smoothing("AC_Power = { window = 2, publish_raw = true }");
+
+        let mut first = vec![measurement("AC_Power", "100")];
+        smoothing.apply("7799", &mut first);
+        let mut other = vec![measurement("AC_Power", "500")];
+        smoothing.apply("8800", &mut other);
+        let mut second = vec![measurement("AC_Power", "0.2"), measurement("DC_Power", "7")];
+        smoothing.apply("7799", &mut second);
+
+        assert_eq!(values(&other), [("AC_Power", "500"), ("AC_Power_raw", "500")]);
+        assert_eq!(values(&second), [("AC_Power", "50.1"), ("AC_Power_raw", "0.2"), ("DC_Power", "7")]);
+        assert_eq!(second[1].unit.as_deref(), Some("W"));
+    }
+}
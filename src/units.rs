@@ -0,0 +1,190 @@
+// --- Units ---
+//
+// Firmware versions spell the same @Unit differently ("W", "Watt", "w"), which
+// splits one InfluxDB series into several. Known spellings are rewritten to
+// one canonical symbol before anything is published, unknown units are kept
+// as they are.
+
+use crate::Measurement;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Mutex;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Unit {
+    Watt,
+    Kilowatt,
+    WattHour,
+    KilowattHour,
+    MegawattHour,
+    Volt,
+    Ampere,
+    VoltAmpere,
+    KilovoltAmpere,
+    Var,
+    Kilovar,
+    Hertz,
+    Celsius,
+    Percent,
+    Ohm,
+    Hour,
+}
+
+// Units already logged as unknown, so each one appears once per run.
+static UNKNOWN_UNITS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+impl Unit {
+    // Names and unprefixed symbols ignore case. SI prefixes do not, "mWh"
+    // (milli) is no "MWh", only the kilo forms also accept "K", which is no
+    // prefix of its own. The unit behind a prefix ignores case again ("KWH").
+    pub(crate) fn parse(unit: &str) -> Option<Unit> {
+        let unit = unit.trim();
+        if let Some(named) = Unit::parse_unprefixed(unit) {
+            return Some(named);
+        }
+        let prefix = unit.chars().next()?;
+        let unit = match (prefix, Unit::parse_unprefixed(&unit[prefix.len_utf8()..])?) {
+            ('k' | 'K', Unit::Watt) => Unit::Kilowatt,
+            ('k' | 'K', Unit::WattHour) => Unit::KilowattHour,
+            ('M', Unit::WattHour) => Unit::MegawattHour,
+            ('k' | 'K', Unit::VoltAmpere) => Unit::KilovoltAmpere,
+            ('k' | 'K', Unit::Var) => Unit::Kilovar,
+            _ => return None,
+        };
+        Some(unit)
+    }
+
+    fn parse_unprefixed(unit: &str) -> Option<Unit> {
+        // "c" alone is too likely something else, only "C" is read as °C.
+        if unit == "C" {
+            return Some(Unit::Celsius);
+        }
+        let unit = match unit.to_lowercase().as_str() {
+            "w" | "watt" | "watts" => Unit::Watt,
+            "kilowatt" | "kilowatts" => Unit::Kilowatt,
+            "wh" | "watthour" | "watthours" | "watt hours" => Unit::WattHour,
+            "kilowatthour" | "kilowatthours" | "kilowatt hours" => Unit::KilowattHour,
+            "megawatthour" | "megawatthours" | "megawatt hours" => Unit::MegawattHour,
+            "v" | "volt" | "volts" => Unit::Volt,
+            "a" | "amp" | "amps" | "ampere" | "amperes" => Unit::Ampere,
+            "va" => Unit::VoltAmpere,
+            "var" => Unit::Var,
+            "hz" | "hertz" => Unit::Hertz,
+            "°c" | "ºc" | "degc" | "deg c" | "celsius" => Unit::Celsius,
+            "%" | "percent" => Unit::Percent,
+            "ohm" | "ohms" | "ω" => Unit::Ohm,
+            "h" | "hour" | "hours" => Unit::Hour,
+            _ => return None,
+        };
+        Some(unit)
+    }
+
+    pub(crate) fn symbol(self) -> &'static str {
+        match self {
+            Unit::Watt => "W",
+            Unit::Kilowatt => "kW",
+            Unit::WattHour => "Wh",
+            Unit::KilowattHour => "kWh",
+            Unit::MegawattHour => "MWh",
+            Unit::Volt => "V",
+            Unit::Ampere => "A",
+            Unit::VoltAmpere => "VA",
+            Unit::KilovoltAmpere => "kVA",
+            Unit::Var => "var",
+            Unit::Kilovar => "kvar",
+            Unit::Hertz => "Hz",
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::Ohm => "Ω",
+            Unit::Hour => "h",
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+// The canonical symbol of a known unit, anything else unchanged.
+pub(crate) fn canonical_unit(unit: &str) -> String {
+    match Unit::parse(unit) {
+        Some(unit) => unit.to_string(),
+        None => unit.to_string(),
+    }
+}
+
+pub(crate) fn canonicalize_units(measurements: &mut [Measurement]) {
+    for measurement in measurements {
+        let Some(unit) = measurement.unit.as_deref().filter(|unit| !unit.trim().is_empty()) else {
+            continue;
+        };
+        match Unit::parse(unit) {
+            Some(known) => {
+                if unit != known.symbol() {
+                    measurement.unit = Some(known.to_string());
+                }
+            }
+            None => {
+                if UNKNOWN_UNITS.lock().unwrap().insert(unit.to_string()) {
+                    info!("Unknown unit '{}' of {}, published unchanged", unit, measurement.typ);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(unit: Option<&str>) -> Measurement {
+        Measurement {
+            value: Some("1".to_string()),
+            typ: "AC_Power".to_string(),
+            unit: unit.map(str::to_string),
+            name: None,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn parses_common_spellings() {
+        for unit in ["W", "w", "Watt", " watts "] {
+            assert_eq!(Unit::parse(unit), Some(Unit::Watt), "{}", unit);
+        }
+        for unit in ["kWh", "KWH", "kwh"] {
+            assert_eq!(Unit::parse(unit), Some(Unit::KilowattHour), "{}", unit);
+        }
+        for unit in ["°C", "ºC", "degC", "C"] {
+            assert_eq!(Unit::parse(unit), Some(Unit::Celsius), "{}", unit);
+        }
+        assert_eq!(Unit::parse("VAr"), Some(Unit::Var));
+        assert_eq!(Unit::parse("MWh"), Some(Unit::MegawattHour));
+        assert_eq!(Unit::parse("kVAr"), Some(Unit::Kilovar));
+        // Milli is no mega, and a lowercase c no Celsius.
+        for unit in ["mW", "mWh", "mwh", "c", "kC"] {
+            assert_eq!(Unit::parse(unit), None, "{}", unit);
+        }
+        assert_eq!(Unit::parse("furlongs"), None);
+    }
+
+    #[test]
+    fn rewrites_known_units_and_keeps_unknown_ones() {
+        let mut measurements = vec![
+            measurement(Some("Watt")),
+            measurement(Some("KWH")),
+            measurement(Some("V")),
+            measurement(Some("rpm")),
+            measurement(Some("")),
+            measurement(None),
+        ];
+
+        canonicalize_units(&mut measurements);
+
+        let units: Vec<_> = measurements.iter().map(|m| m.unit.as_deref()).collect();
+        assert_eq!(units, [Some("W"), Some("kWh"), Some("V"), Some("rpm"), Some(""), None]);
+    }
+}
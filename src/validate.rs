@@ -0,0 +1,176 @@
+// --- Validate ---
+//
+// --validate checks what a deployment needs before the bridge is started for
+// real. The config was already validated when this runs; then every inverter
+// is fetched and the MQTT broker and InfluxDB are connected to with their
+// credentials, each check with a timeout of its own. Nothing is published.
+
+use crate::sinks::InfluxSink;
+use crate::{build_http_client, fetch_devices, mqtt_options, Config, Inverter, Metrics, MqttConfig};
+use futures::future::join_all;
+use reqwest::Client;
+use rumqttc::{AsyncClient, Event, Outgoing, Packet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+pub(crate) const VALIDATE_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug)]
+struct Check {
+    name: &'static str,
+    target: String,
+    // What was found on success, why it failed otherwise.
+    result: Result<String, String>,
+}
+
+async fn check_inverter(http_client: &Client, inverter: &Inverter, limit: Duration) -> Result<String, String> {
+    let devices = timeout(limit, fetch_devices(http_client, inverter, None))
+        .await
+        .map_err(|_| format!("no response within {}s", limit.as_secs()))??;
+    let measurements: usize = devices.iter().map(|device| device.measurements.measurement.len()).sum();
+    Ok(format!("{} device(s), {} measurements", devices.len(), measurements))
+}
+
+// A connection of its own without Last Will, like --purge-retained, so a
+// running bridge stays connected and its availability_topic is left alone.
+async fn check_mqtt(mqtt_conf: &MqttConfig, limit: Duration) -> Result<String, String> {
+    let mut options = mqtt_options(mqtt_conf, &format!("{}_validate", mqtt_conf.client_id))?;
+    options.set_clean_session(true);
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    // A refused login ends the poll with ConnectionRefused(BadUserNamePassword).
+    let connected = async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(format!("{:?}", e)),
+            }
+        }
+    };
+    timeout(limit, connected)
+        .await
+        .map_err(|_| format!("not connected within {}s", limit.as_secs()))??;
+
+    if client.disconnect().await.is_ok() {
+        let disconnected = async {
+            while !matches!(eventloop.poll().await, Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_)) {}
+        };
+        let _ = timeout(Duration::from_secs(1), disconnected).await;
+    }
+    Ok("connected".to_string())
+}
+
+async fn run_checks(config: &Config, inverters: &[Inverter], limit: Duration) -> Vec<Check> {
+    let http_client = build_http_client(config);
+    let http_client = &http_client;
+    let inverter_checks = join_all(inverters.iter().map(|inverter| async move {
+        let result = match http_client {
+            Ok(http_client) => check_inverter(http_client, inverter, limit).await,
+            Err(e) => Err(e.clone()),
+        };
+        Check { name: "inverter", target: inverter.url.clone(), result }
+    }));
+
+    let mqtt_check = async {
+        let mqtt_conf = config.mqtt.as_ref()?;
+        Some(Check {
+            name: "mqtt",
+            target: format!("{}:{}", mqtt_conf.broker, mqtt_conf.port),
+            result: check_mqtt(mqtt_conf, limit).await,
+        })
+    };
+
+    let influx_check = async {
+        let metrics = Arc::new(Metrics::default());
+        let (target, sink) = match (&config.influxdb, &config.influxdb_v1) {
+            (Some(influx_conf), _) => {
+                (influx_conf.url.clone(), InfluxSink::new(influx_conf, &config.topic_map, metrics))
+            }
+            (None, Some(influx_conf)) => {
+                (influx_conf.url.clone(), InfluxSink::new_v1(influx_conf, &config.topic_map, metrics))
+            }
+            (None, None) => return None,
+        };
+        let result = match sink {
+            Ok(sink) => sink.check_write(limit).await.map(|_| "write accepted".to_string()),
+            Err(e) => Err(e),
+        };
+        Some(Check { name: "influxdb", target, result })
+    };
+
+    let (mut checks, mqtt_check, influx_check) = tokio::join!(inverter_checks, mqtt_check, influx_check);
+    checks.extend(mqtt_check);
+    checks.extend(influx_check);
+    checks
+}
+
+fn report(config_path: &str, checks: &[Check]) -> String {
+    let mut out = format!("{:<8}{:<10}{}\n", "ok", "config", config_path);
+    for check in checks {
+        let (status, detail) = match &check.result {
+            Ok(detail) => ("ok", detail),
+            Err(e) => ("FAILED", e),
+        };
+        out.push_str(&format!("{:<8}{:<10}{}: {}\n", status, check.name, check.target, detail));
+    }
+    out
+}
+
+// Prints one line per check and fails if any of them did.
+pub(crate) async fn validate(config: &Config, config_path: &str, inverters: &[Inverter]) -> Result<(), String> {
+    let checks = run_checks(config, inverters, Duration::from_secs(VALIDATE_TIMEOUT_SECS)).await;
+    print!("{}", report(config_path, &checks));
+
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed > 0 {
+        return Err(format!("Validation failed: {} of {} checks failed.", failed, checks.len() + 1));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolve_inverters;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn reports_every_check_without_publishing() {
+        let server = MockServer::start().await;
+        let xml = include_str!("../tests/fixtures/measurements.xml");
+        Mock::given(method("GET"))
+            .and(path("/measurements.xml"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(xml, "text/xml"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/write"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Nothing listens on a port that was just released.
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config: Config = toml::from_str(&format!(
+            "inverter_url = \"{0}/measurements.xml\"\npoll_interval_secs = 5\nmax_errors = 3\n\
+             [mqtt]\nbroker = \"127.0.0.1\"\nport = {1}\nclient_id = \"solbrid\"\n\
+             [influxdb_v1]\nurl = \"{0}\"\ndatabase = \"solar\"\n",
+            server.uri(),
+            port
+        ))
+        .unwrap();
+
+        let checks = run_checks(&config, &resolve_inverters(&config).unwrap(), Duration::from_secs(5)).await;
+
+        let lines: Vec<String> = report("config.toml", &checks).lines().map(str::to_string).collect();
+        assert_eq!(lines[0], "ok      config    config.toml");
+        assert_eq!(lines[1], format!("ok      inverter  {}/measurements.xml: 1 device(s), 8 measurements", server.uri()));
+        assert!(lines[2].starts_with(&format!("FAILED  mqtt      127.0.0.1:{}: ", port)), "{}", lines[2]);
+        assert_eq!(lines[3], format!("ok      influxdb  {}: write accepted", server.uri()));
+        let requests = server.received_requests().await.unwrap();
+        assert!(requests.iter().all(|request| request.body.is_empty()));
+    }
+}